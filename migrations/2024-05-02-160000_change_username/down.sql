@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS name_redirect;
+
+ALTER TABLE app_user DROP COLUMN name_changed_at;
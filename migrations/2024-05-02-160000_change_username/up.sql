@@ -0,0 +1,9 @@
+ALTER TABLE app_user ADD COLUMN name_changed_at TIMESTAMP;
+
+CREATE TABLE name_redirect (
+    name VARCHAR(255) PRIMARY KEY,
+    app_user_id INTEGER NOT NULL references app_user(id),
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX idx_name_redirect_app_user_id ON name_redirect (app_user_id);
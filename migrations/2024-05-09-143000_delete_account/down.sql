@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS idx_app_user_active_name;
+ALTER TABLE app_user ADD CONSTRAINT app_user_name_key UNIQUE (name);
+
+ALTER TABLE app_user DROP COLUMN deleted_at;
@@ -0,0 +1,5 @@
+ALTER TABLE app_user ADD COLUMN deleted_at TIMESTAMP;
+
+-- deleted users keep their row, so names only need to be unique between active users
+ALTER TABLE app_user DROP CONSTRAINT app_user_name_key;
+CREATE UNIQUE INDEX idx_app_user_active_name ON app_user (name) WHERE deleted_at IS NULL;
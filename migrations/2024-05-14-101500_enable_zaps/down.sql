@@ -0,0 +1,3 @@
+ALTER TABLE app_user DROP COLUMN disabled_zaps_reason;
+ALTER TABLE app_user DROP COLUMN disabled_zaps_until;
+ALTER TABLE app_user DROP COLUMN disabled_zaps_at;
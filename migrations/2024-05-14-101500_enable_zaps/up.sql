@@ -0,0 +1,3 @@
+ALTER TABLE app_user ADD COLUMN disabled_zaps_at TIMESTAMP;
+ALTER TABLE app_user ADD COLUMN disabled_zaps_until TIMESTAMP;
+ALTER TABLE app_user ADD COLUMN disabled_zaps_reason VARCHAR(255);
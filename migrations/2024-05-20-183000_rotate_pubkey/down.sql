@@ -0,0 +1,3 @@
+ALTER TABLE invoice DROP COLUMN pubkey;
+
+DROP TABLE IF EXISTS pubkey_history;
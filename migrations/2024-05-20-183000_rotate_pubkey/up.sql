@@ -0,0 +1,13 @@
+CREATE TABLE pubkey_history (
+    id SERIAL PRIMARY KEY,
+    app_user_id INTEGER NOT NULL references app_user(id),
+    pubkey VARCHAR(64) NOT NULL,
+    replaced_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_pubkey_history_app_user_id ON pubkey_history (app_user_id);
+
+-- remember which key an invoice was tweaked for, the user's key may change afterwards
+ALTER TABLE invoice ADD COLUMN pubkey VARCHAR(64);
+UPDATE invoice SET pubkey = app_user.pubkey FROM app_user WHERE invoice.app_user_id = app_user.id;
+ALTER TABLE invoice ALTER COLUMN pubkey SET NOT NULL;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_app_user_pubkey;
+
+ALTER TABLE app_user DROP COLUMN is_primary;
@@ -0,0 +1,3 @@
+ALTER TABLE app_user ADD COLUMN is_primary BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX idx_app_user_pubkey ON app_user (pubkey);
@@ -0,0 +1,34 @@
+CREATE TABLE reserved_name (
+    id SERIAL PRIMARY KEY,
+    pattern VARCHAR(255) NOT NULL,
+    kind INTEGER NOT NULL,
+    pubkey VARCHAR(64),
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX idx_reserved_name_kind_pattern ON reserved_name (kind, pattern);
+
+-- kind 0 = exact, 1 = prefix, 2 = regex
+INSERT INTO reserved_name (pattern, kind) VALUES
+    ('admin', 0),
+    ('administrator', 0),
+    ('root', 0),
+    ('support', 0),
+    ('help', 0),
+    ('info', 0),
+    ('security', 0),
+    ('abuse', 0),
+    ('postmaster', 0),
+    ('hostmaster', 0),
+    ('webmaster', 0),
+    ('noreply', 0),
+    ('no-reply', 0),
+    ('billing', 0),
+    ('staff', 0),
+    ('mod', 0),
+    ('moderator', 0),
+    ('official', 0),
+    ('hermes', 0),
+    ('mutiny', 1),
+    ('fedimint', 1),
+    ('^.*(admin|support)$', 2);
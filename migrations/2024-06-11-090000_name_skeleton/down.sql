@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_app_user_name_skeleton;
+
+ALTER TABLE app_user DROP COLUMN name_skeleton;
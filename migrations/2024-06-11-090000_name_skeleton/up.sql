@@ -0,0 +1,4 @@
+-- the skeleton is computed by the service, existing names are backfilled on startup
+ALTER TABLE app_user ADD COLUMN name_skeleton VARCHAR(255);
+
+CREATE INDEX idx_app_user_name_skeleton ON app_user (name_skeleton);
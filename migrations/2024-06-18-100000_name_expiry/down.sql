@@ -0,0 +1,6 @@
+DROP TABLE IF EXISTS renewal_token;
+
+DROP INDEX IF EXISTS idx_app_user_expires_at;
+
+ALTER TABLE app_user DROP COLUMN expiry_reminded_at;
+ALTER TABLE app_user DROP COLUMN expires_at;
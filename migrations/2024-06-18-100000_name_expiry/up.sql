@@ -0,0 +1,19 @@
+-- paid names expire, free ones don't
+ALTER TABLE app_user ADD COLUMN expires_at TIMESTAMP;
+ALTER TABLE app_user ADD COLUMN expiry_reminded_at TIMESTAMP;
+
+-- existing names don't record whether they were paid for. Free names have
+-- always been generated, an adjective and a noun followed by four digits,
+-- any other name was chosen with a paid token and gets a full term
+-- (PAID_NAME_TERM_DAYS) from now
+UPDATE app_user SET expires_at = NOW() + INTERVAL '365 days'
+WHERE deleted_at IS NULL AND name !~ '^[a-z]+[0-9]{4}$';
+
+CREATE INDEX idx_app_user_expires_at ON app_user (expires_at) WHERE expires_at IS NOT NULL;
+
+CREATE TABLE renewal_token (
+    unblinded_msg VARCHAR(255) PRIMARY KEY,
+    app_user_id INTEGER NOT NULL references app_user(id),
+    expires_at TIMESTAMP NOT NULL,
+    renewed_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,11 @@
+-- blind signing keys tokens can be issued under, next to the FREE_PK and PAID_PK fallbacks
+CREATE TABLE signing_key (
+    id SERIAL PRIMARY KEY,
+    key_id VARCHAR(64) NOT NULL UNIQUE,
+    pubkey VARCHAR(255) NOT NULL UNIQUE,
+    service_id INTEGER NOT NULL,
+    plan_id INTEGER NOT NULL,
+    paid BOOLEAN NOT NULL,
+    valid_from TIMESTAMP NOT NULL DEFAULT NOW(),
+    valid_until TIMESTAMP
+);
@@ -0,0 +1,9 @@
+-- ids of signed commands that were already accepted, kept until they are too old to be fresh
+CREATE TABLE used_event_ids (
+    event_id VARCHAR(64) PRIMARY KEY,
+    pubkey VARCHAR(64) NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_used_event_ids_created_at ON used_event_ids (created_at);
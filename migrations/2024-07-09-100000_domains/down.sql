@@ -0,0 +1,10 @@
+ALTER TABLE name_redirect DROP CONSTRAINT name_redirect_pkey;
+DELETE FROM name_redirect WHERE domain_id <> (SELECT id FROM domains WHERE is_default);
+ALTER TABLE name_redirect ADD PRIMARY KEY (name);
+ALTER TABLE name_redirect DROP COLUMN domain_id;
+
+DROP INDEX IF EXISTS idx_app_user_active_name;
+CREATE UNIQUE INDEX idx_app_user_active_name ON app_user (name) WHERE deleted_at IS NULL;
+ALTER TABLE app_user DROP COLUMN domain_id;
+
+DROP TABLE IF EXISTS domains;
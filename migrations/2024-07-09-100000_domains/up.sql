@@ -0,0 +1,30 @@
+CREATE TABLE domains (
+    id SERIAL PRIMARY KEY,
+    host VARCHAR(255) NOT NULL UNIQUE,
+    is_default BOOLEAN NOT NULL DEFAULT FALSE,
+    -- the key zap receipts for the domain are signed with, the service key if not set
+    nostr_nsec VARCHAR(255),
+    -- text/plain LNURL metadata, {name} is replaced with the user's name
+    lnurl_description VARCHAR(255),
+    allowed_origins TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX idx_domains_default ON domains (is_default) WHERE is_default;
+
+-- existing names belong to the default domain, its host is set from DOMAIN_URL on startup
+INSERT INTO domains (host, is_default) VALUES ('', TRUE);
+
+ALTER TABLE app_user ADD COLUMN domain_id INTEGER REFERENCES domains(id);
+UPDATE app_user SET domain_id = (SELECT id FROM domains WHERE is_default);
+ALTER TABLE app_user ALTER COLUMN domain_id SET NOT NULL;
+
+-- names are unique per domain
+DROP INDEX IF EXISTS idx_app_user_active_name;
+CREATE UNIQUE INDEX idx_app_user_active_name ON app_user (domain_id, name) WHERE deleted_at IS NULL;
+
+ALTER TABLE name_redirect ADD COLUMN domain_id INTEGER REFERENCES domains(id);
+UPDATE name_redirect SET domain_id = (SELECT id FROM domains WHERE is_default);
+ALTER TABLE name_redirect ALTER COLUMN domain_id SET NOT NULL;
+ALTER TABLE name_redirect DROP CONSTRAINT name_redirect_pkey;
+ALTER TABLE name_redirect ADD PRIMARY KEY (domain_id, name);
@@ -0,0 +1,4 @@
+DELETE FROM domains WHERE owner_user_id IS NOT NULL;
+ALTER TABLE domains DROP COLUMN verified_at;
+ALTER TABLE domains DROP COLUMN owner_name;
+ALTER TABLE domains DROP COLUMN owner_user_id;
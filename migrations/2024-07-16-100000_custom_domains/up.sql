@@ -0,0 +1,5 @@
+-- domains users bring themselves, they serve one name backed by the owner's address
+ALTER TABLE domains ADD COLUMN owner_user_id INTEGER REFERENCES app_user(id);
+ALTER TABLE domains ADD COLUMN owner_name VARCHAR(255);
+-- when the owner last proved control of the domain with a TXT record
+ALTER TABLE domains ADD COLUMN verified_at TIMESTAMP;
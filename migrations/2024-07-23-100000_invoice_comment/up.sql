@@ -0,0 +1 @@
+ALTER TABLE invoice ADD COLUMN comment TEXT;
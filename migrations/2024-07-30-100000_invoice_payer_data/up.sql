@@ -0,0 +1 @@
+ALTER TABLE invoice ADD COLUMN payer_data TEXT;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_app_user_linking_key;
+ALTER TABLE app_user DROP COLUMN linking_key;
+DROP TABLE IF EXISTS lnurl_auth_sessions;
@@ -0,0 +1,14 @@
+-- LNURL-auth challenges, and the session tokens issued once a wallet signed them
+CREATE TABLE lnurl_auth_sessions (
+    k1 VARCHAR(64) PRIMARY KEY,
+    linking_key VARCHAR(66),
+    -- sha256 of the session token, the token itself is only given out once
+    token_hash VARCHAR(64) UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    authenticated_at TIMESTAMP,
+    token_expires_at TIMESTAMP
+);
+
+-- lets an LNURL-auth linking key manage the address
+ALTER TABLE app_user ADD COLUMN linking_key VARCHAR(66);
+CREATE INDEX idx_app_user_linking_key ON app_user (linking_key) WHERE linking_key IS NOT NULL;
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS idx_invoice_idempotency_key;
+ALTER TABLE invoice DROP COLUMN idempotency_key;
@@ -0,0 +1,2 @@
+ALTER TABLE invoice ADD COLUMN idempotency_key VARCHAR(64);
+CREATE INDEX idx_invoice_idempotency_key ON invoice (app_user_id, idempotency_key) WHERE idempotency_key IS NOT NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE invoice DROP COLUMN cancel_reason;
+ALTER TABLE invoice DROP COLUMN cancelled_at;
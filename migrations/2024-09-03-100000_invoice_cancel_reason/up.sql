@@ -0,0 +1,3 @@
+-- why and when a pending invoice was given up on
+ALTER TABLE invoice ADD COLUMN cancelled_at TIMESTAMP;
+ALTER TABLE invoice ADD COLUMN cancel_reason TEXT;
@@ -0,0 +1,3 @@
+-- challenges are also handed out for LUD-18 payer auth, which can't be
+-- turned into a session
+ALTER TABLE lnurl_auth_sessions ADD COLUMN purpose INTEGER NOT NULL DEFAULT 0;
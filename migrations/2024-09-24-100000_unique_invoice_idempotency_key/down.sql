@@ -0,0 +1,2 @@
+DROP INDEX idx_invoice_idempotency_key;
+CREATE INDEX idx_invoice_idempotency_key ON invoice (app_user_id, idempotency_key) WHERE idempotency_key IS NOT NULL;
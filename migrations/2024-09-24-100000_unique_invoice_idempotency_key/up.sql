@@ -0,0 +1,11 @@
+-- only one pending invoice per idempotency key, so concurrent retries can't
+-- both create one. Earlier racing duplicates keep the latest.
+UPDATE invoice SET idempotency_key = NULL
+WHERE state = 0 AND idempotency_key IS NOT NULL AND id NOT IN (
+    SELECT MAX(id) FROM invoice
+    WHERE state = 0 AND idempotency_key IS NOT NULL
+    GROUP BY app_user_id, idempotency_key
+);
+
+DROP INDEX idx_invoice_idempotency_key;
+CREATE UNIQUE INDEX idx_invoice_idempotency_key ON invoice (app_user_id, idempotency_key) WHERE idempotency_key IS NOT NULL AND state = 0;
@@ -0,0 +1,5 @@
+DROP INDEX idx_reserved_name_kind_pattern_domain;
+DROP INDEX idx_reserved_name_kind_pattern;
+DELETE FROM reserved_name WHERE domain_id IS NOT NULL;
+ALTER TABLE reserved_name DROP COLUMN domain_id;
+CREATE UNIQUE INDEX idx_reserved_name_kind_pattern ON reserved_name (kind, pattern);
@@ -0,0 +1,7 @@
+-- a reservation can be limited to one domain, the ones without a domain
+-- cover all of them
+ALTER TABLE reserved_name ADD COLUMN domain_id INTEGER REFERENCES domains(id);
+
+DROP INDEX idx_reserved_name_kind_pattern;
+CREATE UNIQUE INDEX idx_reserved_name_kind_pattern ON reserved_name (kind, pattern) WHERE domain_id IS NULL;
+CREATE UNIQUE INDEX idx_reserved_name_kind_pattern_domain ON reserved_name (kind, pattern, domain_id) WHERE domain_id IS NOT NULL;
@@ -0,0 +1,8 @@
+CREATE TABLE pubkey_history (
+    id SERIAL PRIMARY KEY,
+    app_user_id INTEGER NOT NULL references app_user(id),
+    pubkey VARCHAR(64) NOT NULL,
+    replaced_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_pubkey_history_app_user_id ON pubkey_history (app_user_id);
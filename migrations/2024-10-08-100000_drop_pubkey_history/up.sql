@@ -0,0 +1,2 @@
+-- the key an invoice pays out to is kept on the invoice itself
+DROP TABLE pubkey_history;
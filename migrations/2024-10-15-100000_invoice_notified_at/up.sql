@@ -0,0 +1,6 @@
+-- paid invoices are settled before the user is told, the ones they weren't
+-- told of yet are retried
+ALTER TABLE invoice ADD COLUMN notified_at TIMESTAMP;
+
+-- invoices used to be settled only once the user was told
+UPDATE invoice SET notified_at = NOW() WHERE state = 1;
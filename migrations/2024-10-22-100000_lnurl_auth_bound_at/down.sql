@@ -0,0 +1 @@
+ALTER TABLE lnurl_auth_sessions DROP COLUMN bound_at;
@@ -0,0 +1,2 @@
+-- a signed login challenge binds its linking key to an address only once
+ALTER TABLE lnurl_auth_sessions ADD COLUMN bound_at TIMESTAMP;
@@ -0,0 +1,223 @@
+use axum::{
+    async_trait,
+    body::{Bytes, HttpBody},
+    extract::{FromRequest, FromRequestParts},
+    http::{header::AUTHORIZATION, HeaderValue, Request, StatusCode},
+    BoxError,
+};
+use log::error;
+use nostr::{Event, JsonUtil, Kind, PublicKey};
+use serde::de::DeserializeOwned;
+use sha2::Digest;
+use url::Url;
+
+use crate::{
+    domains::{domain_url, RequestDomain},
+    lnurl_auth::session_scope,
+    models::domain::Domain,
+    signed_command::{check_signed_command, use_signed_command},
+    State,
+};
+
+/// A request authenticated with NIP-98: an `Authorization: Nostr <base64 event>`
+/// header carrying a kind 27235 event whose `u`, `method` and `payload` tags
+/// bind it to the exact URL, method and body of the request.
+///
+/// Each auth event is only accepted once. Requests can also carry an
+/// `Authorization: Bearer <token>` session from an LNURL-auth login, they
+/// act for the pubkey the linking key was bound to but only reach the
+/// addresses it was bound for.
+///
+/// The JSON body is parsed into `T`, an empty body parses as `null` or `{}`.
+pub struct Authenticated<T> {
+    pub pubkey: PublicKey,
+    pub method: AuthMethod,
+    /// The addresses a session is limited to, `None` for nostr auth
+    pub user_ids: Option<Vec<i32>>,
+    /// The domain the request was made to, which the url is checked against
+    pub domain: Domain,
+    pub body: T,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Nostr,
+    LnurlAuth,
+}
+
+#[async_trait]
+impl<S, B, T> FromRequest<S, B> for Authenticated<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let app_state = req
+            .extensions()
+            .get::<State>()
+            .cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()))?;
+
+        let (mut parts, body) = req.into_parts();
+        let RequestDomain(domain) = RequestDomain::from_request_parts(&mut parts, state).await?;
+        let req = Request::from_parts(parts, body);
+
+        let header = req.headers().get(AUTHORIZATION).cloned();
+        let method = req.method().to_string();
+        let url = format!(
+            "{}{}",
+            domain_url(&app_state, &domain),
+            req.uri().path_and_query().map_or("/", |p| p.as_str())
+        );
+
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid body".to_string()))?;
+
+        let (pubkey, auth_method, user_ids) = match bearer_token(header.as_ref()) {
+            Some(token) => match session_scope(&app_state, token) {
+                Ok(Some((pubkey, user_ids))) => (pubkey, AuthMethod::LnurlAuth, Some(user_ids)),
+                Ok(None) => return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string())),
+                Err(e) => {
+                    error!("Error checking session token: {e:?}");
+                    return Err((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()));
+                }
+            },
+            None => {
+                let event = parse_auth_header(header.as_ref())?;
+                verify_auth_event(&event, &url, &method, &body)?;
+                use_signed_command(&app_state, &event)?;
+                (event.author(), AuthMethod::Nostr, None)
+            }
+        };
+
+        let body = if body.is_empty() {
+            serde_json::from_slice(b"null").or_else(|_| serde_json::from_slice(b"{}"))
+        } else {
+            serde_json::from_slice(&body)
+        }
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid request".to_string()))?;
+
+        Ok(Self {
+            pubkey,
+            method: auth_method,
+            user_ids,
+            domain,
+            body,
+        })
+    }
+}
+
+fn bearer_token(header: Option<&HeaderValue>) -> Option<&str> {
+    header
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+fn parse_auth_header(header: Option<&HeaderValue>) -> Result<Event, (StatusCode, String)> {
+    let unauthorized = || (StatusCode::UNAUTHORIZED, "Unauthorized".to_string());
+
+    let header = header
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(unauthorized)?;
+    let encoded = header.strip_prefix("Nostr ").ok_or_else(unauthorized)?;
+    let json = base64::decode(encoded.trim()).map_err(|_| unauthorized())?;
+
+    Event::from_json(json).map_err(|_| unauthorized())
+}
+
+/// The value of the first tag of an event with the given name
+pub(crate) fn tag_value(event: &Event, name: &str) -> Option<String> {
+    event.tags.iter().find_map(|tag| {
+        let tag = tag.as_vec();
+        match (tag.first(), tag.get(1)) {
+            (Some(k), Some(v)) if k == name => Some(v.to_string()),
+            _ => None,
+        }
+    })
+}
+
+fn verify_auth_event(
+    event: &Event,
+    url: &str,
+    method: &str,
+    body: &[u8],
+) -> Result<(), (StatusCode, String)> {
+    let unauthorized = |reason: &str| {
+        error!("Invalid auth event {}: {reason}", event.id);
+        (StatusCode::UNAUTHORIZED, reason.to_string())
+    };
+
+    check_signed_command(event, Kind::HttpAuth)?;
+
+    let event_url = tag_value(event, "u").and_then(|u| Url::parse(&u).ok());
+    if event_url.is_none() || event_url != Url::parse(url).ok() {
+        return Err(unauthorized("Auth event url mismatch"));
+    }
+
+    if !tag_value(event, "method").is_some_and(|m| m.eq_ignore_ascii_case(method)) {
+        return Err(unauthorized("Auth event method mismatch"));
+    }
+
+    if !body.is_empty() {
+        let payload = hex::encode(sha2::Sha256::digest(body));
+        if !tag_value(event, "payload").is_some_and(|p| p.eq_ignore_ascii_case(&payload)) {
+            return Err(unauthorized("Auth event payload mismatch"));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, not(feature = "integration-tests")))]
+mod tests {
+    use nostr::{EventBuilder, Keys, Kind, Tag, TagKind};
+    use sha2::Digest;
+
+    use crate::auth::verify_auth_event;
+
+    const URL: &str = "https://hermes.test/v1/disable-zaps";
+
+    fn auth_event(kind: Kind, tags: &[(&str, &str)]) -> nostr::Event {
+        let tags = tags
+            .iter()
+            .map(|(k, v)| Tag::Generic(TagKind::Custom(k.to_string()), vec![v.to_string()]));
+        EventBuilder::new(kind, "", tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn check_auth_event() {
+        let body = br#"{"reason":"vacation"}"#;
+        let payload = hex::encode(sha2::Sha256::digest(body));
+
+        let event = auth_event(
+            Kind::HttpAuth,
+            &[("u", URL), ("method", "POST"), ("payload", &payload)],
+        );
+        assert!(verify_auth_event(&event, URL, "POST", body).is_ok());
+
+        // bound to the url, method and body
+        assert!(
+            verify_auth_event(&event, "https://hermes.test/v1/enable-zaps", "POST", body).is_err()
+        );
+        assert!(verify_auth_event(&event, URL, "GET", body).is_err());
+        assert!(verify_auth_event(&event, URL, "POST", b"{}").is_err());
+
+        // bodies need a payload tag
+        let event = auth_event(Kind::HttpAuth, &[("u", URL), ("method", "POST")]);
+        assert!(verify_auth_event(&event, URL, "POST", b"").is_ok());
+        assert!(verify_auth_event(&event, URL, "POST", body).is_err());
+
+        // other kinds are rejected
+        let event = auth_event(Kind::TextNote, &[("u", URL), ("method", "POST")]);
+        assert!(verify_auth_event(&event, URL, "POST", b"").is_err());
+    }
+}
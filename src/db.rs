@@ -1,3 +1,4 @@
+use chrono::NaiveDateTime;
 use diesel::{pg::PgConnection, r2d2::ConnectionManager, r2d2::Pool};
 use std::sync::Arc;
 
@@ -6,31 +7,151 @@ use mockall::{automock, predicate::*};
 
 use crate::models::{
     app_user::{AppUser, NewAppUser},
+    domain::{Domain, NewCustomDomain, NewDomain},
     invoice::{Invoice, NewInvoice},
+    lnurl_auth_session::{LnurlAuthPurpose, LnurlAuthSession, NewLnurlAuthSession},
+    renewal_token::NewRenewalToken,
+    reserved_name::{NewReservedName, ReservedName},
+    signing_key::{NewSigningKey, SigningKey},
+    used_event_id::NewUsedEventId,
     zaps::Zap,
 };
 
 #[cfg_attr(test, automock)]
 pub(crate) trait DBConnection {
-    fn check_name_available(&self, name: String) -> anyhow::Result<bool>;
+    fn check_name_available(&self, domain_id: i32, name: String) -> anyhow::Result<bool>;
     fn check_registered_pubkey(&self, pubkey: String) -> anyhow::Result<Option<String>>;
     fn get_user_by_token(&self, msg: String) -> anyhow::Result<Option<AppUser>>;
     fn insert_new_user(&self, name: NewAppUser) -> anyhow::Result<AppUser>;
     fn get_pending_invoices(&self) -> anyhow::Result<Vec<Invoice>>;
     fn insert_new_invoice(&self, invoice: NewInvoice) -> anyhow::Result<Invoice>;
     fn get_invoice_by_op_id(&self, id: String) -> anyhow::Result<Option<Invoice>>;
+    fn get_invoice_by_idempotency_key(
+        &self,
+        app_user_id: i32,
+        key: &str,
+    ) -> anyhow::Result<Option<Invoice>>;
     fn set_invoice_state(&self, invoice: Invoice, s: i32) -> anyhow::Result<()>;
-    fn get_user_by_name(&self, name: String) -> anyhow::Result<Option<AppUser>>;
+    fn get_unnotified_invoices(&self) -> anyhow::Result<Vec<Invoice>>;
+    fn set_invoice_notified(&self, invoice: Invoice) -> anyhow::Result<()>;
+    fn release_invoice_idempotency_key(&self, invoice: Invoice) -> anyhow::Result<()>;
+    fn cancel_invoice(&self, invoice: Invoice, reason: &str) -> anyhow::Result<bool>;
+    fn get_user_by_name(&self, domain_id: i32, name: String) -> anyhow::Result<Option<AppUser>>;
     fn get_user_by_id(&self, id: i32) -> anyhow::Result<Option<AppUser>>;
     fn get_user_by_pubkey(&self, pubkey: String) -> anyhow::Result<Option<AppUser>>;
+    fn get_users_by_pubkey(&self, pubkey: String) -> anyhow::Result<Vec<AppUser>>;
+    fn set_primary_user(&self, user: AppUser) -> anyhow::Result<()>;
+    fn get_user_by_redirect(&self, domain_id: i32, name: String)
+        -> anyhow::Result<Option<AppUser>>;
+    fn change_user_name(
+        &self,
+        user: AppUser,
+        new_name: String,
+        redirect_expires_at: NaiveDateTime,
+    ) -> anyhow::Result<()>;
     fn update_user_federation(
         &self,
         user: AppUser,
         federation_id: String,
         federation_invite_code: String,
     ) -> anyhow::Result<()>;
-    fn disable_user_zaps(&self, user: AppUser) -> anyhow::Result<()>;
-    fn get_user_and_increment_counter(&self, name: &str) -> anyhow::Result<Option<AppUser>>;
+    fn disable_user_zaps(
+        &self,
+        user: AppUser,
+        reason: Option<String>,
+        until: Option<NaiveDateTime>,
+    ) -> anyhow::Result<()>;
+    fn enable_user_zaps(&self, user: AppUser) -> anyhow::Result<()>;
+    fn set_user_success_action(
+        &self,
+        user: AppUser,
+        success_action: Option<String>,
+    ) -> anyhow::Result<()>;
+    fn set_user_profile(&self, user: AppUser, profile: Option<String>) -> anyhow::Result<()>;
+    fn set_user_linking_key(
+        &self,
+        user: AppUser,
+        linking_key: Option<String>,
+    ) -> anyhow::Result<()>;
+    fn get_users_by_linking_key(&self, linking_key: &str) -> anyhow::Result<Vec<AppUser>>;
+    fn insert_lnurl_auth_session(
+        &self,
+        session: NewLnurlAuthSession,
+    ) -> anyhow::Result<LnurlAuthSession>;
+    fn get_lnurl_auth_session(&self, k1: &str) -> anyhow::Result<Option<LnurlAuthSession>>;
+    fn get_lnurl_auth_session_by_token(
+        &self,
+        token_hash: &str,
+    ) -> anyhow::Result<Option<LnurlAuthSession>>;
+    fn authenticate_lnurl_auth_session(
+        &self,
+        k1: &str,
+        linking_key: &str,
+        purpose: LnurlAuthPurpose,
+        issued_after: NaiveDateTime,
+    ) -> anyhow::Result<bool>;
+    fn use_payer_auth_challenge(&self, k1: &str, linking_key: &str) -> anyhow::Result<bool>;
+    fn use_lnurl_auth_session_for_binding(
+        &self,
+        k1: &str,
+        signed_after: NaiveDateTime,
+    ) -> anyhow::Result<Option<String>>;
+    fn issue_lnurl_auth_token(
+        &self,
+        k1: &str,
+        token_hash: &str,
+        expires_at: NaiveDateTime,
+    ) -> anyhow::Result<bool>;
+    fn delete_stale_lnurl_auth_sessions(
+        &self,
+        issued_before: NaiveDateTime,
+    ) -> anyhow::Result<usize>;
+    fn delete_user(&self, user: AppUser) -> anyhow::Result<Vec<Invoice>>;
+    fn rotate_user_pubkey(&self, old_pubkey: String, new_pubkey: String) -> anyhow::Result<usize>;
+    fn get_user_and_increment_counter(
+        &self,
+        domain_id: i32,
+        name: &str,
+    ) -> anyhow::Result<Option<AppUser>>;
+    fn get_domain(&self, id: i32) -> anyhow::Result<Option<Domain>>;
+    fn get_domain_by_host(&self, host: &str) -> anyhow::Result<Option<Domain>>;
+    fn get_default_domain(&self) -> anyhow::Result<Domain>;
+    fn set_default_domain_host(&self, host: &str) -> anyhow::Result<()>;
+    fn upsert_domain(&self, domain: NewDomain) -> anyhow::Result<Domain>;
+    fn upsert_custom_domain(&self, domain: NewCustomDomain) -> anyhow::Result<Option<Domain>>;
+    fn get_reserved_names(&self) -> anyhow::Result<Vec<ReservedName>>;
+    fn get_signing_key(&self, key_id: String) -> anyhow::Result<Option<SigningKey>>;
+    fn get_unexpired_signing_keys(&self) -> anyhow::Result<Vec<SigningKey>>;
+    fn insert_signing_key(&self, key: NewSigningKey) -> anyhow::Result<SigningKey>;
+    fn retire_signing_key(
+        &self,
+        key_id: String,
+        valid_until: NaiveDateTime,
+    ) -> anyhow::Result<bool>;
+    fn backfill_name_skeletons(&self) -> anyhow::Result<usize>;
+    fn renew_user(
+        &self,
+        user: AppUser,
+        unblinded_msg: String,
+        expires_at: NaiveDateTime,
+    ) -> anyhow::Result<bool>;
+    fn is_renewal_token_spent(&self, unblinded_msg: String) -> anyhow::Result<bool>;
+    fn mark_event_used(
+        &self,
+        used: NewUsedEventId,
+        stale_before: NaiveDateTime,
+    ) -> anyhow::Result<bool>;
+    fn get_users_expiring_before(&self, cutoff: NaiveDateTime) -> anyhow::Result<Vec<AppUser>>;
+    fn get_users_expired_before(&self, cutoff: NaiveDateTime) -> anyhow::Result<Vec<AppUser>>;
+    fn set_user_expiry_reminded(&self, user: AppUser) -> anyhow::Result<()>;
+    fn downgrade_user_name(&self, user: AppUser, new_name: String) -> anyhow::Result<()>;
+    fn insert_reserved_name(&self, reserved: NewReservedName) -> anyhow::Result<ReservedName>;
+    fn delete_reserved_name(
+        &self,
+        kind: i32,
+        pattern: String,
+        domain_id: Option<i32>,
+    ) -> anyhow::Result<bool>;
     fn insert_new_zap(&self, new_zap: Zap) -> anyhow::Result<Zap>;
     fn get_zap_by_id(&self, id: i32) -> anyhow::Result<Option<Zap>>;
     fn set_zap_event_id(&self, zap: Zap, event_id: String) -> anyhow::Result<()>;
@@ -41,9 +162,9 @@ pub(crate) struct PostgresConnection {
 }
 
 impl DBConnection for PostgresConnection {
-    fn check_name_available(&self, name: String) -> anyhow::Result<bool> {
+    fn check_name_available(&self, domain_id: i32, name: String) -> anyhow::Result<bool> {
         let conn = &mut self.db.get()?;
-        AppUser::check_available_name(conn, name)
+        AppUser::check_available_name(conn, domain_id, name, None)
     }
 
     fn check_registered_pubkey(&self, pubkey: String) -> anyhow::Result<Option<String>> {
@@ -74,9 +195,140 @@ impl DBConnection for PostgresConnection {
         user.update_federation(conn, federation_id, federation_invite_code)
     }
 
-    fn disable_user_zaps(&self, user: AppUser) -> anyhow::Result<()> {
+    fn disable_user_zaps(
+        &self,
+        user: AppUser,
+        reason: Option<String>,
+        until: Option<NaiveDateTime>,
+    ) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        user.disable_zaps(conn, reason, until)
+    }
+
+    fn enable_user_zaps(&self, user: AppUser) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        user.enable_zaps(conn)
+    }
+
+    fn set_user_success_action(
+        &self,
+        user: AppUser,
+        success_action: Option<String>,
+    ) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        user.set_success_action(conn, success_action)
+    }
+
+    fn set_user_profile(&self, user: AppUser, profile: Option<String>) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        user.set_profile(conn, profile)
+    }
+
+    fn set_user_linking_key(
+        &self,
+        user: AppUser,
+        linking_key: Option<String>,
+    ) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        user.set_linking_key(conn, linking_key)
+    }
+
+    fn get_users_by_linking_key(&self, linking_key: &str) -> anyhow::Result<Vec<AppUser>> {
+        let conn = &mut self.db.get()?;
+        AppUser::get_by_linking_key(conn, linking_key)
+    }
+
+    fn insert_lnurl_auth_session(
+        &self,
+        session: NewLnurlAuthSession,
+    ) -> anyhow::Result<LnurlAuthSession> {
+        let conn = &mut self.db.get()?;
+        session.insert(conn)
+    }
+
+    fn get_lnurl_auth_session(&self, k1: &str) -> anyhow::Result<Option<LnurlAuthSession>> {
+        let conn = &mut self.db.get()?;
+        LnurlAuthSession::get_by_k1(conn, k1)
+    }
+
+    fn get_lnurl_auth_session_by_token(
+        &self,
+        token_hash: &str,
+    ) -> anyhow::Result<Option<LnurlAuthSession>> {
+        let conn = &mut self.db.get()?;
+        LnurlAuthSession::get_by_token_hash(conn, token_hash)
+    }
+
+    fn authenticate_lnurl_auth_session(
+        &self,
+        k1: &str,
+        linking_key: &str,
+        purpose: LnurlAuthPurpose,
+        issued_after: NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        let conn = &mut self.db.get()?;
+        LnurlAuthSession::authenticate(conn, k1, linking_key, purpose, issued_after)
+    }
+
+    fn use_payer_auth_challenge(&self, k1: &str, linking_key: &str) -> anyhow::Result<bool> {
+        let conn = &mut self.db.get()?;
+        LnurlAuthSession::use_payer_auth(conn, k1, linking_key)
+    }
+
+    fn use_lnurl_auth_session_for_binding(
+        &self,
+        k1: &str,
+        signed_after: NaiveDateTime,
+    ) -> anyhow::Result<Option<String>> {
+        let conn = &mut self.db.get()?;
+        LnurlAuthSession::use_for_binding(conn, k1, signed_after)
+    }
+
+    fn issue_lnurl_auth_token(
+        &self,
+        k1: &str,
+        token_hash: &str,
+        expires_at: NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        let conn = &mut self.db.get()?;
+        LnurlAuthSession::issue_token(conn, k1, token_hash, expires_at)
+    }
+
+    fn delete_stale_lnurl_auth_sessions(
+        &self,
+        issued_before: NaiveDateTime,
+    ) -> anyhow::Result<usize> {
+        let conn = &mut self.db.get()?;
+        LnurlAuthSession::delete_stale(conn, issued_before)
+    }
+
+    fn delete_user(&self, user: AppUser) -> anyhow::Result<Vec<Invoice>> {
+        let conn = &mut self.db.get()?;
+        user.delete(conn)
+    }
+
+    fn rotate_user_pubkey(&self, old_pubkey: String, new_pubkey: String) -> anyhow::Result<usize> {
+        let conn = &mut self.db.get()?;
+        AppUser::rotate_pubkey(conn, old_pubkey, new_pubkey)
+    }
+
+    fn get_user_by_redirect(
+        &self,
+        domain_id: i32,
+        name: String,
+    ) -> anyhow::Result<Option<AppUser>> {
+        let conn = &mut self.db.get()?;
+        AppUser::get_by_redirected_name(conn, domain_id, name)
+    }
+
+    fn change_user_name(
+        &self,
+        user: AppUser,
+        new_name: String,
+        redirect_expires_at: NaiveDateTime,
+    ) -> anyhow::Result<()> {
         let conn = &mut self.db.get()?;
-        user.disable_zaps(conn)
+        user.change_name(conn, new_name, redirect_expires_at)
     }
 
     fn get_pending_invoices(&self) -> anyhow::Result<Vec<Invoice>> {
@@ -89,9 +341,18 @@ impl DBConnection for PostgresConnection {
         Invoice::get_by_operation(conn, id)
     }
 
-    fn get_user_by_name(&self, name: String) -> anyhow::Result<Option<AppUser>> {
+    fn get_invoice_by_idempotency_key(
+        &self,
+        app_user_id: i32,
+        key: &str,
+    ) -> anyhow::Result<Option<Invoice>> {
+        let conn = &mut self.db.get()?;
+        Invoice::get_by_idempotency_key(conn, app_user_id, key)
+    }
+
+    fn get_user_by_name(&self, domain_id: i32, name: String) -> anyhow::Result<Option<AppUser>> {
         let conn = &mut self.db.get()?;
-        AppUser::get_by_name(conn, name)
+        AppUser::get_by_name(conn, domain_id, name)
     }
 
     fn get_user_by_pubkey(&self, pubkey: String) -> anyhow::Result<Option<AppUser>> {
@@ -99,14 +360,152 @@ impl DBConnection for PostgresConnection {
         AppUser::get_by_pubkey(conn, pubkey)
     }
 
+    fn get_users_by_pubkey(&self, pubkey: String) -> anyhow::Result<Vec<AppUser>> {
+        let conn = &mut self.db.get()?;
+        AppUser::get_all_by_pubkey(conn, pubkey)
+    }
+
+    fn set_primary_user(&self, user: AppUser) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        user.set_primary(conn)
+    }
+
+    fn renew_user(
+        &self,
+        user: AppUser,
+        unblinded_msg: String,
+        expires_at: NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        let conn = &mut self.db.get()?;
+        user.renew(conn, unblinded_msg, expires_at)
+    }
+
+    fn is_renewal_token_spent(&self, unblinded_msg: String) -> anyhow::Result<bool> {
+        let conn = &mut self.db.get()?;
+        NewRenewalToken::is_spent(conn, unblinded_msg)
+    }
+
+    fn mark_event_used(
+        &self,
+        used: NewUsedEventId,
+        stale_before: NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        let conn = &mut self.db.get()?;
+        NewUsedEventId::prune(conn, stale_before)?;
+        used.insert(conn)
+    }
+
+    fn get_users_expiring_before(&self, cutoff: NaiveDateTime) -> anyhow::Result<Vec<AppUser>> {
+        let conn = &mut self.db.get()?;
+        AppUser::get_expiring(conn, cutoff)
+    }
+
+    fn get_users_expired_before(&self, cutoff: NaiveDateTime) -> anyhow::Result<Vec<AppUser>> {
+        let conn = &mut self.db.get()?;
+        AppUser::get_expired(conn, cutoff)
+    }
+
+    fn set_user_expiry_reminded(&self, user: AppUser) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        user.set_expiry_reminded(conn)
+    }
+
+    fn downgrade_user_name(&self, user: AppUser, new_name: String) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        user.downgrade_name(conn, new_name)
+    }
+
+    fn backfill_name_skeletons(&self) -> anyhow::Result<usize> {
+        let conn = &mut self.db.get()?;
+        AppUser::backfill_name_skeletons(conn)
+    }
+
+    fn get_signing_key(&self, key_id: String) -> anyhow::Result<Option<SigningKey>> {
+        let conn = &mut self.db.get()?;
+        SigningKey::get_by_key_id(conn, key_id)
+    }
+
+    fn get_unexpired_signing_keys(&self) -> anyhow::Result<Vec<SigningKey>> {
+        let conn = &mut self.db.get()?;
+        SigningKey::get_unexpired(conn)
+    }
+
+    fn insert_signing_key(&self, key: NewSigningKey) -> anyhow::Result<SigningKey> {
+        let conn = &mut self.db.get()?;
+        key.insert(conn)
+    }
+
+    fn retire_signing_key(
+        &self,
+        key_id: String,
+        valid_until: NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        let conn = &mut self.db.get()?;
+        SigningKey::retire(conn, key_id, valid_until)
+    }
+
+    fn get_reserved_names(&self) -> anyhow::Result<Vec<ReservedName>> {
+        let conn = &mut self.db.get()?;
+        ReservedName::get_all(conn)
+    }
+
+    fn insert_reserved_name(&self, reserved: NewReservedName) -> anyhow::Result<ReservedName> {
+        let conn = &mut self.db.get()?;
+        reserved.insert(conn)
+    }
+
+    fn delete_reserved_name(
+        &self,
+        kind: i32,
+        pattern: String,
+        domain_id: Option<i32>,
+    ) -> anyhow::Result<bool> {
+        let conn = &mut self.db.get()?;
+        ReservedName::delete(conn, kind, pattern, domain_id)
+    }
+
     fn get_user_by_id(&self, id: i32) -> anyhow::Result<Option<AppUser>> {
         let conn = &mut self.db.get()?;
         AppUser::get_by_id(conn, id)
     }
 
-    fn get_user_and_increment_counter(&self, name: &str) -> anyhow::Result<Option<AppUser>> {
+    fn get_user_and_increment_counter(
+        &self,
+        domain_id: i32,
+        name: &str,
+    ) -> anyhow::Result<Option<AppUser>> {
+        let conn = &mut self.db.get()?;
+        AppUser::get_by_name_and_increment_counter(conn, domain_id, name)
+    }
+
+    fn get_domain(&self, id: i32) -> anyhow::Result<Option<Domain>> {
+        let conn = &mut self.db.get()?;
+        Domain::get_by_id(conn, id)
+    }
+
+    fn get_domain_by_host(&self, host: &str) -> anyhow::Result<Option<Domain>> {
+        let conn = &mut self.db.get()?;
+        Domain::get_by_host(conn, host)
+    }
+
+    fn get_default_domain(&self) -> anyhow::Result<Domain> {
+        let conn = &mut self.db.get()?;
+        Domain::get_default(conn)
+    }
+
+    fn set_default_domain_host(&self, host: &str) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        Domain::set_default_host(conn, host)
+    }
+
+    fn upsert_domain(&self, domain: NewDomain) -> anyhow::Result<Domain> {
+        let conn = &mut self.db.get()?;
+        domain.upsert(conn)
+    }
+
+    fn upsert_custom_domain(&self, domain: NewCustomDomain) -> anyhow::Result<Option<Domain>> {
         let conn = &mut self.db.get()?;
-        AppUser::get_by_name_and_increment_counter(conn, name)
+        domain.upsert(conn)
     }
 
     fn insert_new_invoice(&self, new_invoice: NewInvoice) -> anyhow::Result<Invoice> {
@@ -119,6 +518,26 @@ impl DBConnection for PostgresConnection {
         invoice.set_state(conn, s)
     }
 
+    fn get_unnotified_invoices(&self) -> anyhow::Result<Vec<Invoice>> {
+        let conn = &mut self.db.get()?;
+        Invoice::get_unnotified(conn)
+    }
+
+    fn set_invoice_notified(&self, invoice: Invoice) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        invoice.set_notified(conn)
+    }
+
+    fn release_invoice_idempotency_key(&self, invoice: Invoice) -> anyhow::Result<()> {
+        let conn = &mut self.db.get()?;
+        invoice.release_idempotency_key(conn)
+    }
+
+    fn cancel_invoice(&self, invoice: Invoice, reason: &str) -> anyhow::Result<bool> {
+        let conn = &mut self.db.get()?;
+        invoice.cancel(conn, reason)
+    }
+
     fn insert_new_zap(&self, new_zap: Zap) -> anyhow::Result<Zap> {
         let conn = &mut self.db.get()?;
         new_zap.insert(conn)
@@ -0,0 +1,94 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::Deserialize;
+
+#[cfg(test)]
+use mockall::automock;
+
+const DEFAULT_DOH_URL: &str = "https://cloudflare-dns.com/dns-query";
+const TXT_RECORD_TYPE: u16 = 16;
+
+#[cfg_attr(test, automock)]
+#[async_trait]
+pub(crate) trait TxtResolver {
+    /// The TXT records of a name, each with its strings joined
+    async fn lookup_txt(&self, name: &str) -> anyhow::Result<Vec<String>>;
+}
+
+/// Looks up records with the JSON API of a DNS over HTTPS server
+pub(crate) struct DohResolver {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl DohResolver {
+    pub fn new(url: Option<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.unwrap_or_else(|| DEFAULT_DOH_URL.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+#[async_trait]
+impl TxtResolver for DohResolver {
+    async fn lookup_txt(&self, name: &str) -> anyhow::Result<Vec<String>> {
+        let res: DohResponse = self
+            .client
+            .get(&self.url)
+            .query(&[("name", name), ("type", "TXT")])
+            .header("accept", "application/dns-json")
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // 0 is NOERROR, 3 is NXDOMAIN which just has no records
+        if res.status != 0 && res.status != 3 {
+            return Err(anyhow!("DNS lookup failed with status {}", res.status));
+        }
+
+        Ok(res
+            .answer
+            .into_iter()
+            .filter(|a| a.record_type == TXT_RECORD_TYPE)
+            .map(|a| parse_txt_data(&a.data))
+            .collect())
+    }
+}
+
+/// TXT data is given as quoted strings, long records are split into several
+fn parse_txt_data(data: &str) -> String {
+    data.trim().trim_matches('"').split("\" \"").collect()
+}
+
+#[cfg(all(test, not(feature = "integration-tests")))]
+mod tests {
+    use crate::dns::parse_txt_data;
+
+    #[tokio::test]
+    async fn check_parse_txt_data() {
+        assert_eq!(parse_txt_data("\"hermes-verify=abc\""), "hermes-verify=abc");
+        assert_eq!(
+            parse_txt_data("\"hermes-\" \"verify=abc\""),
+            "hermes-verify=abc"
+        );
+        assert_eq!(parse_txt_data("unquoted"), "unquoted");
+    }
+}
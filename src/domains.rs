@@ -0,0 +1,217 @@
+use anyhow::anyhow;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Host},
+    http::{request::Parts, StatusCode},
+};
+use chrono::Utc;
+use log::error;
+use nostr::Keys;
+use std::str::FromStr;
+
+use crate::{
+    models::{
+        app_user::AppUser,
+        domain::{Domain, NewCustomDomain, NewDomain},
+    },
+    register::{get_target_users, get_user_by_pubkey, is_valid_name, normalize_name},
+    State,
+};
+
+/// The domain a request was made to, from its `Host` header. Hosts that
+/// aren't registered get the default domain.
+pub struct RequestDomain(pub Domain);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for RequestDomain
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let app_state = parts
+            .extensions
+            .get::<State>()
+            .cloned()
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()))?;
+        let host = Host::from_request_parts(parts, state).await.ok();
+
+        match resolve_domain(&app_state, host.as_ref().map(|Host(h)| h.as_str())) {
+            Ok(domain) => Ok(RequestDomain(domain)),
+            Err(e) => {
+                error!("Error resolving domain {host:?}: {e:?}");
+                Err((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()))
+            }
+        }
+    }
+}
+
+pub fn resolve_domain(state: &State, host: Option<&str>) -> anyhow::Result<Domain> {
+    match host {
+        Some(host) => match state.db.get_domain_by_host(host)? {
+            Some(domain) => Ok(domain),
+            None => state.db.get_default_domain(),
+        },
+        None => state.db.get_default_domain(),
+    }
+}
+
+/// The domain registered for a host, without falling back to the default
+pub fn find_domain(state: &State, host: &str) -> anyhow::Result<Domain> {
+    state
+        .db
+        .get_domain_by_host(&normalize_host(host)?)?
+        .ok_or(anyhow!("Domain not found"))
+}
+
+pub fn get_default_domain(state: &State) -> anyhow::Result<Domain> {
+    state.db.get_default_domain()
+}
+
+/// The domain a user's name is registered under
+pub fn get_user_domain(state: &State, user: &AppUser) -> anyhow::Result<Domain> {
+    state
+        .db
+        .get_domain(user.domain_id)?
+        .ok_or(anyhow!("Domain not found"))
+}
+
+/// Whether requests made to the domain reach the user: the users registered
+/// under it, or only the owner on a user's own domain
+pub fn serves_user(domain: &Domain, user: &AppUser) -> bool {
+    match domain.owner_user_id {
+        Some(owner_user_id) => user.id == owner_user_id,
+        None => user.domain_id == domain.id,
+    }
+}
+
+/// Base url of the domain, without a trailing slash
+pub fn domain_url(state: &State, domain: &Domain) -> String {
+    if domain.is_default {
+        state.domain.clone()
+    } else {
+        format!("https://{}", domain.host)
+    }
+}
+
+/// The key zap receipts for the domain are signed with
+pub fn domain_keys(state: &State, domain: &Domain) -> Keys {
+    domain
+        .nostr_nsec
+        .as_ref()
+        .and_then(|nsec| Keys::from_str(nsec).ok())
+        .unwrap_or_else(|| state.nostr_sk.clone())
+}
+
+fn normalize_host(host: &str) -> anyhow::Result<String> {
+    let host = host.trim().to_lowercase();
+    if host.is_empty() || host.len() > 255 || host.contains('/') {
+        return Err(anyhow!("Invalid host"));
+    }
+
+    Ok(host)
+}
+
+pub fn add_domain(
+    state: &State,
+    host: String,
+    nostr_nsec: Option<String>,
+    lnurl_description: Option<String>,
+    allowed_origins: Vec<String>,
+) -> anyhow::Result<Domain> {
+    let host = normalize_host(&host)?;
+    if let Some(ref nsec) = nostr_nsec {
+        Keys::from_str(nsec).map_err(|_| anyhow!("Invalid nostr key"))?;
+    }
+    if lnurl_description.as_ref().is_some_and(|d| d.len() > 255) {
+        return Err(anyhow!("Description too long"));
+    }
+
+    state.db.upsert_domain(NewDomain {
+        host,
+        nostr_nsec,
+        lnurl_description,
+        allowed_origins,
+    })
+}
+
+/// A user's request to serve one of their addresses on a domain they own
+pub struct DomainClaim {
+    pub host: String,
+    /// The name served on the domain, `name@host`
+    pub name: String,
+    /// The address payments to the name go to
+    pub owner: AppUser,
+}
+
+/// The TXT record the owner of a domain sets to prove they control it
+pub fn verification_record(claim: &DomainClaim) -> (String, String) {
+    (
+        format!("_hermes.{}", claim.host),
+        format!("hermes-verify={}", claim.owner.pubkey),
+    )
+}
+
+/// Checks a claim of `pubkey` for `host`. The address is the one named by
+/// `address`, or their primary one, out of `user_ids` for a session and
+/// out of the ones `domain` serves.
+pub fn check_domain_claim(
+    state: &State,
+    pubkey: String,
+    host: String,
+    name: String,
+    address: Option<String>,
+    user_ids: Option<&[i32]>,
+    domain: &Domain,
+) -> anyhow::Result<DomainClaim> {
+    let host = normalize_host(&host)?;
+    if !host.contains('.') {
+        return Err(anyhow!("Invalid host"));
+    }
+    if state
+        .db
+        .get_domain_by_host(&host)?
+        .is_some_and(|d| d.owner_user_id.is_none())
+    {
+        return Err(anyhow!("Unavailable"));
+    }
+
+    let name = normalize_name(&name);
+    if !is_valid_name(&name) {
+        return Err(anyhow!("Invalid name"));
+    }
+
+    let owner = match address {
+        Some(address) => get_target_users(state, pubkey, Some(address), user_ids, Some(domain))?
+            .into_iter()
+            .next(),
+        None => get_user_by_pubkey(state, pubkey)?
+            .filter(|u| user_ids.map_or(true, |ids| ids.contains(&u.id)))
+            .filter(|u| serves_user(domain, u)),
+    }
+    .ok_or(anyhow!("User not found"))?;
+
+    Ok(DomainClaim { host, name, owner })
+}
+
+/// Looks for the verification record of a claim and starts serving the
+/// domain once it's there. A domain verified again by someone else goes
+/// to them, since they are the one in control of it now.
+pub async fn verify_custom_domain(state: &State, claim: DomainClaim) -> anyhow::Result<Domain> {
+    let (record_name, record_value) = verification_record(&claim);
+    let records = state.resolver.lookup_txt(&record_name).await?;
+    if !records.contains(&record_value) {
+        return Err(anyhow!("Verification record not found"));
+    }
+
+    state
+        .db
+        .upsert_custom_domain(NewCustomDomain {
+            host: claim.host,
+            owner_user_id: claim.owner.id,
+            owner_name: claim.name,
+            verified_at: Utc::now().naive_utc(),
+        })?
+        .ok_or(anyhow!("Unavailable"))
+}
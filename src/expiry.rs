@@ -0,0 +1,99 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::Result;
+use chrono::Utc;
+use log::{error, info};
+use nostr::PublicKey;
+
+use crate::{
+    domains::get_user_domain, models::app_user::AppUser, register::generate_random_name, State,
+};
+
+/// How long before a paid name expires its owner gets a reminder
+const RENEWAL_REMINDER_DAYS: i64 = 30;
+
+/// How long an expired paid name keeps receiving before it is downgraded
+const NAME_GRACE_PERIOD_DAYS: i64 = 14;
+
+/// How often expiring names are checked for
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically reminds users of expiring paid names and downgrades the
+/// ones that are past their grace period to a random free name.
+pub(crate) async fn handle_name_expiry(state: State) {
+    loop {
+        if let Err(e) = process_name_expiry(&state).await {
+            error!("Error processing name expiry: {e}");
+        }
+
+        tokio::time::sleep(EXPIRY_CHECK_INTERVAL).await;
+    }
+}
+
+async fn process_name_expiry(state: &State) -> Result<()> {
+    let now = Utc::now().naive_utc();
+
+    let reminder_cutoff = now + chrono::Duration::days(RENEWAL_REMINDER_DAYS);
+    // one user failing doesn't hold up the rest of the batch
+    for user in state.db.get_users_expiring_before(reminder_cutoff)? {
+        let name = user.name.clone();
+        if let Err(e) = remind_user(state, user).await {
+            error!("Error reminding {name} of expiry: {e}");
+        }
+    }
+
+    let grace_cutoff = now - chrono::Duration::days(NAME_GRACE_PERIOD_DAYS);
+    for user in state.db.get_users_expired_before(grace_cutoff)? {
+        let name = user.name.clone();
+        if let Err(e) = downgrade_user(state, user).await {
+            error!("Error downgrading expired name {name}: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn remind_user(state: &State, user: AppUser) -> Result<()> {
+    let expires_at = user.expires_at.expect("only expiring users");
+    let domain = get_user_domain(state, &user)?;
+    let msg = format!(
+        "Your address {}@{} expires on {}. Renew it with a new paid token to keep it, \
+        {NAME_GRACE_PERIOD_DAYS} days after it expires it will be replaced with a random name.",
+        user.name,
+        domain.host,
+        expires_at.format("%Y-%m-%d"),
+    );
+    match send_dm(state, &user, msg).await {
+        Ok(_) => state.db.set_user_expiry_reminded(user)?,
+        Err(e) => error!("Error sending expiry reminder to {}: {e}", user.name),
+    }
+
+    Ok(())
+}
+
+async fn downgrade_user(state: &State, user: AppUser) -> Result<()> {
+    let domain = get_user_domain(state, &user)?;
+    let new_name = generate_random_name(state, &domain)?;
+    state
+        .db
+        .downgrade_user_name(user.clone(), new_name.clone())?;
+    info!("Downgraded expired name {} to {new_name}", user.name);
+
+    let msg = format!(
+        "Your address {}@{} expired and was replaced with {new_name}@{}.",
+        user.name, domain.host, domain.host,
+    );
+    if let Err(e) = send_dm(state, &user, msg).await {
+        error!("Error sending downgrade notice to {new_name}: {e}");
+    }
+
+    Ok(())
+}
+
+async fn send_dm(state: &State, user: &AppUser, msg: String) -> Result<()> {
+    state
+        .nostr
+        .send_direct_msg(PublicKey::from_str(&user.pubkey)?, msg, None)
+        .await?;
+    Ok(())
+}
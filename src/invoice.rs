@@ -1,4 +1,9 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, Result};
 use fedimint_client::oplog::UpdateStreamOrOutcome;
@@ -11,8 +16,10 @@ use nostr::{Event, EventBuilder, JsonUtil};
 use nostr_sdk::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tokio::sync::{broadcast, oneshot};
 
 use crate::{
+    domains::{domain_keys, get_user_domain},
     models::{app_user::AppUser, invoice::Invoice},
     State,
 };
@@ -28,6 +35,100 @@ pub enum InvoiceState {
     Cancelled = 2,
 }
 
+/// How often pending invoices are checked for expiry
+const INVOICE_SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long past its expiry an invoice is still waited on, a payment made
+/// right before it expired can take a while to be claimed
+const INVOICE_EXPIRY_GRACE: Duration = Duration::from_secs(10 * 60);
+
+const EXPIRED_CANCEL_REASON: &str = "Expired unpaid";
+
+/// Updates buffered for each listener before it starts missing some
+const INVOICE_UPDATES_CAPACITY: usize = 1_024;
+
+/// A pending invoice being paid or cancelled, as the subscription tasks see it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvoiceUpdate {
+    pub op_id: String,
+    /// Set once the invoice is paid, `None` if it was cancelled
+    pub preimage: Option<String>,
+}
+
+/// Stops the subscription tasks of pending invoices, by invoice id, and
+/// tells listeners what happened to them
+#[derive(Clone)]
+pub struct InvoiceSubscriptions {
+    stops: Arc<Mutex<HashMap<i32, oneshot::Sender<()>>>>,
+    updates: broadcast::Sender<InvoiceUpdate>,
+}
+
+impl Default for InvoiceSubscriptions {
+    fn default() -> Self {
+        Self {
+            stops: Arc::new(Mutex::new(HashMap::new())),
+            updates: broadcast::channel(INVOICE_UPDATES_CAPACITY).0,
+        }
+    }
+}
+
+impl InvoiceSubscriptions {
+    /// Listens for invoices being paid or cancelled from now on
+    pub fn updates(&self) -> broadcast::Receiver<InvoiceUpdate> {
+        self.updates.subscribe()
+    }
+
+    fn publish(&self, op_id: &str, preimage: Option<String>) {
+        // no one listening is fine
+        let _ = self.updates.send(InvoiceUpdate {
+            op_id: op_id.to_string(),
+            preimage,
+        });
+    }
+
+    fn register(&self, invoice_id: i32) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.stops
+            .lock()
+            .expect("subscriptions lock poisoned")
+            .insert(invoice_id, tx);
+        rx
+    }
+
+    fn remove(&self, invoice_id: i32) {
+        self.stops
+            .lock()
+            .expect("subscriptions lock poisoned")
+            .remove(&invoice_id);
+    }
+
+    /// Stops an invoice's subscription, false if it didn't have a running one
+    fn cancel(&self, invoice_id: i32) -> bool {
+        let tx = self
+            .stops
+            .lock()
+            .expect("subscriptions lock poisoned")
+            .remove(&invoice_id);
+        tx.is_some_and(|tx| tx.send(()).is_ok())
+    }
+
+    fn is_running(&self, invoice_id: i32) -> bool {
+        self.stops
+            .lock()
+            .expect("subscriptions lock poisoned")
+            .contains_key(&invoice_id)
+    }
+}
+
+/// Stops following invoices cancelled outside of the sweeper, and tells
+/// listeners they won't be paid
+pub fn stop_cancelled_invoices(state: &State, invoices: &[Invoice]) {
+    for invoice in invoices {
+        state.subscriptions.publish(&invoice.op_id, None);
+        state.subscriptions.cancel(invoice.id);
+    }
+}
+
 /// Starts subscription for all pending invoices from previous run
 pub(crate) async fn handle_pending_invoices(state: &State) -> Result<()> {
     let invoices = state.db.get_pending_invoices()?;
@@ -47,11 +148,14 @@ pub(crate) async fn handle_pending_invoices(state: &State) -> Result<()> {
                 let ln = client.get_first_module::<LightningClientModule>();
                 for invoice in invoices {
                     // Check if invoice has expired
-                    if invoice.bolt11().is_expired() {
-                        state
-                            .db
-                            .set_invoice_state(invoice, InvoiceState::Cancelled as i32)?;
-                        continue;
+                    match past_expiry_grace(&invoice) {
+                        Ok(true) => {
+                            state.db.cancel_invoice(invoice, EXPIRED_CANCEL_REASON)?;
+                            continue;
+                        }
+                        Ok(false) => (),
+                        // still followed, it can be claimed all the same
+                        Err(e) => error!("Error reading pending invoice {}: {e}", invoice.id),
                     }
 
                     // Create subscription to operation if it exists
@@ -85,17 +189,30 @@ pub(crate) async fn spawn_invoice_subscription(
     user: AppUser,
     subscription: UpdateStreamOrOutcome<LnReceiveState>,
 ) {
+    let invoice_id = i.id;
+    let mut stopped = state.subscriptions.register(invoice_id);
     spawn("waiting for invoice being paid", async move {
-        let nostr = state.nostr.clone();
         let mut stream = subscription.into_stream();
-        while let Some(op_state) = stream.next().await {
+        loop {
+            let op_state = tokio::select! {
+                _ = &mut stopped => {
+                    info!("Stopped waiting for invoice {invoice_id}");
+                    break;
+                }
+                op_state = stream.next() => match op_state {
+                    Some(op_state) => op_state,
+                    None => break,
+                },
+            };
+
             // TODO if anything fails here, try again
             match op_state {
                 LnReceiveState::Canceled { reason } => {
                     error!("Payment canceled, reason: {:?}", reason);
+                    state.subscriptions.publish(&i.op_id, None);
                     match state
                         .db
-                        .set_invoice_state(i, InvoiceState::Cancelled as i32)
+                        .cancel_invoice(i, &format!("Canceled by the federation: {reason:?}"))
                     {
                         Ok(_) => (),
                         Err(e) => {
@@ -106,18 +223,20 @@ pub(crate) async fn spawn_invoice_subscription(
                 }
                 LnReceiveState::Claimed => {
                     info!("Payment claimed");
-                    match notify_user(&nostr, &state, &i, user).await {
-                        Ok(_) => {
-                            match state.db.set_invoice_state(i, InvoiceState::Settled as i32) {
-                                Ok(_) => (),
-                                Err(e) => {
-                                    error!("Error setting invoice as settled: {:?}", e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("Error notifying user of ecash: {:?}", e);
-                        }
+                    // payers waiting on the invoice don't need to wait on the user's DM
+                    state
+                        .subscriptions
+                        .publish(&i.op_id, Some(i.preimage.clone()));
+                    // recorded before the user is told, a failed DM is retried
+                    // by the sweeper rather than the paid invoice expiring
+                    if let Err(e) = state
+                        .db
+                        .set_invoice_state(i.clone(), InvoiceState::Settled as i32)
+                    {
+                        error!("Error setting invoice as settled: {:?}", e);
+                    }
+                    if let Err(e) = notify_settled_user(&state, i, user).await {
+                        error!("Error notifying user of ecash: {:?}", e);
                     }
 
                     break;
@@ -125,9 +244,101 @@ pub(crate) async fn spawn_invoice_subscription(
                 _ => {}
             }
         }
+
+        state.subscriptions.remove(invoice_id);
     });
 }
 
+/// Periodically cancels pending invoices that expired unpaid, and stops
+/// waiting on them. Users that weren't told of a paid invoice are retried.
+pub(crate) async fn sweep_expired_invoices(state: State) {
+    loop {
+        tokio::time::sleep(INVOICE_SWEEP_INTERVAL).await;
+
+        match cancel_expired_invoices(&state) {
+            Ok(0) => (),
+            Ok(n) => info!("Cancelled {n} expired invoices"),
+            Err(e) => error!("Error cancelling expired invoices: {e}"),
+        }
+
+        match notify_settled_invoices(&state).await {
+            Ok(0) => (),
+            Ok(n) => info!("Notified users of {n} settled invoices"),
+            Err(e) => error!("Error notifying users of settled invoices: {e}"),
+        }
+    }
+}
+
+/// Whether an invoice expired longer than the grace period ago. One that
+/// can't be read is an error, not expired.
+fn past_expiry_grace(invoice: &Invoice) -> Result<bool> {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    Ok(invoice
+        .bolt11()?
+        .would_expire(since_epoch.saturating_sub(INVOICE_EXPIRY_GRACE)))
+}
+
+fn cancel_expired_invoices(state: &State) -> Result<usize> {
+    let mut cancelled = 0;
+    for invoice in state.db.get_pending_invoices()? {
+        match past_expiry_grace(&invoice) {
+            Ok(true) => (),
+            Ok(false) => continue,
+            Err(e) => {
+                error!("Error reading pending invoice {}: {e}", invoice.id);
+                continue;
+            }
+        }
+
+        if cancel_expired_invoice(state, invoice)? {
+            cancelled += 1;
+        }
+    }
+
+    Ok(cancelled)
+}
+
+/// Gives up on an expired invoice, false if it wasn't pending anymore
+fn cancel_expired_invoice(state: &State, invoice: Invoice) -> Result<bool> {
+    let invoice_id = invoice.id;
+    let op_id = invoice.op_id.clone();
+    let cancelled = state.db.cancel_invoice(invoice, EXPIRED_CANCEL_REASON)?;
+    if cancelled {
+        state.subscriptions.publish(&op_id, None);
+    }
+    state.subscriptions.cancel(invoice_id);
+
+    Ok(cancelled)
+}
+
+async fn notify_settled_invoices(state: &State) -> Result<usize> {
+    let mut notified = 0;
+    for invoice in state.db.get_unnotified_invoices()? {
+        // the subscription that settled it may still be telling the user
+        if state.subscriptions.is_running(invoice.id) {
+            continue;
+        }
+
+        let invoice_id = invoice.id;
+        let user = state
+            .db
+            .get_user_by_id(invoice.app_user_id)?
+            .ok_or(anyhow!("no user"))?;
+        match notify_settled_user(state, invoice, user).await {
+            Ok(_) => notified += 1,
+            Err(e) => error!("Error notifying user of invoice {invoice_id}: {e:?}"),
+        }
+    }
+
+    Ok(notified)
+}
+
+/// Tells the user of a paid invoice, and records that they were told
+async fn notify_settled_user(state: &State, invoice: Invoice, user: AppUser) -> Result<()> {
+    notify_user(&state.nostr, state, &invoice, user).await?;
+    state.db.set_invoice_notified(invoice)
+}
+
 async fn notify_user(
     nostr: &Client,
     state: &State,
@@ -136,22 +347,47 @@ async fn notify_user(
 ) -> Result<()> {
     let zap = state.db.get_zap_by_id(invoice.id)?;
 
+    let payment = json!({
+        "federation_id": invoice.federation_id,
+        "tweak_index": invoice.user_invoice_index,
+        "pubkey": invoice.pubkey,
+        "amount": invoice.amount,
+        "bolt11": invoice.bolt11,
+        "preimage": invoice.preimage,
+        "comment": invoice.comment,
+        "payer_data": invoice
+            .payer_data
+            .as_deref()
+            .and_then(|p| serde_json::from_str::<serde_json::Value>(p).ok()),
+        "zap_request": zap.as_ref().map(|z| z.request.clone()),
+    })
+    .to_string();
+
+    // the ecash is tweaked for the key the invoice was created for
     let dm = nostr
         .send_direct_msg(
-            ::nostr::PublicKey::from_str(&user.pubkey)?,
-            json!({
-                "federation_id": invoice.federation_id,
-                "tweak_index": invoice.user_invoice_index,
-                "amount": invoice.amount,
-                "bolt11": invoice.bolt11,
-                "preimage": invoice.preimage,
-                "zap_request": zap.as_ref().map(|z| z.request.clone()),
-            })
-            .to_string(),
+            ::nostr::PublicKey::from_str(&invoice.pubkey)?,
+            payment.clone(),
             None,
         )
         .await?;
 
+    // if the user has rotated their key since, let their new key know as well
+    let current_pubkey = state
+        .db
+        .get_user_by_id(user.id)?
+        .map(|u| u.pubkey)
+        .unwrap_or(user.pubkey);
+    if current_pubkey != invoice.pubkey {
+        nostr
+            .send_direct_msg(
+                ::nostr::PublicKey::from_str(&current_pubkey)?,
+                payment,
+                None,
+            )
+            .await?;
+    }
+
     // Send zap if needed
     if let Some(zap) = zap {
         let request = Event::from_json(&zap.request)?;
@@ -160,7 +396,7 @@ async fn notify_user(
             Some(invoice.preimage.clone()),
             request,
         )
-        .to_event(&state.nostr_sk)?;
+        .to_event(&domain_keys(state, &get_user_domain(state, &user)?))?;
 
         let event_id = nostr.send_event(event).await?;
         info!("Broadcasted zap {event_id}!");
@@ -171,3 +407,77 @@ async fn notify_user(
     info!("Sent nostr dm: {dm}");
     Ok(())
 }
+
+#[cfg(all(test, not(feature = "integration-tests")))]
+mod tests {
+    use crate::{
+        invoice::{past_expiry_grace, InvoiceState, InvoiceSubscriptions, InvoiceUpdate},
+        models::invoice::Invoice,
+    };
+
+    #[tokio::test]
+    async fn check_invoice_updates() {
+        let subscriptions = InvoiceSubscriptions::default();
+        // nothing listening yet
+        subscriptions.publish("op1", None);
+
+        let mut updates = subscriptions.updates();
+        subscriptions.publish("op2", Some("preimage".to_string()));
+        subscriptions.publish("op3", None);
+
+        assert_eq!(
+            updates.recv().await.unwrap(),
+            InvoiceUpdate {
+                op_id: "op2".to_string(),
+                preimage: Some("preimage".to_string()),
+            }
+        );
+        assert_eq!(updates.recv().await.unwrap().op_id, "op3");
+        assert!(updates.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn check_subscription_cancel() {
+        let subscriptions = InvoiceSubscriptions::default();
+        let mut stopped = subscriptions.register(1);
+        assert!(stopped.try_recv().is_err());
+        assert!(subscriptions.is_running(1));
+
+        // only running subscriptions can be stopped, and only once
+        assert!(!subscriptions.cancel(2));
+        assert!(subscriptions.cancel(1));
+        assert!(stopped.try_recv().is_ok());
+        assert!(!subscriptions.cancel(1));
+        assert!(!subscriptions.is_running(1));
+
+        // a finished subscription has nothing to stop
+        let _stopped = subscriptions.register(3);
+        subscriptions.remove(3);
+        assert!(!subscriptions.cancel(3));
+    }
+
+    #[tokio::test]
+    async fn check_unreadable_invoice_not_expired() {
+        let invoice = Invoice {
+            id: 1,
+            federation_id: "".to_string(),
+            op_id: "op1".to_string(),
+            preimage: "00".repeat(32),
+            app_user_id: 1,
+            user_invoice_index: 0,
+            bolt11: "lnbc1".to_string(),
+            amount: 1_000,
+            state: InvoiceState::Pending as i32,
+            pubkey: "".to_string(),
+            comment: None,
+            payer_data: None,
+            idempotency_key: None,
+            cancelled_at: None,
+            cancel_reason: None,
+            notified_at: None,
+        };
+
+        // left pending for someone to look at instead of cancelled
+        assert!(past_expiry_grace(&invoice).is_err());
+    }
+}
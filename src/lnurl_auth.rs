@@ -0,0 +1,285 @@
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
+use log::{error, info};
+use nostr::{prelude::rand, PublicKey};
+use secp256k1::{
+    ecdsa::Signature,
+    hashes::{hmac, sha256, Hash, HashEngine},
+    All, Message, Secp256k1,
+};
+use sha2::Digest;
+use std::str::FromStr;
+
+use crate::{
+    domains::domain_url,
+    models::{
+        domain::Domain,
+        lnurl_auth_session::{LnurlAuthPurpose, NewLnurlAuthSession},
+    },
+    register::get_target_users,
+    State,
+};
+
+/// How long a wallet has to sign a login challenge
+const LNURL_AUTH_CHALLENGE_MINUTES: i64 = 10;
+
+/// How long a session token from an LNURL-auth login is valid for
+const LNURL_AUTH_SESSION_HOURS: i64 = 24;
+
+/// How long a payer has from the pay request to the callback to sign its
+/// payer auth challenge
+const PAYER_AUTH_CHALLENGE_MINUTES: i64 = 60;
+
+/// How often challenges that can't be used anymore are deleted
+const STALE_CHALLENGE_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+fn random_hex() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(sha2::Sha256::digest(token.as_bytes()))
+}
+
+/// Checks a LUD-04 signature: the DER encoded `sig` of `k1` by the
+/// compressed secp256k1 `key`, all in hex
+pub(crate) fn verify_linking_key_sig(
+    secp: &Secp256k1<All>,
+    k1: &str,
+    key: &str,
+    sig: &str,
+) -> bool {
+    let key = secp256k1::PublicKey::from_str(key);
+    let sig = hex::decode(sig)
+        .ok()
+        .and_then(|s| Signature::from_der(&s).ok());
+    let msg = hex::decode(k1)
+        .ok()
+        .and_then(|k1| Message::from_digest_slice(&k1).ok());
+
+    match (key, sig, msg) {
+        (Ok(key), Some(sig), Some(msg)) => secp.verify_ecdsa(&msg, &sig, &key).is_ok(),
+        _ => false,
+    }
+}
+
+/// Starts a login, returns the challenge and the LUD-04 url for the wallet
+pub fn new_challenge(state: &State, domain: &Domain) -> anyhow::Result<(String, String)> {
+    let session = state.db.insert_lnurl_auth_session(NewLnurlAuthSession {
+        k1: random_hex(),
+        purpose: LnurlAuthPurpose::Login as i32,
+    })?;
+    let url = format!(
+        "{}/v1/lnurl-auth?tag=login&k1={}&action=login",
+        domain_url(state, domain),
+        session.k1
+    );
+
+    Ok((session.k1, url))
+}
+
+/// Handles the wallet's signed challenge
+pub fn login(state: &State, k1: &str, sig: &str, key: &str) -> anyhow::Result<()> {
+    if !verify_linking_key_sig(&state.secp, k1, key, sig) {
+        return Err(anyhow!("Invalid signature"));
+    }
+
+    let issued_after = Utc::now().naive_utc() - Duration::minutes(LNURL_AUTH_CHALLENGE_MINUTES);
+    if !state
+        .db
+        .authenticate_lnurl_auth_session(k1, key, LnurlAuthPurpose::Login, issued_after)?
+    {
+        return Err(anyhow!("Unknown or expired challenge"));
+    }
+
+    Ok(())
+}
+
+/// MAC of a payer auth challenge's issue time and nonce, keyed by the
+/// service's nostr key
+fn payer_auth_mac(state: &State, payload: &[u8]) -> anyhow::Result<[u8; 16]> {
+    let secret = state.nostr_sk.secret_key()?.secret_bytes();
+    let key = sha256::Hash::hash(&[b"payer auth".as_slice(), &secret].concat());
+
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(key.as_byte_array());
+    engine.input(payload);
+    let mac = hmac::Hmac::<sha256::Hash>::from_engine(engine);
+
+    let mut truncated = [0u8; 16];
+    truncated.copy_from_slice(&mac.as_byte_array()[..16]);
+    Ok(truncated)
+}
+
+/// A fresh challenge for the `auth` field of a pay request's LUD-18 payer
+/// data, the time it was issued and a nonce followed by their MAC. Nothing
+/// is stored until a payer uses it, so pay requests don't write anything.
+pub fn new_payer_auth_challenge(state: &State) -> anyhow::Result<String> {
+    let mut payload = [0u8; 16];
+    payload[..8].copy_from_slice(&Utc::now().timestamp().to_be_bytes());
+    payload[8..].copy_from_slice(&rand::random::<[u8; 8]>());
+    let mac = payer_auth_mac(state, &payload)?;
+
+    Ok(hex::encode([payload.as_slice(), &mac].concat()))
+}
+
+/// Uses up a payer auth challenge the `key` signed, false if it wasn't
+/// handed out, expired or was already used. It can only be signed once, and
+/// never grants a session.
+pub(crate) fn use_payer_auth_challenge(state: &State, k1: &str, key: &str) -> anyhow::Result<bool> {
+    let challenge = match hex::decode(k1) {
+        Ok(challenge) if challenge.len() == 32 => challenge,
+        _ => return Ok(false),
+    };
+    let (payload, mac) = challenge.split_at(16);
+    let expected = payer_auth_mac(state, payload)?;
+    if mac
+        .iter()
+        .zip(expected)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        != 0
+    {
+        return Ok(false);
+    }
+
+    let issued_at = i64::from_be_bytes(payload[..8].try_into()?);
+    let issued_after = Utc::now() - Duration::minutes(PAYER_AUTH_CHALLENGE_MINUTES);
+    if issued_at <= issued_after.timestamp() {
+        return Ok(false);
+    }
+
+    state.db.use_payer_auth_challenge(k1, key)
+}
+
+/// Gives out the session token of a signed challenge, only once. Returns
+/// the token and when it expires.
+pub fn issue_session_token(state: &State, k1: &str) -> anyhow::Result<(String, u64)> {
+    let session = state
+        .db
+        .get_lnurl_auth_session(k1)?
+        .ok_or(anyhow!("Unknown challenge"))?;
+    if session.linking_key.is_none() {
+        return Err(anyhow!("Login pending"));
+    }
+
+    let token = random_hex();
+    let expires_at = Utc::now().naive_utc() + Duration::hours(LNURL_AUTH_SESSION_HOURS);
+    if !state
+        .db
+        .issue_lnurl_auth_token(k1, &hash_token(&token), expires_at)?
+    {
+        return Err(anyhow!("Session already issued"));
+    }
+
+    Ok((token, expires_at.and_utc().timestamp() as u64))
+}
+
+/// The nostr pubkey a session token acts for and the ids of the addresses
+/// its linking key is bound to, the only ones it can manage
+pub fn session_scope(state: &State, token: &str) -> anyhow::Result<Option<(PublicKey, Vec<i32>)>> {
+    let linking_key = match state
+        .db
+        .get_lnurl_auth_session_by_token(&hash_token(token))?
+        .and_then(|s| s.linking_key)
+    {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+
+    let users = state.db.get_users_by_linking_key(&linking_key)?;
+    match users.first() {
+        Some(user) => Ok(Some((
+            PublicKey::from_str(&user.pubkey)?,
+            users.iter().map(|u| u.id).collect(),
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Lets the linking key that signed `k1` manage the pubkey's targeted addresses
+/// on `domain`. Only a login challenge signed moments ago is taken, and only
+/// once, so a payer's signature or a challenge seen on someone's screen can't
+/// be bound later on.
+pub fn bind_linking_key(
+    state: &State,
+    pubkey: String,
+    name: Option<String>,
+    domain: &Domain,
+    k1: &str,
+) -> anyhow::Result<()> {
+    let signed_after = Utc::now().naive_utc() - Duration::minutes(LNURL_AUTH_CHALLENGE_MINUTES);
+    let linking_key = state
+        .db
+        .use_lnurl_auth_session_for_binding(k1, signed_after)?
+        .ok_or(anyhow!("Unknown, pending or used challenge"))?;
+
+    // a linking key can only act for one pubkey
+    if state
+        .db
+        .get_users_by_linking_key(&linking_key)?
+        .iter()
+        .any(|u| u.pubkey != pubkey)
+    {
+        return Err(anyhow!("Linking key already bound"));
+    }
+
+    let users = get_target_users(state, pubkey, name, None, Some(domain))?;
+    if users.is_empty() {
+        return Err(anyhow!("User not found"));
+    }
+    for u in users {
+        state
+            .db
+            .set_user_linking_key(u, Some(linking_key.clone()))?;
+    }
+
+    Ok(())
+}
+
+/// Periodically deletes the challenges that expired unsigned or whose
+/// session is over
+pub(crate) async fn sweep_stale_challenges(state: State) {
+    // past this a challenge can't be signed anymore, only its session is kept
+    let stale_after =
+        Duration::minutes(LNURL_AUTH_CHALLENGE_MINUTES.max(PAYER_AUTH_CHALLENGE_MINUTES));
+
+    loop {
+        tokio::time::sleep(STALE_CHALLENGE_SWEEP_INTERVAL).await;
+
+        let issued_before = Utc::now().naive_utc() - stale_after;
+        match state.db.delete_stale_lnurl_auth_sessions(issued_before) {
+            Ok(0) => (),
+            Ok(n) => info!("Deleted {n} stale LNURL-auth challenges"),
+            Err(e) => error!("Error deleting stale LNURL-auth challenges: {e}"),
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "integration-tests")))]
+mod tests {
+    use secp256k1::{Message, Secp256k1, SecretKey};
+
+    use crate::lnurl_auth::verify_linking_key_sig;
+
+    #[tokio::test]
+    async fn check_linking_key_sig() {
+        let secp = Secp256k1::new();
+        let sk = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let key = sk.public_key(&secp).to_string();
+        let k1 = "ab".repeat(32);
+        let msg = Message::from_digest_slice(&hex::decode(&k1).unwrap()).unwrap();
+        let sig = hex::encode(secp.sign_ecdsa(&msg, &sk).serialize_der());
+
+        assert!(verify_linking_key_sig(&secp, &k1, &key, &sig));
+
+        // bound to the challenge and the key
+        assert!(!verify_linking_key_sig(&secp, &"cd".repeat(32), &key, &sig));
+        let other = SecretKey::from_slice(&[4u8; 32]).unwrap().public_key(&secp);
+        assert!(!verify_linking_key_sig(
+            &secp,
+            &k1,
+            &other.to_string(),
+            &sig
+        ));
+        assert!(!verify_linking_key_sig(&secp, &k1, &key, "not hex"));
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::Cursor;
+
+use anyhow::anyhow;
+use bech32::{ToBase32, Variant};
+use image::{ImageFormat, Luma};
+use qrcode::{render::svg, QrCode};
+use url::Url;
+
+use crate::{
+    domains::domain_url,
+    lnurlp::check_amount,
+    models::domain::Domain,
+    register::{address_name, ascii_name, resolve_user_by_name},
+    State,
+};
+
+/// Smallest width and height of a rendered QR code, in pixels
+const QR_MIN_SIZE: u32 = 256;
+
+/// The pay endpoint of a name, for a fixed amount in msats if one is given
+pub fn pay_url(
+    state: &State,
+    domain: &Domain,
+    name: String,
+    amount: Option<u64>,
+) -> anyhow::Result<Url> {
+    if let Some(amount) = amount {
+        check_amount(amount)?;
+    }
+
+    let user = resolve_user_by_name(state, domain, name)?.ok_or(anyhow!("Not Found"))?;
+    let name = address_name(domain, &user);
+
+    let mut url = Url::parse(&format!(
+        "{}/.well-known/lnurlp/{}",
+        domain_url(state, domain),
+        ascii_name(&name)
+    ))?;
+    if let Some(amount) = amount {
+        url.query_pairs_mut()
+            .append_pair("amount", &amount.to_string());
+    }
+
+    Ok(url)
+}
+
+/// LUD-01 bech32 encoding of a url, `lnurl1...`
+pub fn encode_lnurl(url: &Url) -> anyhow::Result<String> {
+    Ok(bech32::encode(
+        "lnurl",
+        url.as_str().as_bytes().to_base32(),
+        Variant::Bech32,
+    )?)
+}
+
+/// The pay endpoint of a name and its lnurl
+pub fn user_lnurl(
+    state: &State,
+    domain: &Domain,
+    name: String,
+    amount: Option<u64>,
+) -> anyhow::Result<(Url, String)> {
+    let url = pay_url(state, domain, name, amount)?;
+    let lnurl = encode_lnurl(&url)?;
+    Ok((url, lnurl))
+}
+
+/// QR codes hold the lnurl upper cased, which fits the smaller alphanumeric
+/// mode and is what wallets scan for
+fn qr_code(lnurl: &str) -> anyhow::Result<QrCode> {
+    Ok(QrCode::new(lnurl.to_uppercase().as_bytes())?)
+}
+
+pub fn render_svg(lnurl: &str) -> anyhow::Result<String> {
+    Ok(qr_code(lnurl)?
+        .render::<svg::Color>()
+        .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+        .build())
+}
+
+pub fn render_png(lnurl: &str) -> anyhow::Result<Vec<u8>> {
+    let image = qr_code(lnurl)?
+        .render::<Luma<u8>>()
+        .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+        .build();
+
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
+
+#[cfg(all(test, not(feature = "integration-tests")))]
+mod tests {
+    use url::Url;
+
+    use crate::lnurl_code::{encode_lnurl, render_png, render_svg};
+
+    #[tokio::test]
+    async fn check_encode_lnurl() {
+        // the example from LUD-01
+        let url = Url::parse(
+            "https://service.com/api?q=3fc3645b439ce8e7f2553a69e5267081d96dcd340693afabe04be7b0ccd178df",
+        )
+        .unwrap();
+        let lnurl = encode_lnurl(&url).unwrap();
+        assert_eq!(
+            lnurl,
+            "lnurl1dp68gurn8ghj7um9wfmxjcm99e3k7mf0v9cxj0m385ekvcenxc6r2c35xvukxefcv5mkvv34x5ekzd3ev56nyd3hxqurzepexejxxepnxscrvwfnv9nxzcn9xq6xyefhvgcxxcmyxymnserxfq5fns"
+        );
+
+        let svg = render_svg(&lnurl).unwrap();
+        assert!(svg.contains("<svg"));
+        let png = render_png(&lnurl).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+    }
+}
@@ -1,67 +1,303 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use crate::{
-    invoice::{spawn_invoice_subscription, InvoiceState},
+    domains::{domain_keys, domain_url, get_user_domain},
+    invoice::{spawn_invoice_subscription, InvoiceState, InvoiceUpdate},
+    lnurl_auth::{new_payer_auth_challenge, use_payer_auth_challenge, verify_linking_key_sig},
     mint::select_gateway,
-    models::{invoice::NewInvoice, zaps::Zap},
-    routes::{LnurlCallbackParams, LnurlCallbackResponse, LnurlVerifyResponse},
+    models::{
+        app_user::AppUser,
+        domain::Domain,
+        invoice::{Invoice, NewInvoice},
+        zaps::Zap,
+    },
+    register::{address_name, ascii_name, normalize_name, resolve_user_by_name},
+    routes::{
+        AvatarMime, LnurlCallbackParams, LnurlCallbackResponse, LnurlCallbackSuccessAction,
+        LnurlVerifyResponse, PayerData, PayerDataField, PayerDataSpec, SuccessActionConfig,
+        UserProfile,
+    },
     State,
 };
+use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
 use anyhow::anyhow;
 use fedimint_core::{config::FederationId, Amount, BitcoinHash};
 use fedimint_ln_client::LightningClientModule;
 use fedimint_ln_common::bitcoin::hashes::sha256;
 use fedimint_ln_common::bitcoin::secp256k1::Parity;
-use fedimint_ln_common::lightning_invoice::{Bolt11InvoiceDescription, Sha256};
+use fedimint_ln_common::lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription, Sha256};
 use nostr::{Event, JsonUtil, Kind};
+use secp256k1::{All, Secp256k1};
+use serde_json::{json, Value};
+use sha2::Digest;
+use tokio::sync::broadcast::{self, error::RecvError};
 
 use crate::routes::{LnurlStatus, LnurlType, LnurlWellKnownResponse};
 
 const INVALID_AMT_ERR: &str = "Invalid amount. Make sure the amount is within the range.";
+const COMMENT_TOO_LONG_ERR: &str = "Comment is longer than allowed.";
+const INVALID_PAYER_DATA_ERR: &str = "Invalid payerdata.";
+
+fn calc_metadata(name: &str, domain: &Domain, profile: &UserProfile) -> String {
+    // LUD-16 identifiers are ASCII only
+    let name = ascii_name(name);
+    let host = &domain.host;
+
+    let mut metadata = Vec::new();
+    let description = if profile.private {
+        // a private address is only named by the url the payer used
+        profile
+            .description
+            .clone()
+            .unwrap_or_else(|| "Sats".to_string())
+    } else {
+        metadata.push(json!(["text/identifier", format!("{name}@{host}")]));
+        match profile
+            .description
+            .as_ref()
+            .or(domain.lnurl_description.as_ref())
+        {
+            Some(description) => description.replace("{name}", &name),
+            None => format!("Sats for {name}"),
+        }
+    };
+    metadata.push(json!(["text/plain", description]));
 
-fn calc_metadata(name: &str, domain: &str) -> String {
-    format!("[[\"text/identifier\",\"{name}@{domain}\"],[\"text/plain\",\"Sats for {name}\"]]")
+    if let Some(ref long_description) = profile.long_description {
+        metadata.push(json!(["text/long-desc", long_description]));
+    }
+    if let Some(ref avatar) = profile.avatar {
+        let mime = match avatar.mime {
+            AvatarMime::Png => "image/png;base64",
+            AvatarMime::Jpeg => "image/jpeg;base64",
+        };
+        metadata.push(json!([mime, avatar.data]));
+    }
+
+    Value::Array(metadata).to_string()
+}
+
+/// The metadata of a user's address, the same in the pay request and in
+/// the description hash of its invoices
+fn user_metadata(domain: &Domain, user: &AppUser) -> anyhow::Result<String> {
+    let profile = match user.profile {
+        Some(ref profile) => serde_json::from_str::<UserProfile>(profile)?,
+        None => UserProfile::default(),
+    };
+
+    Ok(calc_metadata(&address_name(domain, user), domain, &profile))
 }
 
+/// The LUD-18 payer fields we take, all of them optional. The pay request
+/// carries the challenge for `auth`, the callback checks it was handed out.
+fn payer_data_spec(auth_k1: Option<String>) -> PayerDataSpec {
+    let optional = || {
+        Some(PayerDataField {
+            mandatory: false,
+            k1: None,
+        })
+    };
+    PayerDataSpec {
+        name: optional(),
+        pubkey: optional(),
+        identifier: optional(),
+        email: optional(),
+        auth: Some(PayerDataField {
+            mandatory: false,
+            k1: auth_k1,
+        }),
+    }
+}
+
+/// Checks LUD-18 payer data against the fields we asked for
+fn validate_payer_data(
+    secp: &Secp256k1<All>,
+    spec: &PayerDataSpec,
+    payer_data: &str,
+) -> anyhow::Result<PayerData> {
+    let invalid = || anyhow!(INVALID_PAYER_DATA_ERR);
+    let data: PayerData = serde_json::from_str(payer_data).map_err(|_| invalid())?;
+
+    // only fields we asked for, and all the mandatory ones
+    let fields = [
+        (&spec.name, data.name.is_some()),
+        (&spec.pubkey, data.pubkey.is_some()),
+        (&spec.identifier, data.identifier.is_some()),
+        (&spec.email, data.email.is_some()),
+        (&spec.auth, data.auth.is_some()),
+    ];
+    for (field, given) in fields {
+        match field {
+            None if given => return Err(invalid()),
+            Some(f) if f.mandatory && !given => return Err(invalid()),
+            _ => (),
+        }
+    }
+
+    if let Some(ref pubkey) = data.pubkey {
+        secp256k1::PublicKey::from_str(pubkey).map_err(|_| invalid())?;
+    }
+    if data.email.as_ref().is_some_and(|e| !e.contains('@')) {
+        return Err(invalid());
+    }
+
+    if let Some(ref auth) = data.auth {
+        if spec
+            .auth
+            .as_ref()
+            .and_then(|a| a.k1.as_ref())
+            .is_some_and(|k1| *k1 != auth.k1)
+        {
+            return Err(invalid());
+        }
+        if !verify_linking_key_sig(secp, &auth.k1, &auth.key, &auth.sig) {
+            return Err(invalid());
+        }
+    }
+
+    Ok(data)
+}
+
+/// The pay request for a name, with `amount` set it's for that exact amount
 pub async fn well_known_lnurlp(
     state: &State,
+    domain: &Domain,
     name: String,
+    amount: Option<u64>,
 ) -> anyhow::Result<LnurlWellKnownResponse> {
-    let user = state.db.get_user_by_name(name.clone())?;
+    let (min_sendable, max_sendable) = match amount {
+        Some(amount) => {
+            check_amount(amount)?;
+            (amount, amount)
+        }
+        None => (MIN_AMOUNT, MAX_AMOUNT),
+    };
+
+    let user = resolve_user_by_name(state, domain, name)?;
     if user.is_none() {
         return Err(anyhow!("Not Found"));
     }
+    let user = user.expect("just checked");
+    let name = address_name(domain, &user);
+
+    // zap receipts are signed for the domain the user registered on
+    let zapper_keys = domain_keys(state, &get_user_domain(state, &user)?);
 
     let res = LnurlWellKnownResponse {
-        callback: format!("{}/lnurlp/{}/callback", state.domain, name).parse()?,
-        max_sendable: Amount { msats: MAX_AMOUNT },
-        min_sendable: Amount { msats: MIN_AMOUNT },
-        metadata: calc_metadata(&name, &state.domain_no_http()),
-        comment_allowed: None,
+        callback: format!(
+            "{}/lnurlp/{}/callback",
+            domain_url(state, domain),
+            ascii_name(&name)
+        )
+        .parse()?,
+        max_sendable: Amount {
+            msats: max_sendable,
+        },
+        min_sendable: Amount {
+            msats: min_sendable,
+        },
+        metadata: user_metadata(domain, &user)?,
+        comment_allowed: (state.comment_allowed > 0).then_some(state.comment_allowed),
+        payer_data: Some(payer_data_spec(Some(new_payer_auth_challenge(state)?))),
         tag: LnurlType::PayRequest,
         status: LnurlStatus::Ok,
-        nostr_pubkey: Some(state.nostr_sk.public_key()),
+        nostr_pubkey: Some(zapper_keys.public_key()),
         allows_nostr: true,
     };
 
     Ok(res)
 }
 
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+
+/// The user's success action for an invoice, the plaintext of an `aes` one
+/// is encrypted with the invoice's preimage as LUD-10 has it
+fn success_action_for(
+    user: &AppUser,
+    preimage: &[u8; 32],
+) -> anyhow::Result<Option<LnurlCallbackSuccessAction>> {
+    let config = match user.success_action {
+        Some(ref config) => serde_json::from_str::<SuccessActionConfig>(config)?,
+        None => return Ok(None),
+    };
+
+    let action = match config {
+        SuccessActionConfig::Message { message } => LnurlCallbackSuccessAction::Message { message },
+        SuccessActionConfig::Url { description, url } => {
+            LnurlCallbackSuccessAction::Url { description, url }
+        }
+        SuccessActionConfig::Aes {
+            description,
+            plaintext,
+        } => {
+            let (ciphertext, iv) = encrypt_success_action(preimage, &plaintext);
+            LnurlCallbackSuccessAction::Aes {
+                description,
+                ciphertext: base64::encode(ciphertext),
+                iv: base64::encode(iv),
+            }
+        }
+    };
+
+    Ok(Some(action))
+}
+
+/// AES-256-CBC keyed by the preimage. A preimage is only used for one
+/// invoice, so the IV is derived from it too.
+fn encrypt_success_action(preimage: &[u8; 32], plaintext: &str) -> (Vec<u8>, [u8; 16]) {
+    let mut iv = [0u8; 16];
+    iv.copy_from_slice(&sha2::Sha256::digest([&preimage[..], b"iv"].concat())[..16]);
+
+    let ciphertext = Aes256CbcEnc::new(&(*preimage).into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(plaintext.as_bytes());
+
+    (ciphertext, iv)
+}
+
 const MAX_AMOUNT: u64 = 100_000_000 * 1_000; // 1 BTC
 const MIN_AMOUNT: u64 = 5_000; // 5 sats
 
+pub(crate) fn check_amount(amount_msats: u64) -> anyhow::Result<()> {
+    if !(MIN_AMOUNT..=MAX_AMOUNT).contains(&amount_msats) {
+        return Err(anyhow!(INVALID_AMT_ERR));
+    }
+
+    Ok(())
+}
+
 pub async fn lnurl_callback(
     state: &State,
+    domain: &Domain,
     name: String,
     params: LnurlCallbackParams,
 ) -> anyhow::Result<LnurlCallbackResponse> {
-    let user = state.db.get_user_and_increment_counter(&name)?;
+    let name = normalize_name(&name);
+
+    // a wallet retrying a request gets the invoice it was already given
+    let idempotency_key = idempotency_key(&params);
+    if let Some(ref key) = idempotency_key {
+        if let Some((user, invoice)) = retried_invoice(state, domain, &name, key)? {
+            return invoice_callback_response(state, domain, &user, &invoice);
+        }
+    }
+
+    let user = match state.db.get_user_and_increment_counter(domain.id, &name)? {
+        Some(user) => Some(user),
+        // the user may have changed their name or the domain may be a
+        // user's own, follow it to their address
+        None => match resolve_user_by_name(state, domain, name)? {
+            Some(u) => state
+                .db
+                .get_user_and_increment_counter(u.domain_id, &u.name)?,
+            None => None,
+        },
+    };
     if user.is_none() {
         return Err(anyhow!("Not Found"));
     }
     let user = user.expect("just checked");
 
-    if user.disabled_zaps {
+    if user.zaps_disabled() {
         return Err(anyhow!(
             "Internal error: User has disabled their address temporarily"
         ));
@@ -72,8 +308,26 @@ pub async fn lnurl_callback(
         None => return Err(anyhow!(INVALID_AMT_ERR)),
     };
 
-    if !(MIN_AMOUNT..=MAX_AMOUNT).contains(&amount_msats) {
-        return Err(anyhow::anyhow!(INVALID_AMT_ERR));
+    check_amount(amount_msats)?;
+
+    // LUD-12 comments can be as long as we advertise, counted in characters
+    if params
+        .comment
+        .as_ref()
+        .is_some_and(|c| c.chars().count() > usize::from(state.comment_allowed))
+    {
+        return Err(anyhow!(COMMENT_TOO_LONG_ERR));
+    }
+
+    if let Some(ref payer_data) = params.payerdata {
+        let data = validate_payer_data(&state.secp, &payer_data_spec(None), payer_data)?;
+
+        // each challenge identifies one payment, a seen signature can't be replayed
+        if let Some(auth) = data.auth {
+            if !use_payer_auth_challenge(state, &auth.k1, &auth.key)? {
+                return Err(anyhow!(INVALID_PAYER_DATA_ERR));
+            }
+        }
     }
 
     // verify nostr param is a zap request if we have one
@@ -101,8 +355,12 @@ pub async fn lnurl_callback(
     let desc_hash = match params.nostr {
         Some(ref nostr) => Sha256(sha256::Hash::hash(nostr.as_bytes())),
         None => {
-            let metadata = calc_metadata(&name, &state.domain_no_http());
-            Sha256(sha256::Hash::hash(metadata.as_bytes()))
+            // LUD-18 payer data is committed to along with the metadata
+            let metadata = user_metadata(domain, &user)?;
+            let payer_data = params.payerdata.as_deref().unwrap_or_default();
+            Sha256(sha256::Hash::hash(
+                format!("{metadata}{payer_data}").as_bytes(),
+            ))
         }
     };
 
@@ -134,10 +392,19 @@ pub async fn lnurl_callback(
         bolt11: pr.to_string(),
         amount: amount_msats as i64,
         state: InvoiceState::Pending as i32,
+        pubkey: user.pubkey.clone(),
+        comment: params.comment.clone(),
+        payer_data: params.payerdata.clone(),
+        idempotency_key,
     };
 
     let created_invoice = state.db.insert_new_invoice(new_invoice)?;
 
+    // a concurrent retry got its invoice in first, both get that one
+    if created_invoice.op_id != op_id.to_string() {
+        return invoice_callback_response(state, domain, &user, &created_invoice);
+    }
+
     // save nostr zap request
     if let Some(request) = params.nostr {
         let new_zap = Zap {
@@ -156,11 +423,90 @@ pub async fn lnurl_callback(
 
     spawn_invoice_subscription(state.clone(), created_invoice, user.clone(), subscription).await;
 
-    let verify_url = format!("{}/lnurlp/{}/verify/{}", state.domain, user.name, op_id);
+    callback_response(state, domain, &user, pr, &op_id.to_string(), &preimage)
+}
+
+/// Identifies a callback by its LUD-06 nonce along with everything that goes
+/// into the invoice, there is none without a nonce
+fn idempotency_key(params: &LnurlCallbackParams) -> Option<String> {
+    let nonce = params.nonce.as_ref()?;
+    let request = json!([
+        nonce,
+        params.amount,
+        params.nostr,
+        params.comment,
+        params.payerdata
+    ]);
+
+    Some(hex::encode(sha2::Sha256::digest(
+        request.to_string().as_bytes(),
+    )))
+}
+
+/// The invoice an earlier callback with the same idempotency key got, as
+/// long as it can still be paid
+fn retried_invoice(
+    state: &State,
+    domain: &Domain,
+    name: &str,
+    key: &str,
+) -> anyhow::Result<Option<(AppUser, Invoice)>> {
+    let user = match resolve_user_by_name(state, domain, name.to_string())? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let invoice = match state.db.get_invoice_by_idempotency_key(user.id, key)? {
+        Some(invoice) if invoice.state == InvoiceState::Pending as i32 => invoice,
+        _ => return Ok(None),
+    };
+    if invoice.bolt11().is_ok_and(|b| !b.is_expired()) {
+        return Ok(Some((user, invoice)));
+    }
+
+    // the retry gets a new invoice, which takes over the key. The expired
+    // one is left to the sweeper, a payment to it may still be claimed.
+    state.db.release_invoice_idempotency_key(invoice)?;
+    Ok(None)
+}
+
+fn invoice_callback_response(
+    state: &State,
+    domain: &Domain,
+    user: &AppUser,
+    invoice: &Invoice,
+) -> anyhow::Result<LnurlCallbackResponse> {
+    let preimage: [u8; 32] = hex::decode(&invoice.preimage)?
+        .try_into()
+        .map_err(|_| anyhow!("Internal error: Invalid preimage"))?;
+    callback_response(
+        state,
+        domain,
+        user,
+        invoice.bolt11()?,
+        &invoice.op_id,
+        &preimage,
+    )
+}
+
+fn callback_response(
+    state: &State,
+    domain: &Domain,
+    user: &AppUser,
+    pr: Bolt11Invoice,
+    op_id: &str,
+    preimage: &[u8; 32],
+) -> anyhow::Result<LnurlCallbackResponse> {
+    let verify_url = format!(
+        "{}/lnurlp/{}/verify/{}",
+        domain_url(state, domain),
+        ascii_name(&address_name(domain, user)),
+        op_id
+    );
 
     Ok(LnurlCallbackResponse {
         pr,
-        success_action: None,
+        success_action: success_action_for(user, preimage)?,
         status: LnurlStatus::Ok,
         reason: None,
         verify: verify_url.parse()?,
@@ -170,6 +516,7 @@ pub async fn lnurl_callback(
 
 pub async fn verify(
     state: &State,
+    domain: &Domain,
     name: String,
     op_id: String,
 ) -> anyhow::Result<LnurlVerifyResponse> {
@@ -178,10 +525,7 @@ pub async fn verify(
         .get_invoice_by_op_id(op_id)?
         .ok_or(anyhow::anyhow!("Not Found"))?;
 
-    let user = state
-        .db
-        .get_user_by_name(name)?
-        .ok_or(anyhow::anyhow!("Not Found"))?;
+    let user = resolve_user_by_name(state, domain, name)?.ok_or(anyhow::anyhow!("Not Found"))?;
 
     if invoice.app_user_id != user.id {
         return Err(anyhow::anyhow!("Not Found"));
@@ -203,53 +547,249 @@ pub async fn verify(
     Ok(verify_response)
 }
 
+/// Like [`verify`], along with the updates to the invoice from then on for
+/// [`wait_for_verify_update`]
+pub async fn watch_verify(
+    state: &State,
+    domain: &Domain,
+    name: String,
+    op_id: String,
+) -> anyhow::Result<(LnurlVerifyResponse, broadcast::Receiver<InvoiceUpdate>)> {
+    // listen first so an update right after the lookup isn't missed
+    let updates = state.subscriptions.updates();
+    let res = verify(state, domain, name, op_id).await?;
+
+    Ok((res, updates))
+}
+
+/// Waits up to `timeout` for a pending invoice to be paid or cancelled.
+/// `None` if it wasn't by then, or `current` already tells how it ended.
+/// An invoice paid after `current` was read is returned right away, its
+/// update may already have gone out.
+pub async fn wait_for_verify_update(
+    state: &State,
+    mut updates: broadcast::Receiver<InvoiceUpdate>,
+    current: &LnurlVerifyResponse,
+    op_id: &str,
+    timeout: Duration,
+) -> Option<LnurlVerifyResponse> {
+    let invoice = state
+        .db
+        .get_invoice_by_op_id(op_id.to_string())
+        .ok()
+        .flatten()?;
+    if invoice.state != InvoiceState::Pending as i32 {
+        let settled = invoice.state == InvoiceState::Settled as i32;
+        return (settled && !current.settled).then(|| LnurlVerifyResponse {
+            status: LnurlStatus::Ok,
+            settled,
+            preimage: Some(invoice.preimage),
+            pr: current.pr.clone(),
+        });
+    }
+    // nothing happens to an expired invoice, the sweeper cancels it
+    let timeout = timeout.min(invoice.bolt11().ok()?.duration_until_expiry());
+
+    let wait = async {
+        loop {
+            match updates.recv().await {
+                Ok(update) if update.op_id == op_id => return Some(update.preimage),
+                Ok(_) => (),
+                // missed some updates, the invoice itself tells if one was ours
+                Err(RecvError::Lagged(_)) => {
+                    if let Ok(Some(invoice)) = state.db.get_invoice_by_op_id(op_id.to_string()) {
+                        if invoice.state == InvoiceState::Settled as i32 {
+                            return Some(Some(invoice.preimage));
+                        } else if invoice.state == InvoiceState::Cancelled as i32 {
+                            return Some(None);
+                        }
+                    }
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    };
+    let preimage = tokio::time::timeout(timeout, wait).await.ok().flatten()?;
+
+    Some(LnurlVerifyResponse {
+        status: LnurlStatus::Ok,
+        settled: preimage.is_some(),
+        preimage,
+        pr: current.pr.clone(),
+    })
+}
+
+#[cfg(all(test, not(feature = "integration-tests")))]
+mod tests {
+    use secp256k1::{Message, Secp256k1, SecretKey};
+    use serde_json::json;
+
+    use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+
+    use crate::{
+        lnurlp::{
+            calc_metadata, encrypt_success_action, idempotency_key, payer_data_spec,
+            validate_payer_data,
+        },
+        models::domain::Domain,
+        routes::{AvatarMime, LnurlCallbackParams, ProfileAvatar, UserProfile},
+    };
+
+    #[tokio::test]
+    async fn check_idempotency_key() {
+        let params = |nonce: Option<&str>, amount: u64| LnurlCallbackParams {
+            amount: Some(amount),
+            nonce: nonce.map(|n| n.to_string()),
+            comment: Some("hi".to_string()),
+            ..Default::default()
+        };
+
+        // only requests with a nonce can be retried
+        assert!(idempotency_key(&params(None, 10_000)).is_none());
+
+        let key = idempotency_key(&params(Some("abc"), 10_000)).unwrap();
+        assert_eq!(
+            idempotency_key(&params(Some("abc"), 10_000)),
+            Some(key.clone())
+        );
+        assert_ne!(
+            idempotency_key(&params(Some("abd"), 10_000)),
+            Some(key.clone())
+        );
+        assert_ne!(
+            idempotency_key(&params(Some("abc"), 20_000)),
+            Some(key.clone())
+        );
+
+        let mut other = params(Some("abc"), 10_000);
+        other.comment = None;
+        assert_ne!(idempotency_key(&other), Some(key));
+    }
+
+    #[tokio::test]
+    async fn check_profile_metadata() {
+        let domain = Domain {
+            id: 1,
+            host: "hermes.test".to_string(),
+            is_default: true,
+            nostr_nsec: None,
+            lnurl_description: None,
+            allowed_origins: vec![],
+            created_at: chrono::Utc::now().naive_utc(),
+            owner_user_id: None,
+            owner_name: None,
+            verified_at: None,
+        };
+
+        let metadata = calc_metadata("alice", &domain, &UserProfile::default());
+        assert_eq!(
+            metadata,
+            json!([
+                ["text/identifier", "alice@hermes.test"],
+                ["text/plain", "Sats for alice"]
+            ])
+            .to_string()
+        );
+
+        let mut profile = UserProfile {
+            description: Some("Tips for {name}".to_string()),
+            long_description: Some("Thanks for the support".to_string()),
+            avatar: Some(ProfileAvatar {
+                mime: AvatarMime::Png,
+                data: "iVBORw0KGgo=".to_string(),
+            }),
+            private: false,
+        };
+        assert_eq!(
+            calc_metadata("alice", &domain, &profile),
+            json!([
+                ["text/identifier", "alice@hermes.test"],
+                ["text/plain", "Tips for alice"],
+                ["text/long-desc", "Thanks for the support"],
+                ["image/png;base64", "iVBORw0KGgo="]
+            ])
+            .to_string()
+        );
+
+        // private profiles don't name the user
+        profile.description = None;
+        profile.private = true;
+        let metadata = calc_metadata("alice", &domain, &profile);
+        assert!(!metadata.contains("alice"));
+        assert!(metadata.contains(r#"["text/plain","Sats"]"#));
+    }
+
+    #[tokio::test]
+    async fn check_payer_data() {
+        let secp = Secp256k1::new();
+        let k1 = "11".repeat(32);
+        let spec = payer_data_spec(Some(k1.clone()));
+
+        let payer_data = json!({"name": "Satoshi", "email": "satoshi@example.com"}).to_string();
+        assert!(validate_payer_data(&secp, &spec, &payer_data).is_ok());
+
+        // fields we didn't ask for are rejected
+        let payer_data = json!({"name": "Satoshi", "phone": "555"}).to_string();
+        assert!(validate_payer_data(&secp, &spec, &payer_data).is_err());
+        assert!(validate_payer_data(&secp, &spec, "not json").is_err());
+
+        // auth has to sign our k1
+        let sk = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let key = sk.public_key(&secp).to_string();
+        let sign = |k1: &str| {
+            let msg = Message::from_digest_slice(&hex::decode(k1).unwrap()).unwrap();
+            hex::encode(secp.sign_ecdsa(&msg, &sk).serialize_der())
+        };
+        let payer_data = json!({"auth": {"key": key, "k1": k1, "sig": sign(&k1)}}).to_string();
+        assert!(validate_payer_data(&secp, &spec, &payer_data).is_ok());
+
+        let other_k1 = "22".repeat(32);
+        let payer_data =
+            json!({"auth": {"key": key, "k1": other_k1, "sig": sign(&other_k1)}}).to_string();
+        assert!(validate_payer_data(&secp, &spec, &payer_data).is_err());
+
+        let payer_data =
+            json!({"auth": {"key": key, "k1": k1, "sig": sign(&other_k1)}}).to_string();
+        assert!(validate_payer_data(&secp, &spec, &payer_data).is_err());
+    }
+
+    #[tokio::test]
+    async fn check_success_action_decrypts_with_preimage() {
+        let preimage = [7u8; 32];
+        let (ciphertext, iv) = encrypt_success_action(&preimage, "download code: 1234");
+
+        let plaintext = cbc::Decryptor::<aes::Aes256>::new(&preimage.into(), &iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&ciphertext)
+            .unwrap();
+        assert_eq!(plaintext, b"download code: 1234");
+
+        // each preimage gets its own iv
+        let (_, other_iv) = encrypt_success_action(&[8u8; 32], "download code: 1234");
+        assert_ne!(iv, other_iv);
+    }
+}
+
 #[cfg(all(test, feature = "integration-tests"))]
 mod tests_integration {
     use fedimint_core::api::InviteCode;
     use nostr::prelude::{rand, ZapRequestData};
     use nostr::{EventBuilder, Keys};
-    use secp256k1::Secp256k1;
     use std::path::PathBuf;
-    use std::sync::Arc;
 
+    use crate::domains::get_default_domain;
     use crate::mint::setup_multimint;
     use crate::register::generate_random_name;
-    use crate::{
-        db::setup_db, lnurlp::*, mint::MockMultiMintWrapperTrait, models::app_user::NewAppUser,
-        register::BlindSigner,
-    };
+    use crate::{lnurlp::*, models::app_user::NewAppUser, test_state};
 
     const INVITE_CODE: &str = "fed11qgqzc2nhwden5te0vejkg6tdd9h8gepwvejkg6tdd9h8garhduhx6at5d9h8jmn9wshxxmmd9uqqzgxg6s3evnr6m9zdxr6hxkdkukexpcs3mn7mj3g5pc5dfh63l4tj6g9zk4er";
 
     #[tokio::test]
     pub async fn well_known_lnurlp_lookup_test() {
-        dotenv::dotenv().ok();
-        let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = setup_db(pg_url);
-
-        // swap out fm with a mock here since that's not what is being tested
-        let mock_mm = MockMultiMintWrapperTrait::new();
-
-        // nostr
-        let nostr_nsec_str = std::env::var("NSEC").expect("FM_DB_PATH must be set");
-        let nostr_sk = Keys::from_str(&nostr_nsec_str).expect("Invalid NOSTR_SK");
-        let nostr = nostr_sdk::Client::new(&nostr_sk);
-
-        // create blind signer
-        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-        let paid_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-
-        let mock_mm = Arc::new(mock_mm);
         let state = State {
-            db: db.clone(),
-            mm: mock_mm,
-            secp: Secp256k1::new(),
-            nostr,
-            free_pk: free_signer.pk,
-            paid_pk: paid_signer.pk,
             domain: "http://hello.com".to_string(),
-            nostr_sk,
+            ..test_state()
         };
+        let domain = get_default_domain(&state).unwrap();
 
         let username = "wellknownuser".to_string();
         let user = NewAppUser {
@@ -258,12 +798,14 @@ mod tests_integration {
             federation_id: "".to_string(),
             unblinded_msg: "".to_string(),
             federation_invite_code: "".to_string(),
+            expires_at: None,
+            domain_id: domain.id,
         };
 
         // don't care about error if already exists
         let _ = state.db.insert_new_user(user);
 
-        match well_known_lnurlp(&state, username.clone()).await {
+        match well_known_lnurlp(&state, &domain, username.clone(), None).await {
             Ok(result) => {
                 assert_eq!(
                     result.callback,
@@ -278,36 +820,15 @@ mod tests_integration {
 
     #[tokio::test]
     pub async fn amt_callback_test() {
-        dotenv::dotenv().ok();
-        let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = setup_db(pg_url);
-
-        // nostr
-        let nostr_nsec_str = std::env::var("NSEC").expect("FM_DB_PATH must be set");
-        let nostr_sk = Keys::from_str(&nostr_nsec_str).expect("Invalid NOSTR_SK");
-        let nostr = nostr_sdk::Client::new(&nostr_sk);
-
-        // create blind signer
-        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-        let paid_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-
-        // swap out fm with a mock here since that's not what is being tested
-        let mock_mm = Arc::new(MockMultiMintWrapperTrait::new());
-
         let state = State {
-            db: db.clone(),
-            mm: mock_mm,
-            secp: Secp256k1::new(),
-            nostr,
-            free_pk: free_signer.pk,
-            paid_pk: paid_signer.pk,
             domain: "http://hello.com".to_string(),
-            nostr_sk,
+            ..test_state()
         };
+        let domain = get_default_domain(&state).unwrap();
 
         let invite_code = InviteCode::from_str(INVITE_CODE).unwrap();
         // generate random username and key
-        let username = generate_random_name(&state).unwrap();
+        let username = generate_random_name(&state, &domain).unwrap();
         let pk = Keys::generate().public_key();
         let user = NewAppUser {
             pubkey: pk.to_string(),
@@ -315,6 +836,8 @@ mod tests_integration {
             federation_id: invite_code.federation_id().to_string(),
             unblinded_msg: pk.to_string(),
             federation_invite_code: INVITE_CODE.to_string(),
+            expires_at: None,
+            domain_id: domain.id,
         };
 
         state.db.insert_new_user(user).unwrap();
@@ -324,7 +847,7 @@ mod tests_integration {
             ..Default::default()
         };
 
-        match lnurl_callback(&state, username.clone(), params).await {
+        match lnurl_callback(&state, &domain, username.clone(), params).await {
             Ok(_) => panic!("unexpected ok"),
             Err(e) => assert_eq!(e.to_string(), INVALID_AMT_ERR),
         }
@@ -334,27 +857,25 @@ mod tests_integration {
             ..Default::default()
         };
 
-        match lnurl_callback(&state, username, params).await {
+        match lnurl_callback(&state, &domain, username.clone(), params).await {
             Ok(_) => panic!("unexpected ok"),
             Err(e) => assert_eq!(e.to_string(), INVALID_AMT_ERR),
         }
+
+        let params = LnurlCallbackParams {
+            amount: Some(10_000),
+            comment: Some("a".repeat(256)),
+            ..Default::default()
+        };
+
+        match lnurl_callback(&state, &domain, username, params).await {
+            Ok(_) => panic!("unexpected ok"),
+            Err(e) => assert_eq!(e.to_string(), COMMENT_TOO_LONG_ERR),
+        }
     }
 
     #[tokio::test(flavor = "multi_thread")]
     pub async fn callback_test() {
-        dotenv::dotenv().ok();
-        let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = setup_db(pg_url);
-
-        // nostr
-        let nostr_nsec_str = std::env::var("NSEC").expect("FM_DB_PATH must be set");
-        let nostr_sk = Keys::from_str(&nostr_nsec_str).expect("Invalid NOSTR_SK");
-        let nostr = nostr_sdk::Client::new(&nostr_sk);
-
-        // create blind signer
-        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-        let paid_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-
         // generate random tmp db path
         let tmp_db_path = format!("/tmp/test-{}.db", rand::random::<u64>());
 
@@ -363,19 +884,15 @@ mod tests_integration {
             .unwrap();
 
         let state = State {
-            db: db.clone(),
             mm,
-            secp: Secp256k1::new(),
-            nostr,
-            free_pk: free_signer.pk,
-            paid_pk: paid_signer.pk,
             domain: "http://hello.com".to_string(),
-            nostr_sk,
+            ..test_state()
         };
+        let domain = get_default_domain(&state).unwrap();
 
         let invite_code = InviteCode::from_str(INVITE_CODE).unwrap();
         // generate random username and key
-        let username = generate_random_name(&state).unwrap();
+        let username = generate_random_name(&state, &domain).unwrap();
         let pk = Keys::generate().public_key();
         let user = NewAppUser {
             pubkey: pk.to_string(),
@@ -383,6 +900,8 @@ mod tests_integration {
             federation_id: invite_code.federation_id().to_string(),
             unblinded_msg: pk.to_string(),
             federation_invite_code: INVITE_CODE.to_string(),
+            expires_at: None,
+            domain_id: domain.id,
         };
 
         state.mm.register_new_federation(invite_code).await.unwrap();
@@ -392,12 +911,12 @@ mod tests_integration {
         let params = LnurlCallbackParams {
             amount: Some(10_000),
             nonce: None,
-            comment: None,
-            proofofpayer: None,
+            comment: Some("Thanks!".to_string()),
+            payerdata: None,
             nostr: None,
         };
 
-        match lnurl_callback(&state, username, params).await {
+        match lnurl_callback(&state, &domain, username, params).await {
             Ok(result) => {
                 assert_eq!(result.status, LnurlStatus::Ok);
                 assert!(!result.pr.is_expired());
@@ -408,19 +927,6 @@ mod tests_integration {
 
     #[tokio::test(flavor = "multi_thread")]
     pub async fn callback_with_zap_test() {
-        dotenv::dotenv().ok();
-        let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = setup_db(pg_url);
-
-        // nostr
-        let nostr_nsec_str = std::env::var("NSEC").expect("FM_DB_PATH must be set");
-        let nostr_sk = Keys::from_str(&nostr_nsec_str).expect("Invalid NOSTR_SK");
-        let nostr = nostr_sdk::Client::new(&nostr_sk);
-
-        // create blind signer
-        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-        let paid_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-
         // generate random tmp db path
         let tmp_db_path = format!("/tmp/test-{}.db", rand::random::<u64>());
 
@@ -429,19 +935,15 @@ mod tests_integration {
             .unwrap();
 
         let state = State {
-            db: db.clone(),
             mm,
-            secp: Secp256k1::new(),
-            nostr,
-            free_pk: free_signer.pk,
-            paid_pk: paid_signer.pk,
             domain: "http://hello.com".to_string(),
-            nostr_sk,
+            ..test_state()
         };
+        let domain = get_default_domain(&state).unwrap();
 
         let invite_code = InviteCode::from_str(INVITE_CODE).unwrap();
         // generate random username and key
-        let username = generate_random_name(&state).unwrap();
+        let username = generate_random_name(&state, &domain).unwrap();
         let pk = Keys::generate().public_key();
         let user = NewAppUser {
             pubkey: pk.to_string(),
@@ -449,6 +951,8 @@ mod tests_integration {
             federation_id: invite_code.federation_id().to_string(),
             unblinded_msg: pk.to_string(),
             federation_invite_code: INVITE_CODE.to_string(),
+            expires_at: None,
+            domain_id: domain.id,
         };
 
         state.mm.register_new_federation(invite_code).await.unwrap();
@@ -466,11 +970,11 @@ mod tests_integration {
             amount: Some(10_000),
             nonce: None,
             comment: None,
-            proofofpayer: None,
+            payerdata: None,
             nostr: Some(zap_request.as_json()),
         };
 
-        match lnurl_callback(&state, username, params).await {
+        match lnurl_callback(&state, &domain, username, params).await {
             Ok(result) => {
                 assert_eq!(result.status, LnurlStatus::Ok);
                 assert!(!result.pr.is_expired());
@@ -478,4 +982,142 @@ mod tests_integration {
             Err(e) => panic!("shouldn't error: {e}"),
         }
     }
+
+    #[tokio::test]
+    pub async fn idempotent_invoice_insert_test() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let username = generate_random_name(&state, &domain).unwrap();
+        let pk = Keys::generate().public_key();
+        let user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: username,
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        let new_invoice = || NewInvoice {
+            federation_id: "".to_string(),
+            // any unique id will do
+            op_id: Keys::generate().public_key().to_string(),
+            preimage: "00".repeat(32),
+            app_user_id: user.id,
+            user_invoice_index: 0,
+            bolt11: "lnbc1".to_string(),
+            amount: 1_000,
+            state: InvoiceState::Pending as i32,
+            pubkey: pk.to_string(),
+            comment: None,
+            payer_data: None,
+            idempotency_key: Some("key".to_string()),
+        };
+
+        // a racing retry gets the pending invoice that got in first
+        let first = state.db.insert_new_invoice(new_invoice()).unwrap();
+        let second = state.db.insert_new_invoice(new_invoice()).unwrap();
+        assert_eq!(second.id, first.id);
+        assert_eq!(second.op_id, first.op_id);
+
+        // once it's given up on, the key can be used again
+        assert!(state.db.cancel_invoice(first.clone(), "test").unwrap());
+        let third = state.db.insert_new_invoice(new_invoice()).unwrap();
+        assert_ne!(third.id, first.id);
+        assert_eq!(
+            state
+                .db
+                .get_invoice_by_idempotency_key(user.id, "key")
+                .unwrap()
+                .unwrap()
+                .id,
+            third.id
+        );
+
+        // without a key every callback gets its own invoice
+        let unkeyed = || NewInvoice {
+            idempotency_key: None,
+            ..new_invoice()
+        };
+        let first = state.db.insert_new_invoice(unkeyed()).unwrap();
+        let second = state.db.insert_new_invoice(unkeyed()).unwrap();
+        assert_ne!(second.id, first.id);
+    }
+
+    #[tokio::test]
+    pub async fn verify_update_after_lookup_test() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let username = generate_random_name(&state, &domain).unwrap();
+        let pk = Keys::generate().public_key();
+        let user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: username.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+        let invoice = state
+            .db
+            .insert_new_invoice(NewInvoice {
+                federation_id: "".to_string(),
+                op_id: Keys::generate().public_key().to_string(),
+                preimage: "00".repeat(32),
+                app_user_id: user.id,
+                user_invoice_index: 0,
+                bolt11: "lnbc1".to_string(),
+                amount: 1_000,
+                state: InvoiceState::Pending as i32,
+                pubkey: pk.to_string(),
+                comment: None,
+                payer_data: None,
+                idempotency_key: None,
+            })
+            .unwrap();
+
+        let (current, updates) = watch_verify(&state, &domain, username, invoice.op_id.clone())
+            .await
+            .unwrap();
+        assert!(!current.settled);
+
+        // paid between the lookup and the wait, without an update to receive
+        drop(updates);
+        state
+            .db
+            .set_invoice_state(invoice.clone(), InvoiceState::Settled as i32)
+            .unwrap();
+        let update = wait_for_verify_update(
+            &state,
+            state.subscriptions.updates(),
+            &current,
+            &invoice.op_id,
+            Duration::from_secs(1),
+        )
+        .await
+        .expect("should see the payment");
+        assert!(update.settled);
+        assert_eq!(update.preimage, Some(invoice.preimage));
+
+        // nothing new for a response that already has it
+        assert!(wait_for_verify_update(
+            &state,
+            state.subscriptions.updates(),
+            &update,
+            &invoice.op_id,
+            Duration::from_secs(1),
+        )
+        .await
+        .is_none());
+    }
 }
@@ -1,12 +1,14 @@
 use axum::headers::Origin;
 use axum::http::{Method, StatusCode, Uri};
+use axum::middleware;
 use axum::routing::get;
 use axum::{extract::DefaultBodyLimit, routing::post};
 use axum::{http, Extension, Router, TypedHeader};
 use log::{error, info};
-use nostr_sdk::nostr::Keys;
+use nostr_sdk::nostr::{Keys, PublicKey};
 use secp256k1::{All, Secp256k1};
-use std::{path::PathBuf, str::FromStr, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
 use tbs::{AggregatePublicKey, PubKeyPoint};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::oneshot;
@@ -14,23 +16,43 @@ use tower_http::cors::{AllowOrigin, CorsLayer};
 
 use crate::{
     db::{setup_db, DBConnection},
-    invoice::handle_pending_invoices,
+    dns::{DohResolver, TxtResolver},
+    expiry::handle_name_expiry,
+    invoice::{handle_pending_invoices, sweep_expired_invoices, InvoiceSubscriptions},
+    lnurl_auth::sweep_stale_challenges,
     mint::{setup_multimint, MultiMintWrapperTrait},
+    nostr_commands::handle_nostr_commands,
+    rate_limit::{prune_rate_limits, rate_limit, RateLimiter, RateLimits},
+    register::ReservationCache,
     routes::{
-        change_federation, check_pubkey, check_registration_info, check_username, disable_zaps,
-        health_check, lnurl_callback_route, lnurl_verify_route, register_route, root,
-        validate_cors, well_known_lnurlp_route, well_known_nip5_route,
+        add_domain_route, add_signing_key_route, bind_linking_key, change_federation,
+        change_username, check_pubkey, check_registration_info, check_username, claim_domain,
+        delete_account, disable_zaps, enable_zaps, health_check, lnurl_auth_challenge,
+        lnurl_auth_login, lnurl_auth_session, lnurl_callback_route, lnurl_code, lnurl_qr_png,
+        lnurl_qr_svg, lnurl_verify_events, lnurl_verify_route, lnurl_verify_ws, rate_limit_stats,
+        register_route, renew_route, reserve_name, retire_signing_key_route, root, rotate_key,
+        set_primary_name, set_profile, set_success_action, signing_keys, unreserve_name,
+        validate_cors, verify_domain, well_known_lnurlp_route, well_known_nip5_route,
     },
 };
 
+mod auth;
 mod db;
+mod dns;
+mod domains;
+mod expiry;
 mod invoice;
+mod lnurl_auth;
+mod lnurl_code;
 mod lnurlp;
 mod mint;
 mod models;
 mod nostr;
+mod nostr_commands;
+mod rate_limit;
 mod register;
 mod routes;
+mod signed_command;
 
 const ALLOWED_ORIGINS: [&str; 6] = [
     "https://app.mutinywallet.com",
@@ -46,6 +68,8 @@ const ALLOWED_LOCALHOST: &str = "http://127.0.0.1:";
 
 const API_VERSION: &str = "v1";
 
+const DEFAULT_COMMENT_ALLOWED: u16 = 255;
+
 const RELAYS: [&str; 9] = [
     "wss://nostr.mutinywallet.com",
     "wss://relay.mutinywallet.com",
@@ -58,7 +82,7 @@ const RELAYS: [&str; 9] = [
     "wss://relay.nos.social",
 ];
 
-#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SignerIdentity {
     pub service_id: i32,
     pub plan_id: i32,
@@ -68,9 +92,16 @@ pub struct SignerIdentity {
 pub struct State {
     db: Arc<dyn DBConnection + Send + Sync>,
     mm: Arc<dyn MultiMintWrapperTrait + Send + Sync>,
+    resolver: Arc<dyn TxtResolver + Send + Sync>,
     pub secp: Secp256k1<All>,
     pub nostr: nostr_sdk::Client,
     pub nostr_sk: Keys,
+    /// The operator's nostr key, allowed to manage reserved names
+    pub admin_pubkey: Option<PublicKey>,
+    /// Longest LUD-12 comment accepted with a payment, 0 disables comments
+    pub comment_allowed: u16,
+    subscriptions: InvoiceSubscriptions,
+    reservations: ReservationCache,
     pub domain: String,
     pub free_pk: AggregatePublicKey,
     pub paid_pk: AggregatePublicKey,
@@ -85,6 +116,37 @@ impl State {
     }
 }
 
+/// State for the integration tests, against `DATABASE_URL` with mocked
+/// federations and DNS. Signs with the keys of `BlindSigner::derive(&[0u8; 32], 0, 0)`.
+#[cfg(all(test, feature = "integration-tests"))]
+pub(crate) fn test_state() -> State {
+    dotenv::dotenv().ok();
+    let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+    // nostr
+    let nostr_nsec_str = std::env::var("NSEC").expect("NSEC must be set");
+    let nostr_sk = Keys::from_str(&nostr_nsec_str).expect("Invalid NOSTR_SK");
+    let nostr = nostr_sdk::Client::new(&nostr_sk);
+
+    let signer = register::BlindSigner::derive(&[0u8; 32], 0, 0);
+
+    State {
+        db: setup_db(pg_url),
+        mm: Arc::new(mint::MockMultiMintWrapperTrait::new()),
+        resolver: Arc::new(dns::MockTxtResolver::new()),
+        secp: Secp256k1::new(),
+        nostr,
+        nostr_sk,
+        admin_pubkey: None,
+        comment_allowed: 255,
+        subscriptions: InvoiceSubscriptions::default(),
+        reservations: ReservationCache::default(),
+        domain: "http://127.0.0.1:8080".to_string(),
+        free_pk: signer.pk,
+        paid_pk: signer.pk,
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Load .env file
@@ -140,19 +202,56 @@ async fn main() -> anyhow::Result<()> {
         .expect("DOMAIN_URL must be set")
         .to_string();
 
+    // operator
+    let admin_pubkey = std::env::var("ADMIN_PUBKEY")
+        .ok()
+        .map(|pk| PublicKey::from_str(&pk).expect("Invalid ADMIN_PUBKEY"));
+
+    // custom domains are verified with TXT records
+    let resolver = Arc::new(DohResolver::new(std::env::var("DOH_URL").ok()));
+
+    // payments
+    let comment_allowed: u16 = std::env::var("COMMENT_ALLOWED")
+        .ok()
+        .map(|c| {
+            c.parse::<u16>()
+                .map_err(|e| anyhow::anyhow!("Invalid COMMENT_ALLOWED {c}: {e}"))
+        })
+        .transpose()?
+        .unwrap_or(DEFAULT_COMMENT_ALLOWED);
+
+    // abuse protection
+    let rate_limiter = Arc::new(RateLimiter::new(RateLimits::from_env()?));
+
     let db = setup_db(pg_url);
+    match db.backfill_name_skeletons() {
+        Ok(0) => (),
+        Ok(n) => info!("Backfilled {n} name skeletons"),
+        Err(e) => error!("Error backfilling name skeletons: {e}"),
+    }
     let secp = Secp256k1::new();
     let state = State {
         db,
         mm,
+        resolver,
         secp,
         nostr,
         nostr_sk,
+        admin_pubkey,
+        comment_allowed,
+        subscriptions: InvoiceSubscriptions::default(),
+        reservations: ReservationCache::default(),
         domain,
         free_pk,
         paid_pk,
     };
 
+    // unknown hosts and names from before multiple domains use the one from DOMAIN_URL
+    state
+        .db
+        .set_default_domain_host(&state.domain_no_http())
+        .expect("Failed to set default domain");
+
     // spawn a task to check for previous pending invoices
     let cloned_state = state.clone();
     tokio::spawn(async move {
@@ -161,6 +260,21 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
+    // spawn a task to cancel invoices that expired unpaid
+    tokio::spawn(sweep_expired_invoices(state.clone()));
+
+    // spawn a task to clean up LNURL-auth challenges that can't be used anymore
+    tokio::spawn(sweep_stale_challenges(state.clone()));
+
+    // spawn a task to forget rate limit windows that are over
+    tokio::spawn(prune_rate_limits(rate_limiter.clone()));
+
+    // spawn a task to remind and downgrade expiring paid names
+    tokio::spawn(handle_name_expiry(state.clone()));
+
+    // spawn a task to take commands over nostr, for when the api can't be reached
+    tokio::spawn(handle_nostr_commands(state.clone()));
+
     let addr: std::net::SocketAddr = format!("0.0.0.0:{port}")
         .parse()
         .expect("Failed to parse bind/port for webserver");
@@ -173,7 +287,34 @@ async fn main() -> anyhow::Result<()> {
         .route("/v1/check-registration", post(check_registration_info))
         .route("/v1/change-federation", post(change_federation))
         .route("/v1/disable-zaps", post(disable_zaps))
+        .route("/v1/enable-zaps", post(enable_zaps))
+        .route("/v1/change-username", post(change_username))
+        .route("/v1/delete-account", post(delete_account))
+        .route("/v1/rotate-key", post(rotate_key))
+        .route("/v1/set-primary-name", post(set_primary_name))
+        .route("/v1/set-success-action", post(set_success_action))
+        .route("/v1/set-profile", post(set_profile))
+        .route("/v1/bind-linking-key", post(bind_linking_key))
+        .route("/v1/lnurl-auth", get(lnurl_auth_login))
+        .route("/v1/lnurl-auth/challenge", get(lnurl_auth_challenge))
+        .route("/v1/lnurl-auth/session", get(lnurl_auth_session))
+        .route("/v1/claim-domain", post(claim_domain))
+        .route("/v1/verify-domain", post(verify_domain))
         .route("/v1/register", post(register_route))
+        .route("/v1/renew", post(renew_route))
+        .route("/v1/signing-keys", get(signing_keys))
+        .route("/v1/lnurl/:username", get(lnurl_code))
+        .route("/v1/lnurl/:username/qr.svg", get(lnurl_qr_svg))
+        .route("/v1/lnurl/:username/qr.png", get(lnurl_qr_png))
+        .route("/v1/admin/reserve-name", post(reserve_name))
+        .route("/v1/admin/unreserve-name", post(unreserve_name))
+        .route("/v1/admin/add-signing-key", post(add_signing_key_route))
+        .route(
+            "/v1/admin/retire-signing-key",
+            post(retire_signing_key_route),
+        )
+        .route("/v1/admin/add-domain", post(add_domain_route))
+        .route("/v1/admin/rate-limits", get(rate_limit_stats))
         .route("/.well-known/nostr.json", get(well_known_nip5_route))
         .route(
             "/.well-known/lnurlp/:username",
@@ -181,6 +322,12 @@ async fn main() -> anyhow::Result<()> {
         )
         .route("/lnurlp/:username/callback", get(lnurl_callback_route))
         .route("/lnurlp/:username/verify/:op_id", get(lnurl_verify_route))
+        .route(
+            "/lnurlp/:username/verify/:op_id/events",
+            get(lnurl_verify_events),
+        )
+        .route("/lnurlp/:username/verify/:op_id/ws", get(lnurl_verify_ws))
+        .route_layer(middleware::from_fn(rate_limit))
         .fallback(fallback)
         .layer(
             CorsLayer::new()
@@ -195,6 +342,7 @@ async fn main() -> anyhow::Result<()> {
                 ]),
         )
         .layer(DefaultBodyLimit::max(10_000_000)) // max 10mb body size
+        .layer(Extension(rate_limiter))
         .layer(Extension(state));
 
     // Set up a oneshot channel to handle shutdown signal
@@ -223,7 +371,9 @@ async fn main() -> anyhow::Result<()> {
         let _ = tx.send(());
     });
 
-    let server = axum::Server::bind(&addr).serve(server_router.into_make_service());
+    // the peer address is what requests are rate limited by without a proxy
+    let server = axum::Server::bind(&addr)
+        .serve(server_router.into_make_service_with_connect_info::<SocketAddr>());
 
     info!("Webserver running on http://{addr}");
 
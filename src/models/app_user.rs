@@ -1,9 +1,25 @@
-use crate::models::schema::app_user;
+use crate::invoice::InvoiceState;
+use crate::models::{
+    invoice::Invoice,
+    name_redirect::NameRedirect,
+    renewal_token::NewRenewalToken,
+    schema::{app_user, invoice, name_redirect, zaps},
+};
+use crate::register::name_skeleton;
+use anyhow::anyhow;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::dsl::sql;
 use diesel::prelude::*;
+use diesel::sql_types::{Bool, Integer, Nullable, Text, Timestamp};
 use fedimint_ln_common::bitcoin::secp256k1::XOnlyPublicKey;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
+/// How long the name of a deleted user is held back before it can be registered again
+pub const DELETED_NAME_QUARANTINE_DAYS: i64 = 90;
+
+const DELETED_CANCEL_REASON: &str = "Account deleted";
+
 #[derive(
     QueryableByName, Queryable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq,
 )]
@@ -18,6 +34,25 @@ pub struct AppUser {
     pub federation_invite_code: String,
     pub invoice_index: i32,
     pub disabled_zaps: bool,
+    pub name_changed_at: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+    pub disabled_zaps_at: Option<NaiveDateTime>,
+    pub disabled_zaps_until: Option<NaiveDateTime>,
+    pub disabled_zaps_reason: Option<String>,
+    pub is_primary: bool,
+    /// UTS 39 skeleton of the name, used to find lookalike names
+    pub name_skeleton: Option<String>,
+    /// When a paid name expires, free names don't
+    pub expires_at: Option<NaiveDateTime>,
+    pub expiry_reminded_at: Option<NaiveDateTime>,
+    /// The domain the name is registered under
+    pub domain_id: i32,
+    /// LUD-09 success action given with invoices, as JSON
+    pub success_action: Option<String>,
+    /// LNURL-auth linking key allowed to manage the address
+    pub linking_key: Option<String>,
+    /// LNURL metadata the user set for the address, as JSON
+    pub profile: Option<String>,
 }
 
 impl AppUser {
@@ -25,6 +60,15 @@ impl AppUser {
         XOnlyPublicKey::from_str(&self.pubkey).expect("invalid pubkey")
     }
 
+    /// Whether the address is currently refusing payments, a temporary
+    /// disable lapses once `disabled_zaps_until` has passed.
+    pub fn zaps_disabled(&self) -> bool {
+        self.disabled_zaps
+            && !self
+                .disabled_zaps_until
+                .is_some_and(|until| until <= Utc::now().naive_utc())
+    }
+
     pub fn get_app_users(conn: &mut PgConnection) -> anyhow::Result<Vec<AppUser>> {
         Ok(app_user::table.load::<Self>(conn)?)
     }
@@ -36,20 +80,29 @@ impl AppUser {
             .optional()?)
     }
 
-    pub fn get_by_name(conn: &mut PgConnection, name: String) -> anyhow::Result<Option<AppUser>> {
+    pub fn get_by_name(
+        conn: &mut PgConnection,
+        domain_id: i32,
+        name: String,
+    ) -> anyhow::Result<Option<AppUser>> {
         Ok(app_user::table
+            .filter(app_user::domain_id.eq(domain_id))
             .filter(app_user::name.eq(name))
+            .filter(app_user::deleted_at.is_null())
             .first::<AppUser>(conn)
             .optional()?)
     }
 
     pub fn get_by_name_and_increment_counter(
         conn: &mut PgConnection,
+        domain_id: i32,
         name: &str,
     ) -> anyhow::Result<Option<AppUser>> {
         conn.transaction(|conn| {
             let user = app_user::table
+                .filter(app_user::domain_id.eq(domain_id))
                 .filter(app_user::name.eq(name))
+                .filter(app_user::deleted_at.is_null())
                 .first::<AppUser>(conn)
                 .optional()?;
 
@@ -64,12 +117,59 @@ impl AppUser {
         })
     }
 
-    pub fn check_available_name(conn: &mut PgConnection, name: String) -> anyhow::Result<bool> {
-        Ok(app_user::table
-            .filter(app_user::name.eq(name))
+    pub fn get_by_redirected_name(
+        conn: &mut PgConnection,
+        domain_id: i32,
+        name: String,
+    ) -> anyhow::Result<Option<AppUser>> {
+        Ok(name_redirect::table
+            .inner_join(app_user::table)
+            .filter(name_redirect::domain_id.eq(domain_id))
+            .filter(name_redirect::name.eq(name))
+            .filter(name_redirect::expires_at.gt(Utc::now().naive_utc()))
+            .filter(app_user::deleted_at.is_null())
+            .select(app_user::all_columns)
+            .first::<AppUser>(conn)
+            .optional()?)
+    }
+
+    /// A name is taken if it, or a name that looks like it, belongs to a user
+    /// of the domain other than `exclude_user`.
+    pub fn check_available_name(
+        conn: &mut PgConnection,
+        domain_id: i32,
+        name: String,
+        exclude_user: Option<i32>,
+    ) -> anyhow::Result<bool> {
+        // names of deleted users are quarantined for a while so they can't be squatted
+        let quarantine_cutoff =
+            Utc::now().naive_utc() - Duration::days(DELETED_NAME_QUARANTINE_DAYS);
+        // lookalikes as in `is_lookalike`, a skeleton only counts when either
+        // name goes outside of ASCII
+        let lookalike = app_user::name_skeleton.eq(name_skeleton(&name)).and(
+            sql::<Bool>("(octet_length(name) <> char_length(name) OR ")
+                .bind::<Bool, _>(!name.is_ascii())
+                .sql(")"),
+        );
+        let taken = app_user::table
+            .filter(app_user::domain_id.eq(domain_id))
+            .filter(app_user::name.eq(&name).or(lookalike))
+            .filter(app_user::id.ne(exclude_user.unwrap_or(-1)))
+            .filter(
+                app_user::deleted_at
+                    .is_null()
+                    .or(app_user::deleted_at.gt(quarantine_cutoff)),
+            )
             .count()
             .get_result::<i64>(conn)?
-            == 0)
+            > 0;
+        if taken {
+            return Ok(false);
+        }
+
+        // names that are still redirecting to a renamed user are not available
+        let redirect = NameRedirect::get_by_name(conn, domain_id, name)?;
+        Ok(!redirect.is_some_and(|r| r.is_active()))
     }
 
     pub fn get_by_token(conn: &mut PgConnection, msg: String) -> anyhow::Result<Option<AppUser>> {
@@ -85,10 +185,137 @@ impl AppUser {
     ) -> anyhow::Result<Option<AppUser>> {
         Ok(app_user::table
             .filter(app_user::pubkey.eq(pubkey))
+            .filter(app_user::deleted_at.is_null())
+            .order((app_user::is_primary.desc(), app_user::id.asc()))
             .first::<AppUser>(conn)
             .optional()?)
     }
 
+    /// Extends the name to `expires_at` with a paid token, returns false if
+    /// the token was already spent on a renewal.
+    pub fn renew(
+        &self,
+        conn: &mut PgConnection,
+        unblinded_msg: String,
+        expires_at: NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        conn.transaction(|conn| {
+            let token = NewRenewalToken {
+                unblinded_msg,
+                app_user_id: self.id,
+                expires_at,
+            };
+            if !token.insert(conn)? {
+                return Ok(false);
+            }
+
+            diesel::update(app_user::table)
+                .filter(app_user::id.eq(self.id))
+                .set((
+                    app_user::expires_at.eq(expires_at),
+                    app_user::expiry_reminded_at.eq(None::<NaiveDateTime>),
+                ))
+                .execute(conn)?;
+
+            Ok(true)
+        })
+    }
+
+    /// Active users whose name expires before `cutoff` and haven't been reminded yet
+    pub fn get_expiring(
+        conn: &mut PgConnection,
+        cutoff: NaiveDateTime,
+    ) -> anyhow::Result<Vec<AppUser>> {
+        Ok(app_user::table
+            .filter(app_user::expires_at.lt(cutoff))
+            .filter(app_user::expiry_reminded_at.is_null())
+            .filter(app_user::deleted_at.is_null())
+            .load::<AppUser>(conn)?)
+    }
+
+    /// Active users whose name expired before `cutoff`
+    pub fn get_expired(
+        conn: &mut PgConnection,
+        cutoff: NaiveDateTime,
+    ) -> anyhow::Result<Vec<AppUser>> {
+        Ok(app_user::table
+            .filter(app_user::expires_at.lt(cutoff))
+            .filter(app_user::deleted_at.is_null())
+            .load::<AppUser>(conn)?)
+    }
+
+    pub fn set_expiry_reminded(&self, conn: &mut PgConnection) -> anyhow::Result<()> {
+        diesel::update(app_user::table)
+            .filter(app_user::id.eq(self.id))
+            .set(app_user::expiry_reminded_at.eq(Utc::now().naive_utc()))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Replaces an expired paid name with a free one. The old name is released
+    /// right away, without a redirect, so it can be registered again. It
+    /// counts as a name change, and the free name can't be changed anyway.
+    pub fn downgrade_name(&self, conn: &mut PgConnection, new_name: String) -> anyhow::Result<()> {
+        diesel::update(app_user::table)
+            .filter(app_user::id.eq(self.id))
+            .set((
+                app_user::name.eq(&new_name),
+                app_user::name_skeleton.eq(name_skeleton(&new_name)),
+                app_user::name_changed_at.eq(Utc::now().naive_utc()),
+                app_user::expires_at.eq(None::<NaiveDateTime>),
+                app_user::expiry_reminded_at.eq(None::<NaiveDateTime>),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Fills in the skeletons of names registered before they were tracked
+    pub fn backfill_name_skeletons(conn: &mut PgConnection) -> anyhow::Result<usize> {
+        let users = app_user::table
+            .filter(app_user::name_skeleton.is_null())
+            .load::<AppUser>(conn)?;
+
+        for user in users.iter() {
+            diesel::update(app_user::table)
+                .filter(app_user::id.eq(user.id))
+                .set(app_user::name_skeleton.eq(name_skeleton(&user.name)))
+                .execute(conn)?;
+        }
+
+        Ok(users.len())
+    }
+
+    /// All of the addresses registered to a pubkey, the primary one first
+    pub fn get_all_by_pubkey(
+        conn: &mut PgConnection,
+        pubkey: String,
+    ) -> anyhow::Result<Vec<AppUser>> {
+        Ok(app_user::table
+            .filter(app_user::pubkey.eq(pubkey))
+            .filter(app_user::deleted_at.is_null())
+            .order((app_user::is_primary.desc(), app_user::id.asc()))
+            .load::<AppUser>(conn)?)
+    }
+
+    /// Makes this the primary address of its pubkey
+    pub fn set_primary(&self, conn: &mut PgConnection) -> anyhow::Result<()> {
+        conn.transaction(|conn| {
+            diesel::update(app_user::table)
+                .filter(app_user::pubkey.eq(&self.pubkey))
+                .set(app_user::is_primary.eq(false))
+                .execute(conn)?;
+
+            diesel::update(app_user::table)
+                .filter(app_user::id.eq(self.id))
+                .set(app_user::is_primary.eq(true))
+                .execute(conn)?;
+
+            Ok(())
+        })
+    }
+
     pub fn update_federation(
         &self,
         conn: &mut PgConnection,
@@ -96,21 +323,246 @@ impl AppUser {
         new_federation_invite_code: String,
     ) -> anyhow::Result<()> {
         diesel::update(app_user::table)
-            .filter(app_user::name.eq(&self.name))
+            .filter(app_user::id.eq(self.id))
             .set((
                 app_user::federation_id.eq(new_federation_id),
                 app_user::federation_invite_code.eq(new_federation_invite_code),
                 app_user::disabled_zaps.eq(false),
+                app_user::disabled_zaps_at.eq(None::<NaiveDateTime>),
+                app_user::disabled_zaps_until.eq(None::<NaiveDateTime>),
+                app_user::disabled_zaps_reason.eq(None::<String>),
             ))
             .execute(conn)?;
 
         Ok(())
     }
 
-    pub fn disable_zaps(&self, conn: &mut PgConnection) -> anyhow::Result<()> {
+    /// Moves the user to `new_name` and keeps the old name redirecting
+    /// to them until `redirect_expires_at`.
+    pub fn change_name(
+        &self,
+        conn: &mut PgConnection,
+        new_name: String,
+        redirect_expires_at: NaiveDateTime,
+    ) -> anyhow::Result<()> {
+        conn.transaction(|conn| {
+            // users are allowed to reclaim one of their own old names
+            diesel::delete(name_redirect::table)
+                .filter(name_redirect::name.eq(&new_name))
+                .filter(name_redirect::app_user_id.eq(self.id))
+                .execute(conn)?;
+
+            if !Self::check_available_name(conn, self.domain_id, new_name.clone(), Some(self.id))? {
+                return Err(anyhow!("Unavailable"));
+            }
+
+            diesel::update(app_user::table)
+                .filter(app_user::id.eq(self.id))
+                .set((
+                    app_user::name.eq(&new_name),
+                    app_user::name_skeleton.eq(name_skeleton(&new_name)),
+                    app_user::name_changed_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)?;
+
+            let redirect = NameRedirect {
+                name: self.name.clone(),
+                app_user_id: self.id,
+                expires_at: redirect_expires_at,
+                domain_id: self.domain_id,
+            };
+            diesel::insert_into(name_redirect::table)
+                .values(&redirect)
+                .on_conflict((name_redirect::domain_id, name_redirect::name))
+                .do_update()
+                .set((
+                    name_redirect::app_user_id.eq(self.id),
+                    name_redirect::expires_at.eq(redirect_expires_at),
+                ))
+                .execute(conn)?;
+
+            Ok(())
+        })
+    }
+
+    /// Moves every active user registered to `old_pubkey` over to `new_pubkey`.
+    /// Their pending invoices keep the key they were tweaked for.
+    pub fn rotate_pubkey(
+        conn: &mut PgConnection,
+        old_pubkey: String,
+        new_pubkey: String,
+    ) -> anyhow::Result<usize> {
+        conn.transaction(|conn| {
+            let user_ids = app_user::table
+                .filter(app_user::pubkey.eq(&old_pubkey))
+                .filter(app_user::deleted_at.is_null())
+                .select(app_user::id)
+                .load::<i32>(conn)?;
+            if user_ids.is_empty() {
+                return Ok(0);
+            }
+
+            let has_primary = diesel::select(diesel::dsl::exists(
+                app_user::table
+                    .filter(app_user::pubkey.eq(&new_pubkey))
+                    .filter(app_user::deleted_at.is_null())
+                    .filter(app_user::is_primary.eq(true)),
+            ))
+            .get_result::<bool>(conn)?;
+
+            let updated = diesel::update(app_user::table)
+                .filter(app_user::id.eq_any(&user_ids))
+                .set(app_user::pubkey.eq(&new_pubkey))
+                .execute(conn)?;
+
+            // the new key's own primary address stays its only one
+            if has_primary {
+                diesel::update(app_user::table)
+                    .filter(app_user::id.eq_any(&user_ids))
+                    .set(app_user::is_primary.eq(false))
+                    .execute(conn)?;
+            }
+
+            Ok(updated)
+        })
+    }
+
+    /// Soft deletes the user, their invoices are anonymized and their zaps removed.
+    /// Returns the invoices that were still pending and got cancelled.
+    pub fn delete(&self, conn: &mut PgConnection) -> anyhow::Result<Vec<Invoice>> {
+        conn.transaction(|conn| {
+            let invoice_ids = invoice::table
+                .filter(invoice::app_user_id.eq(self.id))
+                .select(invoice::id);
+            diesel::delete(zaps::table)
+                .filter(zaps::id.eq_any(invoice_ids))
+                .execute(conn)?;
+
+            // locked until the update, so the invoices returned are exactly
+            // the ones it cancels and none settles in between
+            let cancelled = invoice::table
+                .filter(invoice::app_user_id.eq(self.id))
+                .filter(invoice::state.eq(InvoiceState::Pending as i32))
+                .for_update()
+                .load::<Invoice>(conn)?;
+
+            // pending invoices will never be served again, and none of them
+            // keeps the details of the payment
+            let pending = InvoiceState::Pending as i32;
+            diesel::update(invoice::table)
+                .filter(invoice::app_user_id.eq(self.id))
+                .set((
+                    invoice::state.eq(sql::<Integer>("CASE WHEN state = ")
+                        .bind::<Integer, _>(pending)
+                        .sql(" THEN ")
+                        .bind::<Integer, _>(InvoiceState::Cancelled as i32)
+                        .sql(" ELSE state END")),
+                    invoice::cancelled_at.eq(sql::<Nullable<Timestamp>>("CASE WHEN state = ")
+                        .bind::<Integer, _>(pending)
+                        .sql(" THEN ")
+                        .bind::<Timestamp, _>(Utc::now().naive_utc())
+                        .sql(" ELSE cancelled_at END")),
+                    invoice::cancel_reason.eq(sql::<Nullable<Text>>("CASE WHEN state = ")
+                        .bind::<Integer, _>(pending)
+                        .sql(" THEN ")
+                        .bind::<Text, _>(DELETED_CANCEL_REASON)
+                        .sql(" ELSE cancel_reason END")),
+                    invoice::preimage.eq(""),
+                    invoice::bolt11.eq(""),
+                    invoice::pubkey.eq(""),
+                ))
+                .execute(conn)?;
+
+            diesel::update(app_user::table)
+                .filter(app_user::id.eq(self.id))
+                .set((
+                    app_user::deleted_at.eq(Utc::now().naive_utc()),
+                    app_user::disabled_zaps.eq(true),
+                ))
+                .execute(conn)?;
+
+            Ok(cancelled)
+        })
+    }
+
+    pub fn disable_zaps(
+        &self,
+        conn: &mut PgConnection,
+        reason: Option<String>,
+        until: Option<NaiveDateTime>,
+    ) -> anyhow::Result<()> {
+        diesel::update(app_user::table)
+            .filter(app_user::id.eq(self.id))
+            .set((
+                app_user::disabled_zaps.eq(true),
+                app_user::disabled_zaps_at.eq(Utc::now().naive_utc()),
+                app_user::disabled_zaps_until.eq(until),
+                app_user::disabled_zaps_reason.eq(reason),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn enable_zaps(&self, conn: &mut PgConnection) -> anyhow::Result<()> {
+        diesel::update(app_user::table)
+            .filter(app_user::id.eq(self.id))
+            .set((
+                app_user::disabled_zaps.eq(false),
+                app_user::disabled_zaps_at.eq(None::<NaiveDateTime>),
+                app_user::disabled_zaps_until.eq(None::<NaiveDateTime>),
+                app_user::disabled_zaps_reason.eq(None::<String>),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn set_linking_key(
+        &self,
+        conn: &mut PgConnection,
+        linking_key: Option<String>,
+    ) -> anyhow::Result<()> {
+        diesel::update(app_user::table)
+            .filter(app_user::id.eq(self.id))
+            .set(app_user::linking_key.eq(linking_key))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn get_by_linking_key(
+        conn: &mut PgConnection,
+        linking_key: &str,
+    ) -> anyhow::Result<Vec<AppUser>> {
+        Ok(app_user::table
+            .filter(app_user::linking_key.eq(linking_key))
+            .filter(app_user::deleted_at.is_null())
+            .order((app_user::is_primary.desc(), app_user::id.asc()))
+            .load::<AppUser>(conn)?)
+    }
+
+    pub fn set_success_action(
+        &self,
+        conn: &mut PgConnection,
+        success_action: Option<String>,
+    ) -> anyhow::Result<()> {
+        diesel::update(app_user::table)
+            .filter(app_user::id.eq(self.id))
+            .set(app_user::success_action.eq(success_action))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn set_profile(
+        &self,
+        conn: &mut PgConnection,
+        profile: Option<String>,
+    ) -> anyhow::Result<()> {
         diesel::update(app_user::table)
-            .filter(app_user::name.eq(&self.name))
-            .set((app_user::disabled_zaps.eq(true),))
+            .filter(app_user::id.eq(self.id))
+            .set(app_user::profile.eq(profile))
             .execute(conn)?;
 
         Ok(())
@@ -125,12 +577,14 @@ pub struct NewAppUser {
     pub federation_id: String,
     pub unblinded_msg: String,
     pub federation_invite_code: String,
+    pub expires_at: Option<NaiveDateTime>,
+    pub domain_id: i32,
 }
 
 impl NewAppUser {
     pub fn insert(&self, conn: &mut PgConnection) -> anyhow::Result<AppUser> {
         diesel::insert_into(app_user::table)
-            .values(self)
+            .values((self, app_user::name_skeleton.eq(name_skeleton(&self.name))))
             .get_result::<AppUser>(conn)
             .map_err(|e| e.into())
     }
@@ -0,0 +1,128 @@
+use crate::models::schema::domains;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// A domain names are registered under, each one is its own namespace
+#[derive(
+    QueryableByName, Queryable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq,
+)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = domains)]
+pub struct Domain {
+    pub id: i32,
+    /// Host the domain is served on, as sent in the `Host` header
+    pub host: String,
+    /// The domain from `DOMAIN_URL`, used for unknown hosts
+    pub is_default: bool,
+    #[serde(skip_serializing)]
+    pub nostr_nsec: Option<String>,
+    pub lnurl_description: Option<String>,
+    /// Origins allowed on top of the service wide ones
+    pub allowed_origins: Vec<String>,
+    pub created_at: NaiveDateTime,
+    /// The address backing a domain a user brought, `None` for the operator's
+    pub owner_user_id: Option<i32>,
+    /// The only name served on a user's domain
+    pub owner_name: Option<String>,
+    pub verified_at: Option<NaiveDateTime>,
+}
+
+impl Domain {
+    pub fn get_by_id(conn: &mut PgConnection, id: i32) -> anyhow::Result<Option<Domain>> {
+        Ok(domains::table
+            .filter(domains::id.eq(id))
+            .first::<Domain>(conn)
+            .optional()?)
+    }
+
+    pub fn get_by_host(conn: &mut PgConnection, host: &str) -> anyhow::Result<Option<Domain>> {
+        Ok(domains::table
+            .filter(domains::host.eq(host.to_lowercase()))
+            .first::<Domain>(conn)
+            .optional()?)
+    }
+
+    pub fn get_default(conn: &mut PgConnection) -> anyhow::Result<Domain> {
+        Ok(domains::table
+            .filter(domains::is_default.eq(true))
+            .first::<Domain>(conn)?)
+    }
+
+    /// Points the default domain at the host of `DOMAIN_URL`
+    pub fn set_default_host(conn: &mut PgConnection, host: &str) -> anyhow::Result<()> {
+        diesel::update(domains::table)
+            .filter(domains::is_default.eq(true))
+            .set(domains::host.eq(host.to_lowercase()))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = domains)]
+pub struct NewDomain {
+    pub host: String,
+    pub nostr_nsec: Option<String>,
+    pub lnurl_description: Option<String>,
+    pub allowed_origins: Vec<String>,
+}
+
+impl NewDomain {
+    /// Adds the domain, or updates its settings if the host already exists
+    pub fn upsert(&self, conn: &mut PgConnection) -> anyhow::Result<Domain> {
+        Ok(diesel::insert_into(domains::table)
+            .values(self)
+            .on_conflict(domains::host)
+            .do_update()
+            .set((
+                domains::nostr_nsec.eq(&self.nostr_nsec),
+                domains::lnurl_description.eq(&self.lnurl_description),
+                domains::allowed_origins.eq(&self.allowed_origins),
+            ))
+            .get_result::<Domain>(conn)?)
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = domains)]
+pub struct NewCustomDomain {
+    pub host: String,
+    pub owner_user_id: i32,
+    pub owner_name: String,
+    pub verified_at: NaiveDateTime,
+}
+
+impl NewCustomDomain {
+    /// Adds the domain, or hands it to whoever verified it last. Returns
+    /// `None` if the host is one of the operator's domains.
+    pub fn upsert(&self, conn: &mut PgConnection) -> anyhow::Result<Option<Domain>> {
+        conn.transaction(|conn| {
+            let existing = domains::table
+                .filter(domains::host.eq(&self.host))
+                .for_update()
+                .first::<Domain>(conn)
+                .optional()?;
+
+            match existing {
+                None => Ok(Some(
+                    diesel::insert_into(domains::table)
+                        .values(self)
+                        .get_result::<Domain>(conn)?,
+                )),
+                Some(domain) if domain.owner_user_id.is_none() => Ok(None),
+                Some(domain) => Ok(Some(
+                    diesel::update(domains::table)
+                        .filter(domains::id.eq(domain.id))
+                        .set((
+                            domains::owner_user_id.eq(self.owner_user_id),
+                            domains::owner_name.eq(&self.owner_name),
+                            domains::verified_at.eq(self.verified_at),
+                        ))
+                        .get_result::<Domain>(conn)?,
+                )),
+            }
+        })
+    }
+}
@@ -1,6 +1,10 @@
 use std::str::FromStr;
+use crate::invoice::InvoiceState;
 use crate::models::schema::invoice;
+use anyhow::anyhow;
+use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
+use diesel::upsert::DecoratableTarget;
 use fedimint_ln_common::lightning_invoice::Bolt11Invoice;
 use serde::{Deserialize, Serialize};
 
@@ -19,11 +23,26 @@ pub struct Invoice {
     pub bolt11: String,
     pub amount: i64,
     pub state: i32,
+    pub pubkey: String,
+    /// LUD-12 comment the payer sent with the payment
+    pub comment: Option<String>,
+    /// LUD-18 payer identity sent with the payment, as JSON
+    pub payer_data: Option<String>,
+    /// Hash of the callback parameters, retries with the same ones get
+    /// this invoice back
+    pub idempotency_key: Option<String>,
+    pub cancelled_at: Option<NaiveDateTime>,
+    /// Why the invoice was cancelled instead of paid
+    pub cancel_reason: Option<String>,
+    /// When the user was told of the payment, settled invoices without it
+    /// are retried
+    pub notified_at: Option<NaiveDateTime>,
 }
 
 impl Invoice {
-    pub fn bolt11(&self) -> Bolt11Invoice {
-        Bolt11Invoice::from_str(&self.bolt11).expect("invalid bolt11")
+    /// The invoice is blanked once its user is deleted, and won't parse anymore
+    pub fn bolt11(&self) -> anyhow::Result<Bolt11Invoice> {
+        Bolt11Invoice::from_str(&self.bolt11).map_err(|e| anyhow!("Invalid bolt11: {e}"))
     }
 
     pub fn get_invoices(conn: &mut PgConnection) -> anyhow::Result<Vec<Invoice>> {
@@ -47,12 +66,53 @@ impl Invoice {
             .optional()?)
     }
 
+    /// The latest invoice of a user created for the idempotency key
+    pub fn get_by_idempotency_key(
+        conn: &mut PgConnection,
+        app_user_id: i32,
+        key: &str,
+    ) -> anyhow::Result<Option<Invoice>> {
+        Ok(invoice::table
+            .filter(invoice::app_user_id.eq(app_user_id))
+            .filter(invoice::idempotency_key.eq(key))
+            .order(invoice::id.desc())
+            .first::<Invoice>(conn)
+            .optional()?)
+    }
+
     pub fn get_by_state(conn: &mut PgConnection, state: i32) -> anyhow::Result<Vec<Invoice>> {
         Ok(invoice::table
             .filter(invoice::state.eq(state))
             .load::<Invoice>(conn)?)
     }
 
+    /// Settled invoices whose user wasn't told of the payment yet
+    pub fn get_unnotified(conn: &mut PgConnection) -> anyhow::Result<Vec<Invoice>> {
+        Ok(invoice::table
+            .filter(invoice::state.eq(InvoiceState::Settled as i32))
+            .filter(invoice::notified_at.is_null())
+            .load::<Invoice>(conn)?)
+    }
+
+    pub fn set_notified(&self, conn: &mut PgConnection) -> anyhow::Result<()> {
+        diesel::update(invoice::table)
+            .filter(invoice::id.eq(self.id))
+            .set(invoice::notified_at.eq(Some(Utc::now().naive_utc())))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Lets a later invoice take over the idempotency key
+    pub fn release_idempotency_key(&self, conn: &mut PgConnection) -> anyhow::Result<()> {
+        diesel::update(invoice::table)
+            .filter(invoice::id.eq(self.id))
+            .set(invoice::idempotency_key.eq(None::<String>))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
     pub fn set_state(&self, conn: &mut PgConnection, s: i32) -> anyhow::Result<()> {
         diesel::update(invoice::table)
             .filter(invoice::id.eq(self.id))
@@ -61,6 +121,21 @@ impl Invoice {
 
         Ok(())
     }
+
+    /// Cancels the invoice if it's still pending, returns whether it was
+    pub fn cancel(&self, conn: &mut PgConnection, reason: &str) -> anyhow::Result<bool> {
+        let updated = diesel::update(invoice::table)
+            .filter(invoice::id.eq(self.id))
+            .filter(invoice::state.eq(InvoiceState::Pending as i32))
+            .set((
+                invoice::state.eq(InvoiceState::Cancelled as i32),
+                invoice::cancelled_at.eq(Some(Utc::now().naive_utc())),
+                invoice::cancel_reason.eq(Some(reason)),
+            ))
+            .execute(conn)?;
+
+        Ok(updated > 0)
+    }
 }
 
 #[derive(Insertable)]
@@ -74,13 +149,45 @@ pub struct NewInvoice {
     pub bolt11: String,
     pub amount: i64,
     pub state: i32,
+    pub pubkey: String,
+    pub comment: Option<String>,
+    pub payer_data: Option<String>,
+    pub idempotency_key: Option<String>,
 }
 
 impl NewInvoice {
+    /// Inserts the invoice, unless a pending one already has its idempotency
+    /// key, then that one is returned instead
     pub fn insert(&self, conn: &mut PgConnection) -> anyhow::Result<Invoice> {
-        diesel::insert_into(invoice::table)
+        let Some(ref key) = self.idempotency_key else {
+            return Ok(diesel::insert_into(invoice::table)
+                .values(self)
+                .get_result::<Invoice>(conn)?);
+        };
+
+        // only the pending idempotency key index is a conflict, any other
+        // violation is still an error
+        let pending = InvoiceState::Pending as i32;
+        let inserted = diesel::insert_into(invoice::table)
             .values(self)
+            .on_conflict((invoice::app_user_id, invoice::idempotency_key))
+            .filter_target(
+                invoice::idempotency_key
+                    .is_not_null()
+                    .and(invoice::state.eq(pending)),
+            )
+            .do_nothing()
             .get_result::<Invoice>(conn)
-            .map_err(|e| e.into())
+            .optional()?;
+
+        match inserted {
+            Some(invoice) => Ok(invoice),
+            None => invoice::table
+                .filter(invoice::app_user_id.eq(self.app_user_id))
+                .filter(invoice::idempotency_key.eq(key))
+                .filter(invoice::state.eq(pending))
+                .first::<Invoice>(conn)
+                .map_err(|e| e.into()),
+        }
     }
 }
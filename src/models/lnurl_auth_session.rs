@@ -0,0 +1,173 @@
+use crate::models::schema::lnurl_auth_sessions;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// What an LNURL-auth challenge was handed out for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LnurlAuthPurpose {
+    /// A LUD-04 login, which grants a session once signed
+    Login = 0,
+    /// The `auth` field of LUD-18 payer data, only stored once a payer
+    /// signed it
+    PayerAuth = 1,
+}
+
+/// An LNURL-auth login, from the challenge to the session it grants
+#[derive(
+    QueryableByName, Queryable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq,
+)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = lnurl_auth_sessions)]
+pub struct LnurlAuthSession {
+    pub k1: String,
+    /// The key that signed the challenge, once it has been
+    pub linking_key: Option<String>,
+    #[serde(skip_serializing)]
+    pub token_hash: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub authenticated_at: Option<NaiveDateTime>,
+    pub token_expires_at: Option<NaiveDateTime>,
+    pub purpose: i32,
+    /// When the linking key was bound to addresses with the challenge
+    pub bound_at: Option<NaiveDateTime>,
+}
+
+impl LnurlAuthSession {
+    pub fn get_by_k1(conn: &mut PgConnection, k1: &str) -> anyhow::Result<Option<Self>> {
+        Ok(lnurl_auth_sessions::table
+            .filter(lnurl_auth_sessions::k1.eq(k1))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// The session a token was issued for, if it hasn't expired
+    pub fn get_by_token_hash(
+        conn: &mut PgConnection,
+        token_hash: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        Ok(lnurl_auth_sessions::table
+            .filter(lnurl_auth_sessions::token_hash.eq(token_hash))
+            .filter(lnurl_auth_sessions::token_expires_at.gt(Utc::now().naive_utc()))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    /// Records the key that signed the challenge, returns false if the
+    /// challenge is unknown, for something else, already signed or was
+    /// issued before `issued_after`
+    pub fn authenticate(
+        conn: &mut PgConnection,
+        k1: &str,
+        linking_key: &str,
+        purpose: LnurlAuthPurpose,
+        issued_after: NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        let updated = diesel::update(lnurl_auth_sessions::table)
+            .filter(lnurl_auth_sessions::k1.eq(k1))
+            .filter(lnurl_auth_sessions::purpose.eq(purpose as i32))
+            .filter(lnurl_auth_sessions::linking_key.is_null())
+            .filter(lnurl_auth_sessions::created_at.gt(issued_after))
+            .set((
+                lnurl_auth_sessions::linking_key.eq(linking_key),
+                lnurl_auth_sessions::authenticated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /// Records a payer auth challenge as signed by `linking_key`, returns
+    /// false if it already was
+    pub fn use_payer_auth(
+        conn: &mut PgConnection,
+        k1: &str,
+        linking_key: &str,
+    ) -> anyhow::Result<bool> {
+        let inserted = diesel::insert_into(lnurl_auth_sessions::table)
+            .values((
+                lnurl_auth_sessions::k1.eq(k1),
+                lnurl_auth_sessions::purpose.eq(LnurlAuthPurpose::PayerAuth as i32),
+                lnurl_auth_sessions::linking_key.eq(linking_key),
+                lnurl_auth_sessions::authenticated_at.eq(Utc::now().naive_utc()),
+            ))
+            .on_conflict(lnurl_auth_sessions::k1)
+            .do_nothing()
+            .execute(conn)?;
+
+        Ok(inserted > 0)
+    }
+
+    /// Uses up a login challenge signed after `signed_after` to bind its
+    /// linking key, returns the key or none if the challenge is unknown, for
+    /// something else, unsigned, stale or was already used to bind
+    pub fn use_for_binding(
+        conn: &mut PgConnection,
+        k1: &str,
+        signed_after: NaiveDateTime,
+    ) -> anyhow::Result<Option<String>> {
+        Ok(diesel::update(lnurl_auth_sessions::table)
+            .filter(lnurl_auth_sessions::k1.eq(k1))
+            .filter(lnurl_auth_sessions::purpose.eq(LnurlAuthPurpose::Login as i32))
+            .filter(lnurl_auth_sessions::authenticated_at.gt(signed_after))
+            .filter(lnurl_auth_sessions::bound_at.is_null())
+            .set(lnurl_auth_sessions::bound_at.eq(Utc::now().naive_utc()))
+            .returning(lnurl_auth_sessions::linking_key)
+            .get_result::<Option<String>>(conn)
+            .optional()?
+            .flatten())
+    }
+
+    /// Attaches the session token to a signed login challenge, returns false
+    /// if it isn't signed yet or already has a token
+    pub fn issue_token(
+        conn: &mut PgConnection,
+        k1: &str,
+        token_hash: &str,
+        expires_at: NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        let updated = diesel::update(lnurl_auth_sessions::table)
+            .filter(lnurl_auth_sessions::k1.eq(k1))
+            .filter(lnurl_auth_sessions::purpose.eq(LnurlAuthPurpose::Login as i32))
+            .filter(lnurl_auth_sessions::linking_key.is_not_null())
+            .filter(lnurl_auth_sessions::token_hash.is_null())
+            .set((
+                lnurl_auth_sessions::token_hash.eq(token_hash),
+                lnurl_auth_sessions::token_expires_at.eq(expires_at),
+            ))
+            .execute(conn)?;
+
+        Ok(updated > 0)
+    }
+
+    /// Deletes challenges issued before `issued_before` that don't hold a
+    /// live session, returns how many
+    pub fn delete_stale(
+        conn: &mut PgConnection,
+        issued_before: NaiveDateTime,
+    ) -> anyhow::Result<usize> {
+        Ok(diesel::delete(lnurl_auth_sessions::table)
+            .filter(lnurl_auth_sessions::created_at.lt(issued_before))
+            .filter(
+                lnurl_auth_sessions::token_expires_at
+                    .is_null()
+                    .or(lnurl_auth_sessions::token_expires_at.lt(Utc::now().naive_utc())),
+            )
+            .execute(conn)?)
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = lnurl_auth_sessions)]
+pub struct NewLnurlAuthSession {
+    pub k1: String,
+    pub purpose: i32,
+}
+
+impl NewLnurlAuthSession {
+    pub fn insert(&self, conn: &mut PgConnection) -> anyhow::Result<LnurlAuthSession> {
+        Ok(diesel::insert_into(lnurl_auth_sessions::table)
+            .values(self)
+            .get_result::<LnurlAuthSession>(conn)?)
+    }
+}
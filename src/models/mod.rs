@@ -1,4 +1,11 @@
 pub mod app_user;
+pub mod domain;
 pub mod invoice;
+pub mod lnurl_auth_session;
+pub mod name_redirect;
+pub mod renewal_token;
+pub mod reserved_name;
 mod schema;
+pub mod signing_key;
+pub mod used_event_id;
 pub mod zaps;
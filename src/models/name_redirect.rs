@@ -0,0 +1,43 @@
+use crate::models::schema::name_redirect;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    QueryableByName,
+    Queryable,
+    Insertable,
+    AsChangeset,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    PartialEq,
+)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = name_redirect)]
+#[diesel(primary_key(domain_id, name))]
+pub struct NameRedirect {
+    pub name: String,
+    pub app_user_id: i32,
+    pub expires_at: NaiveDateTime,
+    pub domain_id: i32,
+}
+
+impl NameRedirect {
+    pub fn get_by_name(
+        conn: &mut PgConnection,
+        domain_id: i32,
+        name: String,
+    ) -> anyhow::Result<Option<NameRedirect>> {
+        Ok(name_redirect::table
+            .filter(name_redirect::domain_id.eq(domain_id))
+            .filter(name_redirect::name.eq(name))
+            .first::<NameRedirect>(conn)
+            .optional()?)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.expires_at > chrono::Utc::now().naive_utc()
+    }
+}
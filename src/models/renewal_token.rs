@@ -0,0 +1,32 @@
+use crate::models::schema::renewal_token;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+/// A paid token spent on extending a name, the expiry it extended the name to
+#[derive(Insertable)]
+#[diesel(table_name = renewal_token)]
+pub struct NewRenewalToken {
+    pub unblinded_msg: String,
+    pub app_user_id: i32,
+    pub expires_at: NaiveDateTime,
+}
+
+impl NewRenewalToken {
+    /// Records the token, returns false if it was already spent
+    pub fn insert(&self, conn: &mut PgConnection) -> anyhow::Result<bool> {
+        let inserted = diesel::insert_into(renewal_token::table)
+            .values(self)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        Ok(inserted > 0)
+    }
+
+    pub fn is_spent(conn: &mut PgConnection, unblinded_msg: String) -> anyhow::Result<bool> {
+        Ok(renewal_token::table
+            .filter(renewal_token::unblinded_msg.eq(unblinded_msg))
+            .count()
+            .get_result::<i64>(conn)?
+            > 0)
+    }
+}
@@ -0,0 +1,161 @@
+use crate::models::schema::reserved_name;
+use crate::register::name_skeleton;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use lazy_regex::Regex;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+/// How the pattern of a reserved name is matched against a name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReservationKind {
+    /// The name must equal the pattern
+    Exact = 0,
+    /// The name must start with the pattern
+    Prefix = 1,
+    /// The name must match the pattern as a regex
+    Regex = 2,
+}
+
+impl ReservationKind {
+    pub fn from_i32(kind: i32) -> Option<Self> {
+        match kind {
+            0 => Some(Self::Exact),
+            1 => Some(Self::Prefix),
+            2 => Some(Self::Regex),
+            _ => None,
+        }
+    }
+}
+
+#[derive(
+    QueryableByName, Queryable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq,
+)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = reserved_name)]
+pub struct ReservedName {
+    pub id: i32,
+    pub pattern: String,
+    pub kind: i32,
+    /// The only pubkey allowed to register a matching name, if any
+    pub pubkey: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// The only domain the reservation covers, all of them if none
+    pub domain_id: Option<i32>,
+}
+
+/// A reservation with its regex compiled and its pattern's skeleton found
+/// once, to be matched against many names
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub reserved: ReservedName,
+    regex: Option<Regex>,
+    skeleton: String,
+}
+
+impl From<ReservedName> for Reservation {
+    fn from(reserved: ReservedName) -> Self {
+        let regex = match ReservationKind::from_i32(reserved.kind) {
+            Some(ReservationKind::Regex) => match Regex::new(&reserved.pattern) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    error!("Invalid reserved name regex {}: {e}", reserved.pattern);
+                    None
+                }
+            },
+            _ => None,
+        };
+        let skeleton = name_skeleton(&reserved.pattern);
+
+        Self {
+            reserved,
+            regex,
+            skeleton,
+        }
+    }
+}
+
+impl Reservation {
+    pub fn covers(&self, domain_id: i32) -> bool {
+        self.reserved.domain_id.map_or(true, |id| id == domain_id)
+    }
+
+    /// Lookalikes of a pattern match too, like with [`is_lookalike`](crate::register::is_lookalike)
+    pub fn matches(&self, name: &str) -> bool {
+        let pattern = &self.reserved.pattern;
+        let by_skeleton = !(name.is_ascii() && pattern.is_ascii());
+        match ReservationKind::from_i32(self.reserved.kind) {
+            Some(ReservationKind::Exact) => {
+                name == pattern || (by_skeleton && name_skeleton(name) == self.skeleton)
+            }
+            Some(ReservationKind::Prefix) => {
+                name.starts_with(pattern.as_str())
+                    || (by_skeleton && name_skeleton(name).starts_with(&self.skeleton))
+            }
+            // invalid patterns never match
+            Some(ReservationKind::Regex) => self.regex.as_ref().is_some_and(|re| re.is_match(name)),
+            None => false,
+        }
+    }
+}
+
+impl ReservedName {
+    pub fn get_all(conn: &mut PgConnection) -> anyhow::Result<Vec<ReservedName>> {
+        Ok(reserved_name::table
+            .order(reserved_name::id.asc())
+            .load::<Self>(conn)?)
+    }
+
+    /// Removes a reservation, returns whether one existed
+    pub fn delete(
+        conn: &mut PgConnection,
+        kind: i32,
+        pattern: String,
+        domain_id: Option<i32>,
+    ) -> anyhow::Result<bool> {
+        let deleted = diesel::delete(reserved_name::table)
+            .filter(reserved_name::kind.eq(kind))
+            .filter(reserved_name::pattern.eq(pattern))
+            .filter(reserved_name::domain_id.is_not_distinct_from(domain_id))
+            .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = reserved_name)]
+pub struct NewReservedName {
+    pub pattern: String,
+    pub kind: i32,
+    pub pubkey: Option<String>,
+    pub domain_id: Option<i32>,
+}
+
+impl NewReservedName {
+    /// Inserts the reservation, replacing the pubkey of an existing one
+    pub fn insert(&self, conn: &mut PgConnection) -> anyhow::Result<ReservedName> {
+        conn.transaction(|conn| {
+            // the unique indexes are partial on the domain, which a conflict
+            // target can't name
+            let existing = reserved_name::table
+                .filter(reserved_name::kind.eq(self.kind))
+                .filter(reserved_name::pattern.eq(&self.pattern))
+                .filter(reserved_name::domain_id.is_not_distinct_from(self.domain_id))
+                .for_update()
+                .first::<ReservedName>(conn)
+                .optional()?;
+
+            Ok(match existing {
+                Some(reserved) => diesel::update(reserved_name::table)
+                    .filter(reserved_name::id.eq(reserved.id))
+                    .set(reserved_name::pubkey.eq(&self.pubkey))
+                    .get_result::<ReservedName>(conn)?,
+                None => diesel::insert_into(reserved_name::table)
+                    .values(self)
+                    .get_result::<ReservedName>(conn)?,
+            })
+        })
+    }
+}
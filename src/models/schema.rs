@@ -15,6 +15,41 @@ diesel::table! {
         federation_invite_code -> Varchar,
         invoice_index -> Int4,
         disabled_zaps -> Bool,
+        name_changed_at -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        disabled_zaps_at -> Nullable<Timestamp>,
+        disabled_zaps_until -> Nullable<Timestamp>,
+        #[max_length = 255]
+        disabled_zaps_reason -> Nullable<Varchar>,
+        is_primary -> Bool,
+        #[max_length = 255]
+        name_skeleton -> Nullable<Varchar>,
+        expires_at -> Nullable<Timestamp>,
+        expiry_reminded_at -> Nullable<Timestamp>,
+        domain_id -> Int4,
+        success_action -> Nullable<Text>,
+        #[max_length = 66]
+        linking_key -> Nullable<Varchar>,
+        profile -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    domains (id) {
+        id -> Int4,
+        #[max_length = 255]
+        host -> Varchar,
+        is_default -> Bool,
+        #[max_length = 255]
+        nostr_nsec -> Nullable<Varchar>,
+        #[max_length = 255]
+        lnurl_description -> Nullable<Varchar>,
+        allowed_origins -> Array<Text>,
+        created_at -> Timestamp,
+        owner_user_id -> Nullable<Int4>,
+        #[max_length = 255]
+        owner_name -> Nullable<Varchar>,
+        verified_at -> Nullable<Timestamp>,
     }
 }
 
@@ -33,6 +68,90 @@ diesel::table! {
         bolt11 -> Varchar,
         amount -> Int8,
         state -> Int4,
+        #[max_length = 64]
+        pubkey -> Varchar,
+        comment -> Nullable<Text>,
+        payer_data -> Nullable<Text>,
+        #[max_length = 64]
+        idempotency_key -> Nullable<Varchar>,
+        cancelled_at -> Nullable<Timestamp>,
+        cancel_reason -> Nullable<Text>,
+        notified_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    lnurl_auth_sessions (k1) {
+        #[max_length = 64]
+        k1 -> Varchar,
+        #[max_length = 66]
+        linking_key -> Nullable<Varchar>,
+        #[max_length = 64]
+        token_hash -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        authenticated_at -> Nullable<Timestamp>,
+        token_expires_at -> Nullable<Timestamp>,
+        purpose -> Int4,
+        bound_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    name_redirect (domain_id, name) {
+        #[max_length = 255]
+        name -> Varchar,
+        app_user_id -> Int4,
+        expires_at -> Timestamp,
+        domain_id -> Int4,
+    }
+}
+
+diesel::table! {
+    renewal_token (unblinded_msg) {
+        #[max_length = 255]
+        unblinded_msg -> Varchar,
+        app_user_id -> Int4,
+        expires_at -> Timestamp,
+        renewed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    reserved_name (id) {
+        id -> Int4,
+        #[max_length = 255]
+        pattern -> Varchar,
+        kind -> Int4,
+        #[max_length = 64]
+        pubkey -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        domain_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    signing_key (id) {
+        id -> Int4,
+        #[max_length = 64]
+        key_id -> Varchar,
+        #[max_length = 255]
+        pubkey -> Varchar,
+        service_id -> Int4,
+        plan_id -> Int4,
+        paid -> Bool,
+        valid_from -> Timestamp,
+        valid_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    used_event_ids (event_id) {
+        #[max_length = 64]
+        event_id -> Varchar,
+        #[max_length = 64]
+        pubkey -> Varchar,
+        created_at -> Timestamp,
+        used_at -> Timestamp,
     }
 }
 
@@ -45,11 +164,22 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(app_user -> domains (domain_id));
 diesel::joinable!(invoice -> app_user (app_user_id));
+diesel::joinable!(name_redirect -> app_user (app_user_id));
+diesel::joinable!(name_redirect -> domains (domain_id));
+diesel::joinable!(renewal_token -> app_user (app_user_id));
 diesel::joinable!(zaps -> invoice (id));
 
 diesel::allow_tables_to_appear_in_same_query!(
     app_user,
+    domains,
     invoice,
+    lnurl_auth_sessions,
+    name_redirect,
+    renewal_token,
+    reserved_name,
+    signing_key,
+    used_event_ids,
     zaps,
 );
@@ -0,0 +1,113 @@
+use crate::{models::schema::signing_key, SignerIdentity};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use tbs::{AggregatePublicKey, PubKeyPoint};
+
+/// A blind signing key tokens are issued under, a key accepts tokens for
+/// registration and renewal while it is in its validity window.
+#[derive(
+    QueryableByName, Queryable, AsChangeset, Serialize, Deserialize, Debug, Clone, PartialEq,
+)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(table_name = signing_key)]
+pub struct SigningKey {
+    pub id: i32,
+    pub key_id: String,
+    /// Hex of the compressed aggregate public key
+    pub pubkey: String,
+    pub service_id: i32,
+    pub plan_id: i32,
+    pub paid: bool,
+    pub valid_from: NaiveDateTime,
+    pub valid_until: Option<NaiveDateTime>,
+}
+
+impl SigningKey {
+    pub fn identity(&self) -> SignerIdentity {
+        SignerIdentity {
+            service_id: self.service_id,
+            plan_id: self.plan_id,
+        }
+    }
+
+    pub fn aggregate_pk(&self) -> anyhow::Result<AggregatePublicKey> {
+        parse_aggregate_pk(&self.pubkey)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let now = Utc::now().naive_utc();
+        self.valid_from <= now && self.valid_until.map_or(true, |until| now < until)
+    }
+
+    pub fn get_by_key_id(
+        conn: &mut PgConnection,
+        key_id: String,
+    ) -> anyhow::Result<Option<SigningKey>> {
+        Ok(signing_key::table
+            .filter(signing_key::key_id.eq(key_id))
+            .first::<SigningKey>(conn)
+            .optional()?)
+    }
+
+    /// Keys that haven't been retired yet, including ones only valid in the future
+    pub fn get_unexpired(conn: &mut PgConnection) -> anyhow::Result<Vec<SigningKey>> {
+        let now = Utc::now().naive_utc();
+        Ok(signing_key::table
+            .filter(
+                signing_key::valid_until
+                    .is_null()
+                    .or(signing_key::valid_until.gt(now)),
+            )
+            .order(signing_key::valid_from.asc())
+            .load::<SigningKey>(conn)?)
+    }
+
+    /// Stops the key from accepting tokens after `valid_until`
+    pub fn retire(
+        conn: &mut PgConnection,
+        key_id: String,
+        valid_until: NaiveDateTime,
+    ) -> anyhow::Result<bool> {
+        let updated = diesel::update(signing_key::table)
+            .filter(signing_key::key_id.eq(key_id))
+            .set(signing_key::valid_until.eq(valid_until))
+            .execute(conn)?;
+
+        Ok(updated > 0)
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = signing_key)]
+pub struct NewSigningKey {
+    pub key_id: String,
+    pub pubkey: String,
+    pub service_id: i32,
+    pub plan_id: i32,
+    pub paid: bool,
+    pub valid_from: NaiveDateTime,
+    pub valid_until: Option<NaiveDateTime>,
+}
+
+impl NewSigningKey {
+    pub fn insert(&self, conn: &mut PgConnection) -> anyhow::Result<SigningKey> {
+        Ok(diesel::insert_into(signing_key::table)
+            .values(self)
+            .get_result::<SigningKey>(conn)?)
+    }
+}
+
+pub fn parse_aggregate_pk(pubkey: &str) -> anyhow::Result<AggregatePublicKey> {
+    let point = PubKeyPoint::from_compressed(hex::decode(pubkey)?[..].try_into()?);
+    Option::<PubKeyPoint>::from(point)
+        .map(AggregatePublicKey)
+        .ok_or(anyhow::anyhow!("Invalid aggregate public key"))
+}
+
+/// Short identifier of a signing key clients refer to it by
+pub fn signing_key_id(pk: &AggregatePublicKey) -> String {
+    let hash = sha2::Sha256::digest(pk.0.to_compressed());
+    hex::encode(&hash[..8])
+}
@@ -0,0 +1,32 @@
+use crate::models::schema::used_event_ids;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+/// A signed command that was accepted, so it can't be replayed
+#[derive(Insertable)]
+#[diesel(table_name = used_event_ids)]
+pub struct NewUsedEventId {
+    pub event_id: String,
+    pub pubkey: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl NewUsedEventId {
+    /// Records the event, returns false if it was already used
+    pub fn insert(&self, conn: &mut PgConnection) -> anyhow::Result<bool> {
+        let inserted = diesel::insert_into(used_event_ids::table)
+            .values(self)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        Ok(inserted > 0)
+    }
+
+    /// Forgets events made before the cutoff, they are no longer fresh anyway
+    pub fn prune(conn: &mut PgConnection, cutoff: NaiveDateTime) -> anyhow::Result<usize> {
+        Ok(
+            diesel::delete(used_event_ids::table.filter(used_event_ids::created_at.lt(cutoff)))
+                .execute(conn)?,
+        )
+    }
+}
@@ -4,13 +4,14 @@ use nostr::PublicKey;
 use serde_json::{json, Value};
 use std::{collections::HashMap, str::FromStr};
 
-use crate::State;
+use crate::{models::domain::Domain, register::resolve_user_by_name, State};
 
 pub fn well_known_nip5(
     state: &State,
+    domain: &Domain,
     name: String,
 ) -> Result<HashMap<String, PublicKey>, (StatusCode, Json<Value>)> {
-    let user = state.db.get_user_by_name(name).map_err(|e| {
+    let user = resolve_user_by_name(state, domain, name.clone()).map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({"status": "ERROR", "error": e.to_string()})),
@@ -19,10 +20,8 @@ pub fn well_known_nip5(
 
     let mut names = HashMap::new();
     if let Some(user) = user {
-        names.insert(
-            user.name,
-            PublicKey::from_str(&user.pubkey).expect("valid npub"),
-        );
+        // answer for the requested name, it may be a redirect to the user's new name
+        names.insert(name, PublicKey::from_str(&user.pubkey).expect("valid npub"));
     } else {
         return Err((
             StatusCode::NOT_FOUND,
@@ -35,44 +34,18 @@ pub fn well_known_nip5(
 
 #[cfg(all(test, feature = "integration-tests"))]
 mod tests_integration {
-    use nostr::Keys;
-    use secp256k1::{PublicKey, Secp256k1, XOnlyPublicKey};
-    use std::{str::FromStr, sync::Arc};
+    use secp256k1::{PublicKey, XOnlyPublicKey};
+    use std::str::FromStr;
 
     use crate::{
-        db::setup_db, mint::MockMultiMintWrapperTrait, models::app_user::NewAppUser,
-        nostr::well_known_nip5, register::BlindSigner, State,
+        domains::get_default_domain, models::app_user::NewAppUser, nostr::well_known_nip5,
+        test_state,
     };
 
     #[tokio::test]
     pub async fn well_known_nip5_lookup_test() {
-        dotenv::dotenv().ok();
-        let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = setup_db(pg_url);
-
-        // swap out fm with a mock here since that's not what is being tested
-        let mock_mm = MockMultiMintWrapperTrait::new();
-
-        // nostr
-        let nostr_nsec_str = std::env::var("NSEC").expect("FM_DB_PATH must be set");
-        let nostr_sk = Keys::parse(nostr_nsec_str).expect("Invalid NOSTR_SK");
-        let nostr = nostr_sdk::Client::new(&nostr_sk);
-
-        // create blind signer
-        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-        let paid_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-
-        let mock_mm = Arc::new(mock_mm);
-        let state = State {
-            db: db.clone(),
-            mm: mock_mm,
-            secp: Secp256k1::new(),
-            nostr,
-            free_pk: free_signer.pk,
-            paid_pk: paid_signer.pk,
-            domain: "http://127.0.0.1:8080".to_string(),
-            nostr_sk,
-        };
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
 
         let username = "wellknownuser".to_string();
         let kpk1 = PublicKey::from_str(
@@ -86,12 +59,14 @@ mod tests_integration {
             federation_id: "".to_string(),
             unblinded_msg: "".to_string(),
             federation_invite_code: "".to_string(),
+            expires_at: None,
+            domain_id: domain.id,
         };
 
         // don't care about error if already exists
         let _ = state.db.insert_new_user(user);
 
-        match well_known_nip5(&state, username.clone()) {
+        match well_known_nip5(&state, &domain, username.clone()) {
             Ok(result) => {
                 assert_eq!(result.get(&username).unwrap().to_string(), pk1.to_string());
             }
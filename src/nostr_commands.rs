@@ -0,0 +1,156 @@
+use axum::http::StatusCode;
+use log::{error, info};
+use nostr::{nips::nip04, Event, Filter, Kind, PublicKey, Timestamp};
+use nostr_sdk::RelayPoolNotification;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+use crate::{
+    domains::resolve_domain,
+    register::register,
+    routes::{
+        apply_change_federation, apply_disable_zaps, handle_anyhow_error, ChangeFederationRequest,
+        DisableZapsRequest, RegisterRequest,
+    },
+    signed_command::{check_signed_command, use_signed_command},
+    State,
+};
+
+/// A command sent as an encrypted DM to the service key, for clients that
+/// can't reach the HTTP API. The author of the DM is the pubkey it acts for.
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum NostrCommand {
+    Register {
+        /// Domain to register on, the default one if not set
+        #[serde(default)]
+        host: Option<String>,
+        #[serde(flatten)]
+        request: RegisterRequest,
+    },
+    ChangeFederation(ChangeFederationRequest),
+    DisableZaps(DisableZapsRequest),
+}
+
+/// Listens for commands DMed to the service key and replies to each with
+/// the result, as a DM as well.
+pub(crate) async fn handle_nostr_commands(state: State) {
+    let filter = Filter::new()
+        .kind(Kind::EncryptedDirectMessage)
+        .pubkey(state.nostr_sk.public_key())
+        .since(Timestamp::now());
+    state.nostr.subscribe(vec![filter], None).await;
+
+    let res = state
+        .nostr
+        .handle_notifications(|notification| {
+            let state = state.clone();
+            async move {
+                if let RelayPoolNotification::Event { event, .. } = notification {
+                    // a slow command doesn't hold up the ones after it
+                    if event.kind == Kind::EncryptedDirectMessage {
+                        tokio::spawn(async move { handle_command_event(&state, &event).await });
+                    }
+                }
+                Ok(false)
+            }
+        })
+        .await;
+
+    if let Err(e) = res {
+        error!("Error handling nostr commands: {e}");
+    }
+}
+
+async fn handle_command_event(state: &State, event: &Event) {
+    // stale or badly signed events aren't answered
+    if check_signed_command(event, Kind::EncryptedDirectMessage).is_err() {
+        return;
+    }
+
+    // every relay delivers the command, only the first copy is handled
+    if use_signed_command(state, event).is_err() {
+        return;
+    }
+
+    info!("nostr command {} from {}", event.id, event.author());
+    let reply = match run_command(state, event).await {
+        Ok(result) => json!({"status": "OK", "result": result}),
+        Err((_, e)) => json!({"status": "ERROR", "error": e}),
+    };
+
+    if let Err(e) = state
+        .nostr
+        .send_direct_msg(event.author(), reply.to_string(), Some(event.id))
+        .await
+    {
+        error!("Error replying to nostr command {}: {e}", event.id);
+    }
+}
+
+async fn run_command(state: &State, event: &Event) -> Result<Value, (StatusCode, String)> {
+    let pubkey = event.author();
+    let secret_key = state
+        .nostr_sk
+        .secret_key()
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()))?;
+    let content = nip04::decrypt(secret_key, &pubkey, &event.content)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid message".to_string()))?;
+    let command: NostrCommand = serde_json::from_str(&content)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid command".to_string()))?;
+
+    match command {
+        NostrCommand::Register { host, request } => {
+            // names are only registered for the author of the command
+            if PublicKey::from_str(&request.pubkey).ok() != Some(pubkey) {
+                return Err((StatusCode::UNAUTHORIZED, "Pubkey mismatch".to_string()));
+            }
+
+            let domain = resolve_domain(state, host.as_deref())
+                .map_err(|e| handle_anyhow_error("nostr register", e))?;
+            let res = register(state, &domain, request).await?;
+            Ok(json!(res))
+        }
+        NostrCommand::ChangeFederation(req) => {
+            apply_change_federation(state, pubkey, None, None, req).await?;
+            Ok(Value::Null)
+        }
+        NostrCommand::DisableZaps(req) => {
+            apply_disable_zaps(state, pubkey, None, None, req)?;
+            Ok(Value::Null)
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "integration-tests")))]
+mod tests {
+    use crate::nostr_commands::NostrCommand;
+
+    #[tokio::test]
+    async fn check_parse_commands() {
+        let command: NostrCommand = serde_json::from_str(
+            r#"{"command":"change-federation","name":"alice","invite_code":"fed11"}"#,
+        )
+        .unwrap();
+        match command {
+            NostrCommand::ChangeFederation(req) => {
+                assert_eq!(req.name.as_deref(), Some("alice"));
+                assert_eq!(req.invite_code, "fed11");
+            }
+            _ => panic!("wrong command"),
+        }
+
+        let command: NostrCommand =
+            serde_json::from_str(r#"{"command":"disable-zaps","reason":"vacation"}"#).unwrap();
+        match command {
+            NostrCommand::DisableZaps(req) => {
+                assert!(req.name.is_none());
+                assert_eq!(req.reason.as_deref(), Some("vacation"));
+            }
+            _ => panic!("wrong command"),
+        }
+
+        assert!(serde_json::from_str::<NostrCommand>(r#"{"command":"delete-account"}"#).is_err());
+    }
+}
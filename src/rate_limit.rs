@@ -0,0 +1,382 @@
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Path},
+    http::{HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use log::info;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    register::normalize_name,
+    routes::{LnUrlErrorResponse, LnurlStatus},
+};
+
+/// Limits are counted over windows of this long
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Past this many tracked clients, new ones share one window per route
+/// until finished windows are pruned
+const MAX_TRACKED_WINDOWS: usize = 100_000;
+
+const TOO_MANY_REQUESTS: &str = "Too many requests, try again later.";
+
+/// Requests allowed per minute, 0 turns a limit off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Name and pubkey lookups from one IP
+    pub lookup: u32,
+    /// LNURL pay requests and callbacks from one IP
+    pub pay: u32,
+    /// Callbacks for one name, each of them takes an invoice index
+    pub callback_name: u32,
+    /// Any other route from one IP
+    pub other: u32,
+    /// Proxies in front of the service whose `X-Forwarded-For` entries are
+    /// trusted, 1 behind Fly
+    pub trusted_proxies: usize,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            lookup: 30,
+            pay: 120,
+            callback_name: 60,
+            other: 60,
+            trusted_proxies: 0,
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let defaults = Self::default();
+        let var = |name: &str, default: u32| -> anyhow::Result<u32> {
+            Ok(std::env::var(name)
+                .ok()
+                .map(|v| v.parse::<u32>())
+                .transpose()?
+                .unwrap_or(default))
+        };
+
+        Ok(Self {
+            lookup: var("RATE_LIMIT_LOOKUP", defaults.lookup)?,
+            pay: var("RATE_LIMIT_PAY", defaults.pay)?,
+            callback_name: var("RATE_LIMIT_CALLBACK_NAME", defaults.callback_name)?,
+            other: var("RATE_LIMIT_OTHER", defaults.other)?,
+            trusted_proxies: var("TRUSTED_PROXIES", defaults.trusted_proxies as u32)? as usize,
+        })
+    }
+
+    /// The per IP limit for a route
+    fn for_route(&self, route: &str) -> u32 {
+        if route.starts_with("/v1/check-") {
+            self.lookup
+        } else if is_lnurl_route(route) || route.starts_with("/v1/lnurl/") {
+            self.pay
+        } else {
+            self.other
+        }
+    }
+}
+
+/// What a request is counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    Name(String),
+    Unknown,
+}
+
+impl Client {
+    fn kind(&self) -> &'static str {
+        match self {
+            Client::Ip(_) => "ip",
+            Client::Name(_) => "name",
+            Client::Unknown => "unknown",
+        }
+    }
+}
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// How many requests were turned away, by route and what they were counted
+/// against
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ThrottledCount {
+    pub route: String,
+    pub kind: String,
+    pub count: u64,
+}
+
+/// In memory, fixed window rate limiter. Each instance of the service keeps
+/// its own counts.
+pub struct RateLimiter {
+    limits: RateLimits,
+    windows: Mutex<HashMap<(String, Client), Window>>,
+    throttled: Mutex<HashMap<(String, &'static str), u64>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            windows: Mutex::new(HashMap::new()),
+            throttled: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request, false if it's over the limit
+    fn check(&self, route: &str, client: Client, limit: u32, now: Instant) -> bool {
+        if limit == 0 {
+            return true;
+        }
+
+        let allowed = {
+            let mut windows = self.windows.lock().expect("rate limit lock poisoned");
+            let mut key = (route.to_string(), client.clone());
+            if windows.len() >= MAX_TRACKED_WINDOWS && !windows.contains_key(&key) {
+                key.1 = Client::Unknown;
+            }
+
+            let window = windows.entry(key).or_insert(Window {
+                started: now,
+                count: 0,
+            });
+            if now.duration_since(window.started) >= RATE_LIMIT_WINDOW {
+                window.started = now;
+                window.count = 0;
+            }
+            window.count += 1;
+            window.count <= limit
+        };
+
+        if !allowed {
+            info!("rate limited {route} for {client:?}");
+            *self
+                .throttled
+                .lock()
+                .expect("rate limit lock poisoned")
+                .entry((route.to_string(), client.kind()))
+                .or_default() += 1;
+        }
+
+        allowed
+    }
+
+    /// Drops the windows that are over, returns how many
+    fn prune(&self, now: Instant) -> usize {
+        let mut windows = self.windows.lock().expect("rate limit lock poisoned");
+        let before = windows.len();
+        windows.retain(|_, w| now.duration_since(w.started) < RATE_LIMIT_WINDOW);
+        before - windows.len()
+    }
+
+    pub fn throttled(&self) -> Vec<ThrottledCount> {
+        let mut counts: Vec<ThrottledCount> = self
+            .throttled
+            .lock()
+            .expect("rate limit lock poisoned")
+            .iter()
+            .map(|((route, kind), count)| ThrottledCount {
+                route: route.clone(),
+                kind: kind.to_string(),
+                count: *count,
+            })
+            .collect();
+        counts.sort_by(|a, b| b.count.cmp(&a.count));
+        counts
+    }
+}
+
+fn is_lnurl_route(route: &str) -> bool {
+    route.starts_with("/.well-known/lnurlp/") || route.starts_with("/lnurlp/")
+}
+
+/// The client's IP, from the `X-Forwarded-For` entry the closest trusted
+/// proxy added when there are any
+fn client_ip(headers: &HeaderMap, peer: Option<IpAddr>, trusted_proxies: usize) -> Option<IpAddr> {
+    if trusted_proxies == 0 {
+        return peer;
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(str::trim)
+        .collect();
+
+    forwarded
+        .iter()
+        .rev()
+        .nth(trusted_proxies - 1)
+        .and_then(|ip| ip.parse().ok())
+        .or(peer)
+}
+
+/// Drops finished windows every window, so clients that went away aren't
+/// kept around
+pub(crate) async fn prune_rate_limits(limiter: Arc<RateLimiter>) {
+    loop {
+        tokio::time::sleep(RATE_LIMIT_WINDOW).await;
+
+        let pruned = limiter.prune(Instant::now());
+        if pruned > 0 {
+            info!("Pruned {pruned} rate limit windows");
+        }
+    }
+}
+
+pub(crate) async fn rate_limit<B>(
+    Extension(limiter): Extension<Arc<RateLimiter>>,
+    route: Option<MatchedPath>,
+    params: Option<Path<HashMap<String, String>>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let route = match route {
+        Some(route) => route.as_str().to_string(),
+        None => return next.run(req).await,
+    };
+    let now = Instant::now();
+
+    let ip = client_ip(
+        req.headers(),
+        peer.map(|ConnectInfo(addr)| addr.ip()),
+        limiter.limits.trusted_proxies,
+    );
+    let client = ip.map_or(Client::Unknown, Client::Ip);
+    let mut allowed = limiter.check(&route, client, limiter.limits.for_route(&route), now);
+
+    if allowed && route.ends_with("/callback") {
+        // counted the way the name is looked up
+        if let Some(name) = params.as_ref().and_then(|Path(p)| p.get("username")) {
+            allowed = limiter.check(
+                &route,
+                Client::Name(normalize_name(name)),
+                limiter.limits.callback_name,
+                now,
+            );
+        }
+    }
+
+    if allowed {
+        return next.run(req).await;
+    }
+
+    if is_lnurl_route(&route) {
+        LnUrlErrorResponse {
+            status: LnurlStatus::Error,
+            reason: TOO_MANY_REQUESTS.to_string(),
+        }
+        .into_response()
+    } else {
+        (StatusCode::TOO_MANY_REQUESTS, TOO_MANY_REQUESTS.to_string()).into_response()
+    }
+}
+
+#[cfg(all(test, not(feature = "integration-tests")))]
+mod tests {
+    use axum::http::HeaderMap;
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    use crate::rate_limit::{
+        client_ip, Client, RateLimiter, RateLimits, MAX_TRACKED_WINDOWS, RATE_LIMIT_WINDOW,
+    };
+
+    #[tokio::test]
+    async fn check_rate_limit() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        let now = Instant::now();
+        let ip = Client::Ip("1.2.3.4".parse().unwrap());
+        let route = "/v1/check-username/:username";
+
+        for _ in 0..3 {
+            assert!(limiter.check(route, ip.clone(), 3, now));
+        }
+        assert!(!limiter.check(route, ip.clone(), 3, now));
+
+        // counted per client and route
+        assert!(limiter.check(route, Client::Ip("5.6.7.8".parse().unwrap()), 3, now));
+        assert!(limiter.check("/v1/check-registration", ip.clone(), 3, now));
+
+        // until the window is over
+        let later = now + RATE_LIMIT_WINDOW + Duration::from_secs(1);
+        assert!(limiter.check(route, ip.clone(), 3, later));
+
+        // 0 is no limit
+        for _ in 0..10 {
+            assert!(limiter.check("/v1/register", ip.clone(), 0, now));
+        }
+
+        let throttled = limiter.throttled();
+        assert_eq!(throttled.len(), 1);
+        assert_eq!(throttled[0].route, route);
+        assert_eq!(throttled[0].kind, "ip");
+        assert_eq!(throttled[0].count, 1);
+    }
+
+    #[tokio::test]
+    async fn check_client_ip() {
+        let peer: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "6.6.6.6, 1.2.3.4".parse().unwrap());
+
+        // forwarded addresses are only used behind a trusted proxy
+        assert_eq!(client_ip(&headers, Some(peer), 0), Some(peer));
+        // the proxy's entry is the last one, the ones before can be spoofed
+        assert_eq!(
+            client_ip(&headers, Some(peer), 1),
+            Some("1.2.3.4".parse().unwrap())
+        );
+        assert_eq!(
+            client_ip(&headers, Some(peer), 2),
+            Some("6.6.6.6".parse().unwrap())
+        );
+        assert_eq!(client_ip(&HeaderMap::new(), Some(peer), 1), Some(peer));
+    }
+
+    #[tokio::test]
+    async fn check_tracked_windows_capped() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        let now = Instant::now();
+        let route = "/lnurlp/:username/callback";
+
+        for i in 0..MAX_TRACKED_WINDOWS {
+            assert!(limiter.check(route, Client::Name(i.to_string()), 1, now));
+        }
+
+        // new clients past the cap share a window
+        assert!(limiter.check(route, Client::Name("new1".to_string()), 1, now));
+        assert!(!limiter.check(route, Client::Name("new2".to_string()), 1, now));
+        // tracked ones keep their own
+        assert!(!limiter.check(route, Client::Name("0".to_string()), 1, now));
+        assert_eq!(
+            limiter.windows.lock().unwrap().len(),
+            MAX_TRACKED_WINDOWS + 1
+        );
+
+        // finished windows are dropped
+        assert_eq!(limiter.prune(now), 0);
+        let later = now + RATE_LIMIT_WINDOW;
+        assert_eq!(limiter.prune(later), MAX_TRACKED_WINDOWS + 1);
+        assert!(limiter.check(route, Client::Name("new2".to_string()), 1, later));
+    }
+}
@@ -1,34 +1,267 @@
-use std::str::FromStr;
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use crate::{
-    models::app_user::{AppUser, NewAppUser},
-    routes::{RegisterRequest, RegisterResponse},
-    State,
+    domains::{find_domain, serves_user},
+    invoice::stop_cancelled_invoices,
+    models::{
+        app_user::{AppUser, NewAppUser},
+        domain::Domain,
+        reserved_name::{NewReservedName, Reservation, ReservationKind, ReservedName},
+        signing_key::{parse_aggregate_pk, signing_key_id, NewSigningKey, SigningKey},
+    },
+    routes::{
+        AvatarMime, RegisterRequest, RegisterResponse, RenewRequest, RenewResponse,
+        SuccessActionConfig, UserProfile,
+    },
+    SignerIdentity, State,
 };
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use fedimint_core::api::InviteCode;
 use lazy_regex::*;
 use log::error;
 use names::Generator;
 use nostr::PublicKey;
 use reqwest::StatusCode;
+use tbs::AggregatePublicKey;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{GeneralSecurityProfile, MixedScript};
 
 pub static ALPHANUMERIC_REGEX: Lazy<Regex> = lazy_regex!("^[a-z0-9-_.]+$");
 
+/// How long a user has to wait between username changes
+const USERNAME_CHANGE_COOLDOWN_DAYS: i64 = 30;
+
+/// How long an old username keeps pointing at the renamed user
+const USERNAME_REDIRECT_DAYS: i64 = 90;
+
+/// How long a paid token keeps a name registered, on registration or renewal
+pub const PAID_NAME_TERM_DAYS: i64 = 365;
+
+/// How long the compiled reservations are used before they are loaded again,
+/// for changes made through other instances
+const RESERVATIONS_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The operator's reservations, compiled once and shared by every name check
+#[derive(Clone, Default)]
+pub struct ReservationCache {
+    cached: Arc<Mutex<Option<(Instant, Arc<Vec<Reservation>>)>>>,
+}
+
+impl ReservationCache {
+    fn get(&self) -> Option<Arc<Vec<Reservation>>> {
+        let cached = self.cached.lock().expect("reservations lock poisoned");
+        cached
+            .as_ref()
+            .filter(|(loaded_at, _)| loaded_at.elapsed() < RESERVATIONS_CACHE_TTL)
+            .map(|(_, reservations)| reservations.clone())
+    }
+
+    fn set(&self, reservations: Arc<Vec<Reservation>>) {
+        *self.cached.lock().expect("reservations lock poisoned") =
+            Some((Instant::now(), reservations));
+    }
+
+    /// Drops the cached reservations, for after they changed
+    pub fn invalidate(&self) {
+        *self.cached.lock().expect("reservations lock poisoned") = None;
+    }
+}
+
+/// Expects a name that went through [`normalize_name`]
 pub fn is_valid_name(name: &str) -> bool {
-    let name_len = name.len();
+    let name_len = name.chars().count();
     if !(2..=30).contains(&name_len) {
         return false;
     }
 
-    ALPHANUMERIC_REGEX.is_match(name)
+    if name.is_ascii() {
+        return ALPHANUMERIC_REGEX.is_match(name);
+    }
+
+    // unicode names must be normalized, only use characters allowed in
+    // identifiers, stay within a single script and have an ASCII form
+    name == normalize_name(name)
+        && name.chars().all(|c| {
+            if c.is_ascii() {
+                c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.".contains(c)
+            } else {
+                c.is_alphanumeric() && c.identifier_allowed()
+            }
+        })
+        && name.is_single_script()
+        && idna::domain_to_ascii(name).is_ok()
+}
+
+/// Brings a name into the form it is stored in: punycode decoded, NFKC
+/// normalized and case folded.
+pub fn normalize_name(name: &str) -> String {
+    let name = if name.split('.').any(|label| label.starts_with("xn--")) {
+        idna::domain_to_unicode(name).0
+    } else {
+        name.to_string()
+    };
+
+    // full case folding, unlike lowercasing, also folds `ß` into `ss` and a
+    // final `ς` into `σ`
+    let name: String = name.nfkc().collect();
+    caseless::default_case_fold_str(&name).nfkc().collect()
+}
+
+/// The UTS 39 skeleton of a name, names that look alike share a skeleton
+pub fn name_skeleton(name: &str) -> String {
+    unicode_security::skeleton(&normalize_name(name)).collect()
+}
+
+/// Whether a name passes for another: the same, or sharing a skeleton when
+/// either of them goes outside of ASCII. Between plain ASCII names skeletons
+/// are too eager, they would make `modern` pass for `modem` and `1` for `l`.
+pub fn is_lookalike(name: &str, other: &str) -> bool {
+    name == other
+        || (!(name.is_ascii() && other.is_ascii()) && name_skeleton(name) == name_skeleton(other))
 }
 
-pub fn check_available(state: &State, name: String) -> anyhow::Result<bool> {
+/// The ASCII form of a name for places like LUD-16 identifiers that don't
+/// allow unicode, punycode encoded when needed.
+pub fn ascii_name(name: &str) -> String {
+    if name.is_ascii() {
+        return name.to_string();
+    }
+
+    idna::domain_to_ascii(name).unwrap_or_else(|_| name.to_string())
+}
+
+pub fn check_available(state: &State, domain: &Domain, name: String) -> anyhow::Result<bool> {
+    // a user's own domain only serves the name they claimed
+    if domain.owner_user_id.is_some() {
+        return Ok(false);
+    }
+
+    let name = normalize_name(&name);
     if !is_valid_name(&name) {
         return Ok(false);
     }
 
-    state.db.check_name_available(name)
+    if reservations(state)?
+        .iter()
+        .any(|r| r.covers(domain.id) && r.matches(&name))
+    {
+        return Ok(false);
+    }
+
+    state.db.check_name_available(domain.id, name)
+}
+
+/// The operator's reservations, from the cache while it's fresh
+fn reservations(state: &State) -> anyhow::Result<Arc<Vec<Reservation>>> {
+    if let Some(reservations) = state.reservations.get() {
+        return Ok(reservations);
+    }
+
+    let reservations: Arc<Vec<Reservation>> = Arc::new(
+        state
+            .db
+            .get_reserved_names()?
+            .into_iter()
+            .map(Reservation::from)
+            .collect(),
+    );
+    state.reservations.set(reservations.clone());
+
+    Ok(reservations)
+}
+
+/// Whether a name on a domain is reserved for anyone other than the given
+/// pubkey. A reservation for the pubkey wins over broader ones covering the
+/// same name.
+fn reserved_for_other(
+    reservations: &[Reservation],
+    domain_id: i32,
+    name: &str,
+    pubkey: &str,
+) -> bool {
+    let mut matching = reservations
+        .iter()
+        .filter(|r| r.covers(domain_id) && r.matches(name))
+        .peekable();
+    matching.peek().is_some() && !matching.any(|r| r.reserved.pubkey.as_deref() == Some(pubkey))
+}
+
+fn is_reserved_for_other(
+    state: &State,
+    domain_id: i32,
+    name: &str,
+    pubkey: &str,
+) -> anyhow::Result<bool> {
+    Ok(reserved_for_other(
+        &reservations(state)?,
+        domain_id,
+        name,
+        pubkey,
+    ))
+}
+
+/// The id of the domain a reservation is limited to, none covers them all
+fn reservation_domain_id(state: &State, host: Option<String>) -> anyhow::Result<Option<i32>> {
+    host.map(|host| find_domain(state, &host).map(|d| d.id))
+        .transpose()
+}
+
+pub fn reserve_name(
+    state: &State,
+    pattern: String,
+    kind: ReservationKind,
+    pubkey: Option<String>,
+    domain: Option<String>,
+) -> anyhow::Result<ReservedName> {
+    if pattern.is_empty() || pattern.len() > 255 {
+        return Err(anyhow!("Invalid pattern"));
+    }
+    let pattern = match kind {
+        ReservationKind::Regex => pattern,
+        _ => normalize_name(&pattern),
+    };
+    if kind == ReservationKind::Regex && Regex::new(&pattern).is_err() {
+        return Err(anyhow!("Invalid regex"));
+    }
+    if let Some(ref pk) = pubkey {
+        PublicKey::from_str(pk).map_err(|_| anyhow!("Nostr Pubkey Invalid"))?;
+    }
+
+    let domain_id = reservation_domain_id(state, domain)?;
+
+    let reserved = state.db.insert_reserved_name(NewReservedName {
+        pattern,
+        kind: kind as i32,
+        pubkey,
+        domain_id,
+    })?;
+    state.reservations.invalidate();
+
+    Ok(reserved)
+}
+
+pub fn unreserve_name(
+    state: &State,
+    pattern: String,
+    kind: ReservationKind,
+    domain: Option<String>,
+) -> anyhow::Result<bool> {
+    let pattern = match kind {
+        ReservationKind::Regex => pattern,
+        _ => normalize_name(&pattern),
+    };
+    let domain_id = reservation_domain_id(state, domain)?;
+    let deleted = state
+        .db
+        .delete_reserved_name(kind as i32, pattern, domain_id)?;
+    state.reservations.invalidate();
+
+    Ok(deleted)
 }
 
 pub fn check_registered_pubkey(state: &State, pubkey: String) -> anyhow::Result<Option<String>> {
@@ -39,6 +272,100 @@ pub fn get_user_by_pubkey(state: &State, pubkey: String) -> anyhow::Result<Optio
     state.db.get_user_by_pubkey(pubkey)
 }
 
+pub fn get_users_by_pubkey(state: &State, pubkey: String) -> anyhow::Result<Vec<AppUser>> {
+    state.db.get_users_by_pubkey(pubkey)
+}
+
+/// The addresses of a pubkey a management request applies to: only the
+/// one named by `target` if given, otherwise all of them. Requests from an
+/// LNURL-auth session are limited to the `user_ids` its linking key is
+/// bound to, and requests made to a `domain` to the addresses it serves.
+pub fn get_target_users(
+    state: &State,
+    pubkey: String,
+    target: Option<String>,
+    user_ids: Option<&[i32]>,
+    domain: Option<&Domain>,
+) -> anyhow::Result<Vec<AppUser>> {
+    let users = state
+        .db
+        .get_users_by_pubkey(pubkey)?
+        .into_iter()
+        .filter(|u| user_ids.map_or(true, |ids| ids.contains(&u.id)))
+        .filter(|u| domain.map_or(true, |d| serves_user(d, u)));
+    match target {
+        Some(name) => {
+            let name = normalize_name(&name);
+            Ok(users.filter(|u| u.name == name).collect())
+        }
+        None => Ok(users.collect()),
+    }
+}
+
+pub fn set_primary_user(state: &State, user: AppUser) -> anyhow::Result<()> {
+    state.db.set_primary_user(user)
+}
+
+/// Looks up a user of the domain by their current name, falling back to
+/// a name they recently changed away from. On a user's own domain the
+/// claimed name resolves to the address backing it.
+pub fn resolve_user_by_name(
+    state: &State,
+    domain: &Domain,
+    name: String,
+) -> anyhow::Result<Option<AppUser>> {
+    let name = normalize_name(&name);
+    if let Some(owner_user_id) = domain.owner_user_id {
+        if domain.owner_name.as_ref() != Some(&name) {
+            return Ok(None);
+        }
+        let owner = state.db.get_user_by_id(owner_user_id)?;
+        return Ok(owner.filter(|u| u.deleted_at.is_none()));
+    }
+
+    match state.db.get_user_by_name(domain.id, name.clone())? {
+        Some(user) => Ok(Some(user)),
+        None => state.db.get_user_by_redirect(domain.id, name),
+    }
+}
+
+/// The name a user goes by on a domain
+pub fn address_name(domain: &Domain, user: &AppUser) -> String {
+    match domain.owner_name {
+        Some(ref name) => name.clone(),
+        None => user.name.clone(),
+    }
+}
+
+pub fn change_username(state: &State, user: AppUser, new_name: String) -> anyhow::Result<()> {
+    let new_name = normalize_name(&new_name);
+    if !is_valid_name(&new_name) || new_name == user.name {
+        return Err(anyhow!("Unavailable"));
+    }
+
+    if is_reserved_for_other(state, user.domain_id, &new_name, &user.pubkey)? {
+        return Err(anyhow!("Unavailable"));
+    }
+
+    // choosing a name is what a paid token buys, free and expired names
+    // only ever get random ones
+    let now = Utc::now().naive_utc();
+    if !user.expires_at.is_some_and(|expires_at| expires_at > now) {
+        return Err(anyhow!("Only paid names can be changed"));
+    }
+
+    if user
+        .name_changed_at
+        .is_some_and(|changed_at| changed_at + Duration::days(USERNAME_CHANGE_COOLDOWN_DAYS) > now)
+    {
+        return Err(anyhow!("Username was changed too recently"));
+    }
+
+    state
+        .db
+        .change_user_name(user, new_name, now + Duration::days(USERNAME_REDIRECT_DAYS))
+}
+
 pub fn change_user_federation(
     state: &State,
     user: AppUser,
@@ -50,18 +377,164 @@ pub fn change_user_federation(
         .update_user_federation(user, federation_id, federation_invite_code)
 }
 
-pub fn disable_user_zaps(state: &State, user: AppUser) -> anyhow::Result<()> {
-    state.db.disable_user_zaps(user)
+pub fn disable_user_zaps(
+    state: &State,
+    user: AppUser,
+    reason: Option<String>,
+    until: Option<u64>,
+) -> anyhow::Result<()> {
+    if reason.as_ref().is_some_and(|r| r.len() > 255) {
+        return Err(anyhow!("Reason too long"));
+    }
+
+    let until = match until {
+        Some(until) => {
+            let until = DateTime::from_timestamp(until as i64, 0)
+                .ok_or(anyhow!("Invalid until timestamp"))?
+                .naive_utc();
+            if until <= Utc::now().naive_utc() {
+                return Err(anyhow!("Until timestamp must be in the future"));
+            }
+            Some(until)
+        }
+        None => None,
+    };
+
+    state.db.disable_user_zaps(user, reason, until)
+}
+
+pub fn enable_user_zaps(state: &State, user: AppUser) -> anyhow::Result<()> {
+    state.db.enable_user_zaps(user)
+}
+
+/// Sets or clears the success action returned with the user's invoices
+pub fn set_user_success_action(
+    state: &State,
+    user: AppUser,
+    success_action: Option<SuccessActionConfig>,
+) -> anyhow::Result<()> {
+    if let Some(ref action) = success_action {
+        validate_success_action(action)?;
+    }
+    let success_action = success_action
+        .map(|a| serde_json::to_string(&a))
+        .transpose()?;
+
+    state.db.set_user_success_action(user, success_action)
+}
+
+/// Limits from LUD-09 and LUD-10
+fn validate_success_action(action: &SuccessActionConfig) -> anyhow::Result<()> {
+    let (text, max_len) = match action {
+        SuccessActionConfig::Message { message } => (message, 144),
+        SuccessActionConfig::Url { description, url } => {
+            if !matches!(url.scheme(), "https" | "http") {
+                return Err(anyhow!("Invalid success action url"));
+            }
+            (description, 144)
+        }
+        SuccessActionConfig::Aes {
+            description,
+            plaintext,
+        } => {
+            if plaintext.len() > 4096 {
+                return Err(anyhow!("Success action plaintext too long"));
+            }
+            (description, 144)
+        }
+    };
+
+    if text.chars().count() > max_len {
+        return Err(anyhow!("Success action text too long"));
+    }
+
+    Ok(())
 }
 
-pub fn generate_random_name(state: &State) -> anyhow::Result<String> {
+/// Longest a profile's `text/plain` description can be, same as a domain's
+const MAX_PROFILE_DESCRIPTION_LEN: usize = 255;
+const MAX_PROFILE_LONG_DESCRIPTION_LEN: usize = 2_048;
+/// Largest avatar, decoded. The metadata is in every pay request.
+const MAX_AVATAR_BYTES: usize = 64 * 1_024;
+
+/// Sets or clears the profile shown in the user's LNURL metadata
+pub fn set_user_profile(
+    state: &State,
+    user: AppUser,
+    profile: Option<UserProfile>,
+) -> anyhow::Result<()> {
+    if let Some(ref profile) = profile {
+        validate_profile(profile)?;
+    }
+    let profile = profile.map(|p| serde_json::to_string(&p)).transpose()?;
+
+    state.db.set_user_profile(user, profile)
+}
+
+fn validate_profile(profile: &UserProfile) -> anyhow::Result<()> {
+    if let Some(ref description) = profile.description {
+        if description.chars().count() > MAX_PROFILE_DESCRIPTION_LEN {
+            return Err(anyhow!("Description too long"));
+        }
+        // a private profile doesn't name the user anywhere
+        if profile.private && description.contains("{name}") {
+            return Err(anyhow!("Private description can't contain the name"));
+        }
+    }
+    if profile
+        .long_description
+        .as_ref()
+        .is_some_and(|d| d.chars().count() > MAX_PROFILE_LONG_DESCRIPTION_LEN)
+    {
+        return Err(anyhow!("Long description too long"));
+    }
+
+    if let Some(ref avatar) = profile.avatar {
+        let image = base64::decode(&avatar.data).map_err(|_| anyhow!("Invalid avatar"))?;
+        if image.len() > MAX_AVATAR_BYTES {
+            return Err(anyhow!("Avatar too large"));
+        }
+        let magic: &[u8] = match avatar.mime {
+            AvatarMime::Png => b"\x89PNG\r\n\x1a\n",
+            AvatarMime::Jpeg => b"\xff\xd8\xff",
+        };
+        if !image.starts_with(magic) {
+            return Err(anyhow!("Avatar doesn't match its type"));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn delete_user(state: &State, user: AppUser) -> anyhow::Result<()> {
+    let cancelled = state.db.delete_user(user)?;
+    stop_cancelled_invoices(state, &cancelled);
+    Ok(())
+}
+
+pub fn rotate_user_pubkey(
+    state: &State,
+    old_pubkey: PublicKey,
+    new_pubkey: PublicKey,
+) -> anyhow::Result<()> {
+    if old_pubkey == new_pubkey {
+        return Err(anyhow!("New pubkey must be different"));
+    }
+
+    state
+        .db
+        .rotate_user_pubkey(old_pubkey.to_string(), new_pubkey.to_string())?;
+    Ok(())
+}
+
+pub fn generate_random_name(state: &State, domain: &Domain) -> anyhow::Result<String> {
     loop {
         let new_name = Generator::with_naming(names::Name::Numbered)
             .next()
             .expect("should generate name")
             .replace('-', "");
 
-        if check_available(state, new_name.clone())? {
+        if check_available(state, domain, new_name.clone())? {
             return Ok(new_name);
         }
     }
@@ -69,22 +542,24 @@ pub fn generate_random_name(state: &State) -> anyhow::Result<String> {
 
 pub async fn register(
     state: &State,
+    domain: &Domain,
     req: RegisterRequest,
 ) -> Result<RegisterResponse, (StatusCode, String)> {
+    if domain.owner_user_id.is_some() {
+        return Err((StatusCode::BAD_REQUEST, "Unavailable".to_string()));
+    }
+
     // validate user name & pubkey first
-    let requested_paid = req.name.is_some();
-    if requested_paid && !is_valid_name(&req.name.clone().unwrap()) {
+    let requested_name = req.name.as_deref().map(normalize_name);
+    let requested_paid = requested_name.is_some();
+    if requested_paid && !is_valid_name(requested_name.as_ref().unwrap()) {
         return Err((StatusCode::BAD_REQUEST, "Unavailable".to_string()));
     }
     PublicKey::from_str(&req.pubkey)
         .map_err(|_| (StatusCode::BAD_REQUEST, "Nostr Pubkey Invalid".to_string()))?;
 
     // a different signer based on paid vs free
-    let signer = if requested_paid {
-        state.paid_pk
-    } else {
-        state.free_pk
-    };
+    let signer = signer_for(state, req.key_id.clone(), requested_paid)?;
 
     // verify token and double check that it has not been spent before
     if !req.verify(signer) {
@@ -93,10 +568,28 @@ pub async fn register(
 
     let user_msg_hex = serde_json::to_string(&req.msg)
         .map_err(|_| (StatusCode::BAD_REQUEST, "Nostr blinded message".to_string()))?;
+    match state.db.is_renewal_token_spent(user_msg_hex.clone()) {
+        Ok(false) => (),
+        Ok(true) => {
+            return Err((StatusCode::BAD_REQUEST, "Token already spent".to_string()));
+        }
+        Err(e) => {
+            error!("Error in register: {e:?}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()));
+        }
+    }
     match state.db.get_user_by_token(user_msg_hex.clone()) {
         Ok(Some(u)) => {
+            // tokens of deleted users can't be used again
+            if u.deleted_at.is_some() {
+                return Err((StatusCode::BAD_REQUEST, "Token already spent".to_string()));
+            }
+
             // if token has already been spent, just return the registered user info
-            return Ok(RegisterResponse { name: u.name });
+            return Ok(RegisterResponse {
+                name: u.name,
+                expires_at: u.expires_at.map(|t| t.and_utc().timestamp() as u64),
+            });
         }
         Ok(None) => (),
         Err(e) => {
@@ -106,9 +599,23 @@ pub async fn register(
     }
 
     let name_to_register = if requested_paid {
-        req.name.clone().unwrap().clone()
+        let name = requested_name.unwrap();
+
+        // reserved names can only be registered by the pubkey they are reserved for
+        match is_reserved_for_other(state, domain.id, &name, &req.pubkey) {
+            Ok(false) => (),
+            Ok(true) => {
+                return Err((StatusCode::BAD_REQUEST, "Unavailable".to_string()));
+            }
+            Err(e) => {
+                error!("Error in register: {e:?}");
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()));
+            }
+        }
+
+        name
     } else {
-        match generate_random_name(state) {
+        match generate_random_name(state, domain) {
             Ok(s) => s,
             Err(e) => {
                 error!("Error in register name generator: {e:?}");
@@ -117,7 +624,10 @@ pub async fn register(
         }
     };
 
-    match state.db.check_name_available(name_to_register.clone()) {
+    match state
+        .db
+        .check_name_available(domain.id, name_to_register.clone())
+    {
         Ok(true) => (),
         Ok(false) => {
             return Err((StatusCode::BAD_REQUEST, "Unavailable".to_string()));
@@ -139,16 +649,23 @@ pub async fn register(
     let federation_id = invite_code.federation_id();
     ensure_added_federation(state, federation_id, invite_code).await?;
 
+    // paid names have to be renewed, free ones don't
+    let expires_at =
+        requested_paid.then(|| Utc::now().naive_utc() + Duration::days(PAID_NAME_TERM_DAYS));
+
     let new_user = NewAppUser {
         pubkey: req.pubkey,
         name: name_to_register.clone(),
         federation_id: federation_id.to_string(),
         unblinded_msg: user_msg_hex,
         federation_invite_code: req.federation_invite_code,
+        expires_at,
+        domain_id: domain.id,
     };
     match state.db.insert_new_user(new_user) {
         Ok(_) => Ok(RegisterResponse {
             name: name_to_register,
+            expires_at: expires_at.map(|t| t.and_utc().timestamp() as u64),
         }),
         Err(e) => {
             error!("Errorgister: {e:?}");
@@ -157,6 +674,152 @@ pub async fn register(
     }
 }
 
+/// The key a token was signed with: the registered key it names, or the
+/// default free or paid key for tokens that don't name one.
+fn signer_for(
+    state: &State,
+    key_id: Option<String>,
+    paid: bool,
+) -> Result<AggregatePublicKey, (StatusCode, String)> {
+    let Some(key_id) = key_id else {
+        return Ok(if paid { state.paid_pk } else { state.free_pk });
+    };
+
+    let key = match state.db.get_signing_key(key_id) {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            return Err((StatusCode::BAD_REQUEST, "Unknown signing key".to_string()));
+        }
+        Err(e) => {
+            error!("Error getting signing key: {e:?}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()));
+        }
+    };
+
+    if !key.is_valid() || key.paid != paid {
+        return Err((StatusCode::BAD_REQUEST, "Invalid signing key".to_string()));
+    }
+
+    key.aggregate_pk().map_err(|e| {
+        error!("Error parsing signing key {}: {e:?}", key.key_id);
+        (StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string())
+    })
+}
+
+fn timestamp_to_naive(timestamp: u64) -> anyhow::Result<NaiveDateTime> {
+    Ok(DateTime::from_timestamp(timestamp as i64, 0)
+        .ok_or(anyhow!("Invalid timestamp"))?
+        .naive_utc())
+}
+
+/// Signing keys clients can currently get tokens for
+pub fn get_signing_keys(state: &State) -> anyhow::Result<Vec<SigningKey>> {
+    state.db.get_unexpired_signing_keys()
+}
+
+pub fn add_signing_key(
+    state: &State,
+    pubkey: String,
+    identity: SignerIdentity,
+    paid: bool,
+    valid_from: Option<u64>,
+    valid_until: Option<u64>,
+) -> anyhow::Result<SigningKey> {
+    let pk = parse_aggregate_pk(&pubkey)?;
+    let valid_from = match valid_from {
+        Some(t) => timestamp_to_naive(t)?,
+        None => Utc::now().naive_utc(),
+    };
+    let valid_until = valid_until.map(timestamp_to_naive).transpose()?;
+    if valid_until.is_some_and(|until| until <= valid_from) {
+        return Err(anyhow!("Key must be valid for some time"));
+    }
+
+    state.db.insert_signing_key(NewSigningKey {
+        key_id: signing_key_id(&pk),
+        pubkey,
+        service_id: identity.service_id,
+        plan_id: identity.plan_id,
+        paid,
+        valid_from,
+        valid_until,
+    })
+}
+
+/// Stops a key from accepting tokens at `valid_until`, or right away. Retire
+/// keys late enough for the tokens already issued under them to be spent.
+pub fn retire_signing_key(
+    state: &State,
+    key_id: String,
+    valid_until: Option<u64>,
+) -> anyhow::Result<bool> {
+    let valid_until = match valid_until {
+        Some(t) => timestamp_to_naive(t)?,
+        None => Utc::now().naive_utc(),
+    };
+
+    state.db.retire_signing_key(key_id, valid_until)
+}
+
+/// Extends a paid name by another term with a new paid token
+pub fn renew(
+    state: &State,
+    domain: &Domain,
+    req: RenewRequest,
+) -> Result<RenewResponse, (StatusCode, String)> {
+    let signer = signer_for(state, req.key_id.clone(), true)?;
+    if !req.verify(signer) {
+        return Err((StatusCode::UNAUTHORIZED, "Invalid blind sig".to_string()));
+    }
+
+    let user_msg_hex = serde_json::to_string(&req.msg)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Nostr blinded message".to_string()))?;
+
+    // tokens spent on a registration can't be used again
+    match state.db.get_user_by_token(user_msg_hex.clone()) {
+        Ok(None) => (),
+        Ok(Some(_)) => {
+            return Err((StatusCode::BAD_REQUEST, "Token already spent".to_string()));
+        }
+        Err(e) => {
+            error!("Error in renew: {e:?}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()));
+        }
+    }
+
+    let user = match state
+        .db
+        .get_user_by_name(domain.id, normalize_name(&req.name))
+    {
+        Ok(Some(u)) => u,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Err(e) => {
+            error!("Error in renew: {e:?}");
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()));
+        }
+    };
+
+    // only paid names expire, renewing from now if it already expired
+    let Some(current_expiry) = user.expires_at else {
+        return Err((StatusCode::BAD_REQUEST, "Name does not expire".to_string()));
+    };
+    let expires_at =
+        current_expiry.max(Utc::now().naive_utc()) + Duration::days(PAID_NAME_TERM_DAYS);
+
+    let name = user.name.clone();
+    match state.db.renew_user(user, user_msg_hex, expires_at) {
+        Ok(true) => Ok(RenewResponse {
+            name,
+            expires_at: expires_at.and_utc().timestamp() as u64,
+        }),
+        Ok(false) => Err((StatusCode::BAD_REQUEST, "Token already spent".to_string())),
+        Err(e) => {
+            error!("Error in renew: {e:?}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "ServerError".to_string()))
+        }
+    }
+}
+
 pub(crate) async fn ensure_added_federation(
     state: &State,
     federation_id: fedimint_core::config::FederationId,
@@ -176,7 +839,12 @@ pub(crate) async fn ensure_added_federation(
 
 #[cfg(all(test, not(feature = "integration-tests")))]
 mod tests {
-    use crate::register::is_valid_name;
+    use crate::models::reserved_name::{Reservation, ReservationKind, ReservedName};
+    use crate::register::{
+        ascii_name, is_lookalike, is_valid_name, name_skeleton, normalize_name, reserved_for_other,
+        validate_profile, MAX_AVATAR_BYTES,
+    };
+    use crate::routes::{AvatarMime, ProfileAvatar, UserProfile};
 
     #[tokio::test]
     async fn check_name() {
@@ -197,6 +865,157 @@ mod tests {
         assert!(is_valid_name("goodname1"));
         assert!(is_valid_name("yesnameisverygoodandunderlimit"));
     }
+
+    #[tokio::test]
+    async fn check_unicode_name() {
+        // normalized names are valid
+        assert!(is_valid_name("josé"));
+        assert!(is_valid_name("мирослав"));
+        assert!(is_valid_name("日本語"));
+        assert!(is_valid_name(&normalize_name("ZOË")));
+
+        // unnormalized, mixed script, or symbols are not
+        assert!(!is_valid_name("ZOË"));
+        assert!(!is_valid_name("\u{0455}atoshi"));
+        assert!(!is_valid_name("love❤"));
+        assert!(!is_valid_name("tab\tname"));
+
+        // normalization folds case and compatibility forms and decodes punycode
+        assert_eq!(normalize_name("ＢＯＢ"), "bob");
+        assert_eq!(normalize_name("José"), "josé");
+        assert_eq!(normalize_name(&ascii_name("josé")), "josé");
+        assert_eq!(ascii_name("josé"), "xn--jos-dma");
+        assert_eq!(ascii_name("good_name"), "good_name");
+
+        // full case folding
+        assert_eq!(normalize_name("Straße"), "strasse");
+        assert_eq!(normalize_name("STRASSE"), "strasse");
+        assert_eq!(normalize_name("σοφος"), normalize_name("ΣΟΦΟΣ"));
+        assert_eq!(normalize_name("σοφος"), "σοφοσ");
+
+        // lookalikes share a skeleton
+        assert_eq!(name_skeleton("satoshi"), name_skeleton("\u{0455}atoshi"));
+        assert_ne!(name_skeleton("satoshi"), name_skeleton("nakamoto"));
+        assert!(is_lookalike("satoshi", "\u{0455}atoshi"));
+        assert!(is_lookalike("\u{0441}\u{043e}\u{0440}\u{0430}", "copa"));
+        assert!(!is_lookalike("satoshi", "nakamoto"));
+
+        // but plain ASCII names only collide when they're the same
+        assert_eq!(name_skeleton("modern"), name_skeleton("modem"));
+        assert!(!is_lookalike("modern", "modem"));
+        assert!(!is_lookalike("bob1", "bobl"));
+        assert!(!is_lookalike("sam0", "samo"));
+        assert!(is_lookalike("modem", "modem"));
+    }
+
+    #[tokio::test]
+    async fn check_reserved_name_matching() {
+        let reserved = |pattern: &str, kind: ReservationKind| {
+            Reservation::from(ReservedName {
+                id: 0,
+                pattern: pattern.to_string(),
+                kind: kind as i32,
+                pubkey: None,
+                created_at: chrono::Utc::now().naive_utc(),
+                domain_id: None,
+            })
+        };
+
+        let exact = reserved("admin", ReservationKind::Exact);
+        assert!(exact.matches("admin"));
+        assert!(!exact.matches("admin1"));
+
+        let prefix = reserved("mutiny", ReservationKind::Prefix);
+        assert!(prefix.matches("mutiny"));
+        assert!(prefix.matches("mutinysupport"));
+        assert!(!prefix.matches("notmutiny"));
+
+        let regex = reserved("^.*(admin|support)$", ReservationKind::Regex);
+        assert!(regex.matches("siteadmin"));
+        assert!(regex.matches("support"));
+        assert!(!regex.matches("supporter"));
+
+        // invalid patterns never match
+        assert!(!reserved("(", ReservationKind::Regex).matches("("));
+
+        // lookalikes in other scripts match, ASCII names only match as they are
+        let lookalike = reserved("pop", ReservationKind::Exact);
+        assert!(lookalike.matches("\u{0440}\u{043e}\u{0440}"));
+        let lookalike = reserved("sea", ReservationKind::Prefix);
+        assert!(lookalike.matches("\u{0455}\u{0435}\u{0430}side"));
+        assert!(!reserved("modem", ReservationKind::Exact).matches("modern"));
+        assert!(!reserved("bobl", ReservationKind::Prefix).matches("bob1"));
+
+        // a name covered by a broad reservation and one for its owner
+        let owner = "owner-pubkey";
+        let mut owned = reserved("mutinyteam", ReservationKind::Exact);
+        owned.reserved.pubkey = Some(owner.to_string());
+        let reservations = vec![prefix, owned];
+        assert!(!reserved_for_other(&reservations, 1, "mutinyteam", owner));
+        assert!(reserved_for_other(
+            &reservations,
+            1,
+            "mutinyteam",
+            "someone-else"
+        ));
+        assert!(reserved_for_other(&reservations, 1, "mutinysupport", owner));
+        assert!(!reserved_for_other(
+            &reservations,
+            1,
+            "satoshi",
+            "someone-else"
+        ));
+
+        // reservations limited to a domain don't cover the others
+        let mut branded = reserved("satoshi", ReservationKind::Exact);
+        branded.reserved.domain_id = Some(2);
+        assert!(branded.covers(2));
+        assert!(!branded.covers(1));
+        let reservations = vec![branded];
+        assert!(reserved_for_other(&reservations, 2, "satoshi", owner));
+        assert!(!reserved_for_other(&reservations, 1, "satoshi", owner));
+    }
+
+    #[tokio::test]
+    async fn check_profile_limits() {
+        let avatar = |mime: AvatarMime, image: &[u8]| ProfileAvatar {
+            mime,
+            data: base64::encode(image),
+        };
+        let png = [&b"\x89PNG\r\n\x1a\n"[..], &[0u8; 16]].concat();
+
+        let mut profile = UserProfile {
+            description: Some("Tips for {name}".to_string()),
+            long_description: Some("a".repeat(2_048)),
+            avatar: Some(avatar(AvatarMime::Png, &png)),
+            private: false,
+        };
+        assert!(validate_profile(&profile).is_ok());
+
+        // private profiles can't name the user
+        profile.private = true;
+        assert!(validate_profile(&profile).is_err());
+        profile.description = Some("Tips".to_string());
+        assert!(validate_profile(&profile).is_ok());
+
+        profile.long_description = Some("a".repeat(2_049));
+        assert!(validate_profile(&profile).is_err());
+        profile.long_description = None;
+
+        // avatars have to be the image they claim, and small
+        profile.avatar = Some(avatar(AvatarMime::Jpeg, &png));
+        assert!(validate_profile(&profile).is_err());
+        profile.avatar = Some(avatar(
+            AvatarMime::Png,
+            &[&png[..], &vec![0u8; MAX_AVATAR_BYTES]].concat(),
+        ));
+        assert!(validate_profile(&profile).is_err());
+        profile.avatar = Some(ProfileAvatar {
+            mime: AvatarMime::Png,
+            data: "not base64!".to_string(),
+        });
+        assert!(validate_profile(&profile).is_err());
+    }
 }
 
 #[cfg(all(test, feature = "integration-tests"))]
@@ -240,49 +1059,41 @@ mod tests_integration {
     use std::{str::FromStr, sync::Arc};
 
     use fedimint_core::{api::InviteCode, config::FederationId, PeerId};
-    use nostr::Keys;
-    use secp256k1::Secp256k1;
+    use nostr::{prelude::rand, Keys};
+    use secp256k1::{Message, SecretKey};
     use tbs::{blind_message, unblind_signature, BlindingKey};
 
     use crate::{
-        db::setup_db,
+        dns::MockTxtResolver,
+        domains::{
+            add_domain, check_domain_claim, get_default_domain, resolve_domain,
+            verify_custom_domain,
+        },
+        invoice::InvoiceState,
+        lnurl_auth::{
+            bind_linking_key, issue_session_token, login, new_challenge, new_payer_auth_challenge,
+            session_scope, use_payer_auth_challenge,
+        },
         mint::MockMultiMintWrapperTrait,
-        models::app_user::NewAppUser,
-        register::{check_available, register, BlindSigner},
-        routes::RegisterRequest,
-        State,
+        models::{app_user::NewAppUser, invoice::NewInvoice, reserved_name::ReservationKind},
+        register::{
+            add_signing_key, ascii_name, change_username, check_available, delete_user,
+            disable_user_zaps, enable_user_zaps, generate_random_name, get_target_users,
+            is_reserved_for_other, is_valid_name, register, renew, reserve_name,
+            resolve_user_by_name, retire_signing_key, rotate_user_pubkey, set_primary_user,
+            set_user_success_action, unreserve_name, BlindSigner, PAID_NAME_TERM_DAYS,
+        },
+        routes::{RegisterRequest, RenewRequest, SuccessActionConfig},
+        test_state, SignerIdentity, State,
     };
 
     #[tokio::test]
     pub async fn test_username_checker() {
-        dotenv::dotenv().ok();
-        let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = setup_db(pg_url);
-
-        // swap out fm with a mock here since that's not what is being tested
-        let mock_mm = Arc::new(MockMultiMintWrapperTrait::new());
-
-        let nostr_nsec_str = std::env::var("NSEC").expect("FM_DB_PATH must be set");
-        let nostr_sk = Keys::from_str(&nostr_nsec_str).expect("Invalid NOSTR_SK");
-        let nostr = nostr_sdk::Client::new(&nostr_sk);
-
-        // create blind signer
-        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-        let paid_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-
-        let state = State {
-            db: db.clone(),
-            mm: mock_mm,
-            secp: Secp256k1::new(),
-            nostr,
-            free_pk: free_signer.pk,
-            paid_pk: paid_signer.pk,
-            domain: "http://127.0.0.1:8080".to_string(),
-            nostr_sk,
-        };
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
 
         let name = "veryuniquename123".to_string();
-        let available = check_available(&state, name).expect("should get");
+        let available = check_available(&state, &domain, name).expect("should get");
         assert!(available);
 
         let commonname = "commonname".to_string();
@@ -292,21 +1103,52 @@ mod tests_integration {
             federation_id: "".to_string(),
             unblinded_msg: "test_username_checker".to_string(),
             federation_invite_code: "".to_string(),
+            expires_at: None,
+            domain_id: domain.id,
         };
 
         // don't care about error if already exists
         let _ = state.db.insert_new_user(common_app_user);
 
-        let available = check_available(&state, commonname).expect("should get");
+        let available = check_available(&state, &domain, commonname).expect("should get");
         assert!(!available);
     }
 
     #[tokio::test]
-    pub async fn register_username_tests() {
-        dotenv::dotenv().ok();
-        let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = setup_db(pg_url);
+    pub async fn lookalike_name_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let n = rand::random::<u16>();
+        for name in [format!("modern{n}"), format!("copa{n}")] {
+            let pk = Keys::generate().public_key();
+            state
+                .db
+                .insert_new_user(NewAppUser {
+                    pubkey: pk.to_string(),
+                    name,
+                    federation_id: "".to_string(),
+                    unblinded_msg: pk.to_string(),
+                    federation_invite_code: "".to_string(),
+                    expires_at: None,
+                    domain_id: domain.id,
+                })
+                .unwrap();
+        }
 
+        // plain ASCII names sharing a skeleton are both fine
+        assert!(check_available(&state, &domain, format!("modem{n}")).unwrap());
+        // a lookalike in another script isn't
+        assert!(!check_available(
+            &state,
+            &domain,
+            format!("\u{0441}\u{043e}\u{0440}\u{0430}{n}")
+        )
+        .unwrap());
+    }
+
+    #[tokio::test]
+    pub async fn register_username_tests() {
         // swap out fm with a mock here since that's not what is being tested
         let mut mock_mm = MockMultiMintWrapperTrait::new();
         mock_mm
@@ -314,26 +1156,15 @@ mod tests_integration {
             .times(1)
             .returning(|_| true);
 
-        // nostr
-        let nostr_nsec_str = std::env::var("NSEC").expect("FM_DB_PATH must be set");
-        let nostr_sk = Keys::from_str(&nostr_nsec_str).expect("Invalid NOSTR_SK");
-        let nostr = nostr_sdk::Client::new(&nostr_sk);
-
         // create blind signer
-        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
         let paid_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
 
         let mock_mm = Arc::new(mock_mm);
         let state = State {
-            db: db.clone(),
             mm: mock_mm,
-            secp: Secp256k1::new(),
-            nostr,
-            free_pk: free_signer.pk,
-            paid_pk: paid_signer.pk,
-            domain: "http://127.0.0.1:8080".to_string(),
-            nostr_sk,
+            ..test_state()
         };
+        let domain = get_default_domain(&state).unwrap();
 
         // generate valid blinded message
         let msg = tbs::Message::from_bytes(b"register_username_tests");
@@ -349,13 +1180,14 @@ mod tests_integration {
         );
         let req = RegisterRequest {
             name: Some("registername".to_string()),
+            key_id: None,
             pubkey: "552a9d06810f306bfc085cb1e1c26102554138a51fa3a7fdf98f5b03a945143a".to_string(),
             federation_invite_code: connect.to_string(),
             msg,
             sig,
         };
 
-        match register(&state, req).await {
+        match register(&state, &domain, req).await {
             Ok(_) => (),
             Err(_) => {
                 panic!("shouldn't error")
@@ -365,10 +1197,6 @@ mod tests_integration {
 
     #[tokio::test]
     pub async fn register_username_add_unknown_federation_tests() {
-        dotenv::dotenv().ok();
-        let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = setup_db(pg_url);
-
         // swap out fm with a mock here since that's not what is being tested
         let mut mock_mm = MockMultiMintWrapperTrait::new();
         mock_mm
@@ -381,26 +1209,12 @@ mod tests_integration {
             .times(1)
             .returning(|_| Ok(()));
 
-        // nostr
-        let nostr_nsec_str = std::env::var("NSEC").expect("FM_DB_PATH must be set");
-        let nostr_sk = Keys::from_str(&nostr_nsec_str).expect("Invalid NOSTR_SK");
-        let nostr = nostr_sdk::Client::new(&nostr_sk);
-
-        // create blind signer
-        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-        let paid_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
-
         let mock_mm = Arc::new(mock_mm);
         let state = State {
-            db: db.clone(),
             mm: mock_mm,
-            secp: Secp256k1::new(),
-            nostr,
-            free_pk: free_signer.pk,
-            paid_pk: paid_signer.pk,
-            domain: "http://127.0.0.1:8080".to_string(),
-            nostr_sk,
+            ..test_state()
         };
+        let domain = get_default_domain(&state).unwrap();
 
         // generate valid blinded message
         let signer = BlindSigner::derive(&[0u8; 32], 0, 0);
@@ -417,13 +1231,14 @@ mod tests_integration {
         );
         let req = RegisterRequest {
             name: Some("newfederationusername".to_string()),
+            key_id: None,
             pubkey: "552a9d06810f306bfc085cb1e1c26102554138a51fa3a7fdf98f5b03a945143a".to_string(),
             federation_invite_code: connect.to_string(),
             msg,
             sig,
         };
 
-        match register(&state, req).await {
+        match register(&state, &domain, req).await {
             Ok(_) => (),
             Err(_) => {
                 panic!("shouldn't error")
@@ -433,10 +1248,6 @@ mod tests_integration {
 
     #[tokio::test]
     pub async fn register_username_already_spent_token_tests() {
-        dotenv::dotenv().ok();
-        let pg_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let db = setup_db(pg_url);
-
         // swap out fm with a mock here since that's not what is being tested
         let mut mock_mm = MockMultiMintWrapperTrait::new();
         mock_mm
@@ -444,26 +1255,15 @@ mod tests_integration {
             .times(1)
             .returning(|_| true);
 
-        // nostr
-        let nostr_nsec_str = std::env::var("NSEC").expect("FM_DB_PATH must be set");
-        let nostr_sk = Keys::from_str(&nostr_nsec_str).expect("Invalid NOSTR_SK");
-        let nostr = nostr_sdk::Client::new(&nostr_sk);
-
         // create blind signer
-        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
         let paid_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
 
         let mock_mm = Arc::new(mock_mm);
         let state = State {
-            db: db.clone(),
             mm: mock_mm,
-            secp: Secp256k1::new(),
-            nostr,
-            free_pk: free_signer.pk,
-            paid_pk: paid_signer.pk,
-            domain: "http://127.0.0.1:8080".to_string(),
-            nostr_sk,
+            ..test_state()
         };
+        let domain = get_default_domain(&state).unwrap();
 
         // generate valid blinded message
         let msg = tbs::Message::from_bytes(b"register_username_already_spent_token_tests");
@@ -479,6 +1279,7 @@ mod tests_integration {
         );
         let req = RegisterRequest {
             name: Some("registername1".to_string()),
+            key_id: None,
             pubkey: "552a9d06810f306bfc085cb1e1c26102554138a51fa3a7fdf98f5b03a945143a".to_string(),
             federation_invite_code: connect.to_string(),
             msg,
@@ -486,7 +1287,7 @@ mod tests_integration {
         };
 
         // let the first user register sucessfully
-        match register(&state, req).await {
+        match register(&state, &domain, req).await {
             Ok(r) => {
                 assert_eq!(r.name, "registername1");
             }
@@ -499,13 +1300,14 @@ mod tests_integration {
         // should return the first username it registered with
         let req2 = RegisterRequest {
             name: Some("registername2".to_string()),
+            key_id: None,
             pubkey: "552a9d06810f306bfc085cb1e1c26102554138a51fa3a7fdf98f5b03a945143a".to_string(),
             federation_invite_code: connect.to_string(),
             msg,
             sig,
         };
 
-        match register(&state, req2).await {
+        match register(&state, &domain, req2).await {
             Ok(r) => {
                 assert_eq!(r.name, "registername1");
             }
@@ -514,4 +1316,1050 @@ mod tests_integration {
             }
         }
     }
+
+    #[tokio::test]
+    pub async fn change_username_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        // free names can't be changed
+        let free_name = generate_random_name(&state, &domain).unwrap();
+        let free_pk = Keys::generate().public_key();
+        let free_user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: free_pk.to_string(),
+                name: free_name,
+                federation_id: "".to_string(),
+                unblinded_msg: free_pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+        let vanity = generate_random_name(&state, &domain).unwrap();
+        assert!(change_username(&state, free_user, vanity).is_err());
+
+        let old_name = generate_random_name(&state, &domain).unwrap();
+        let pk = Keys::generate().public_key();
+        let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::days(30);
+        let user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: old_name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: Some(expires_at),
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        let new_name = generate_random_name(&state, &domain).unwrap();
+        change_username(&state, user.clone(), new_name.clone()).expect("should change");
+
+        // the new name is taken and the old name redirects to the user
+        let renamed = state
+            .db
+            .get_user_by_name(domain.id, new_name.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(renamed.id, user.id);
+        assert_eq!(renamed.expires_at, user.expires_at);
+        assert!(!check_available(&state, &domain, new_name.clone()).unwrap());
+        assert!(!check_available(&state, &domain, old_name.clone()).unwrap());
+        let redirected = resolve_user_by_name(&state, &domain, old_name.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(redirected.id, user.id);
+        assert_eq!(redirected.name, new_name);
+
+        // a second change is blocked by the cooldown
+        let another_name = generate_random_name(&state, &domain).unwrap();
+        assert!(change_username(&state, renamed, another_name).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn downgrade_then_rename_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let paid_name = generate_random_name(&state, &domain).unwrap();
+        let pk = Keys::generate().public_key();
+        let expired_at = chrono::Utc::now().naive_utc() - chrono::Duration::days(30);
+        let user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: paid_name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: Some(expired_at),
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        // an expired name can't be moved somewhere else either
+        let vanity = generate_random_name(&state, &domain).unwrap();
+        assert!(change_username(&state, user.clone(), vanity.clone()).is_err());
+
+        let random_name = generate_random_name(&state, &domain).unwrap();
+        state
+            .db
+            .downgrade_user_name(user, random_name.clone())
+            .unwrap();
+        let downgraded = state
+            .db
+            .get_user_by_name(domain.id, random_name)
+            .unwrap()
+            .unwrap();
+        assert!(downgraded.expires_at.is_none());
+        assert!(downgraded.name_changed_at.is_some());
+
+        // the paid name is released for someone to pay for
+        assert!(check_available(&state, &domain, paid_name.clone()).unwrap());
+
+        // the owner can't rename back, or to any other name, for free
+        assert!(change_username(&state, downgraded.clone(), paid_name).is_err());
+        assert!(change_username(&state, downgraded, vanity).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn delete_account_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let name = generate_random_name(&state, &domain).unwrap();
+        let pk = Keys::generate().public_key();
+        let user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        let new_invoice = |invoice_state: InvoiceState| NewInvoice {
+            federation_id: "".to_string(),
+            // any unique id will do
+            op_id: Keys::generate().public_key().to_string(),
+            preimage: "00".repeat(32),
+            app_user_id: user.id,
+            user_invoice_index: 0,
+            bolt11: "lnbc1".to_string(),
+            amount: 1_000,
+            state: invoice_state as i32,
+            pubkey: pk.to_string(),
+            comment: None,
+            payer_data: None,
+            idempotency_key: None,
+        };
+        let pending = state
+            .db
+            .insert_new_invoice(new_invoice(InvoiceState::Pending))
+            .unwrap();
+        let settled = state
+            .db
+            .insert_new_invoice(new_invoice(InvoiceState::Settled))
+            .unwrap();
+
+        let mut updates = state.subscriptions.updates();
+        delete_user(&state, user).expect("should delete");
+
+        // listeners hear the pending invoice won't be paid
+        let update = updates.try_recv().unwrap();
+        assert_eq!(update.op_id, pending.op_id);
+        assert_eq!(update.preimage, None);
+        assert!(updates.try_recv().is_err());
+
+        // pending invoices are cancelled, and none of them is readable anymore
+        let pending = state
+            .db
+            .get_invoice_by_op_id(pending.op_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(pending.state, InvoiceState::Cancelled as i32);
+        assert!(pending.cancel_reason.is_some());
+        assert!(pending.bolt11().is_err());
+        let settled = state
+            .db
+            .get_invoice_by_op_id(settled.op_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(settled.state, InvoiceState::Settled as i32);
+        assert_eq!(settled.cancel_reason, None);
+        assert!(settled.bolt11().is_err());
+
+        // the user is no longer served but the name is quarantined
+        assert!(resolve_user_by_name(&state, &domain, name.clone())
+            .unwrap()
+            .is_none());
+        assert!(state
+            .db
+            .get_user_by_pubkey(pk.to_string())
+            .unwrap()
+            .is_none());
+        assert!(!check_available(&state, &domain, name).unwrap());
+    }
+
+    #[tokio::test]
+    pub async fn disable_and_enable_zaps_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let name = generate_random_name(&state, &domain).unwrap();
+        let pk = Keys::generate().public_key();
+        let user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        // can't disable until a time in the past
+        assert!(disable_user_zaps(&state, user.clone(), None, Some(1)).is_err());
+
+        let until = nostr::Timestamp::now().as_u64() + 3_600;
+        disable_user_zaps(&state, user, Some("vacation".to_string()), Some(until))
+            .expect("should disable");
+        let user = state
+            .db
+            .get_user_by_name(domain.id, name.clone())
+            .unwrap()
+            .unwrap();
+        assert!(user.zaps_disabled());
+        assert!(user.disabled_zaps_at.is_some());
+        assert_eq!(user.disabled_zaps_reason, Some("vacation".to_string()));
+        assert_eq!(
+            user.disabled_zaps_until
+                .map(|t| t.and_utc().timestamp() as u64),
+            Some(until)
+        );
+
+        enable_user_zaps(&state, user).expect("should enable");
+        let user = state.db.get_user_by_name(domain.id, name).unwrap().unwrap();
+        assert!(!user.zaps_disabled());
+        assert!(user.disabled_zaps_at.is_none());
+        assert!(user.disabled_zaps_reason.is_none());
+    }
+
+    #[tokio::test]
+    pub async fn success_action_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let name = generate_random_name(&state, &domain).unwrap();
+        let pk = Keys::generate().public_key();
+        let user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        // limits from LUD-09 are enforced
+        let too_long = SuccessActionConfig::Message {
+            message: "a".repeat(145),
+        };
+        assert!(set_user_success_action(&state, user.clone(), Some(too_long)).is_err());
+
+        let action = SuccessActionConfig::Aes {
+            description: "Your download code".to_string(),
+            plaintext: "1234".to_string(),
+        };
+        set_user_success_action(&state, user, Some(action.clone())).expect("should set");
+        let user = state
+            .db
+            .get_user_by_name(domain.id, name.clone())
+            .unwrap()
+            .unwrap();
+        let stored: SuccessActionConfig =
+            serde_json::from_str(user.success_action.as_ref().unwrap()).unwrap();
+        assert_eq!(stored, action);
+
+        set_user_success_action(&state, user, None).expect("should clear");
+        let user = state.db.get_user_by_name(domain.id, name).unwrap().unwrap();
+        assert!(user.success_action.is_none());
+    }
+
+    #[tokio::test]
+    pub async fn lnurl_auth_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let name = generate_random_name(&state, &domain).unwrap();
+        let pk = Keys::generate().public_key();
+        state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        // the wallet signs the challenge with its linking key
+        let (k1, url) = new_challenge(&state, &domain).expect("should issue");
+        assert!(url.contains(&format!("k1={k1}")));
+        let linking_sk = SecretKey::from_slice(&rand::random::<[u8; 32]>()).unwrap();
+        let linking_key = linking_sk.public_key(&state.secp).to_string();
+        let msg = Message::from_digest_slice(&hex::decode(&k1).unwrap()).unwrap();
+        let sig = hex::encode(state.secp.sign_ecdsa(&msg, &linking_sk).serialize_der());
+
+        assert!(issue_session_token(&state, &k1).is_err());
+        assert!(login(&state, &k1, "00", &linking_key).is_err());
+        login(&state, &k1, &sig, &linking_key).expect("should login");
+
+        // an unbound linking key doesn't act for anyone yet
+        let (token, _) = issue_session_token(&state, &k1).expect("should issue");
+        assert!(issue_session_token(&state, &k1).is_err());
+        assert!(session_scope(&state, &token).unwrap().is_none());
+
+        // a second address of the pubkey the key isn't bound to
+        let other_name = generate_random_name(&state, &domain).unwrap();
+        let other_user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: other_name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        bind_linking_key(&state, pk.to_string(), Some(name.clone()), &domain, &k1)
+            .expect("should bind");
+        let (session_pk, user_ids) = session_scope(&state, &token).unwrap().unwrap();
+        assert_eq!(session_pk, pk);
+        assert!(!user_ids.contains(&other_user.id));
+
+        let targets =
+            get_target_users(&state, pk.to_string(), None, Some(&user_ids), None).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].name, name);
+        assert!(get_target_users(
+            &state,
+            pk.to_string(),
+            Some(other_name),
+            Some(&user_ids),
+            None
+        )
+        .unwrap()
+        .is_empty());
+
+        // a challenge binds only once
+        assert!(
+            bind_linking_key(&state, pk.to_string(), Some(name.clone()), &domain, &k1).is_err()
+        );
+
+        // and the key can't be bound to another pubkey with a new login
+        let (k1, _) = new_challenge(&state, &domain).unwrap();
+        let msg = Message::from_digest_slice(&hex::decode(&k1).unwrap()).unwrap();
+        let sig = hex::encode(state.secp.sign_ecdsa(&msg, &linking_sk).serialize_der());
+        login(&state, &k1, &sig, &linking_key).expect("should login");
+        let other = Keys::generate().public_key();
+        assert!(bind_linking_key(&state, other.to_string(), None, &domain, &k1).is_err());
+        assert!(session_scope(&state, "not a token").unwrap().is_none());
+    }
+
+    #[tokio::test]
+    pub async fn payer_auth_challenge_tests() {
+        let state = test_state();
+
+        // every pay request gets its own challenge
+        let k1 = new_payer_auth_challenge(&state).unwrap();
+        assert_ne!(k1, new_payer_auth_challenge(&state).unwrap());
+
+        let key = SecretKey::from_slice(&rand::random::<[u8; 32]>())
+            .unwrap()
+            .public_key(&state.secp)
+            .to_string();
+        assert!(use_payer_auth_challenge(&state, &k1, &key).unwrap());
+
+        // a signature can't be used twice
+        assert!(!use_payer_auth_challenge(&state, &k1, &key).unwrap());
+        assert!(!use_payer_auth_challenge(&state, &"00".repeat(32), &key).unwrap());
+
+        // nor one that wasn't handed out by the service
+        let unused = new_payer_auth_challenge(&state).unwrap();
+        let forged = format!("{}{}", &unused[..32], "00".repeat(16));
+        assert!(!use_payer_auth_challenge(&state, &forged, &key).unwrap());
+        assert!(use_payer_auth_challenge(&state, &unused, &key).unwrap());
+
+        // and a payer's signature never turns into a login session or a binding
+        assert!(issue_session_token(&state, &k1).is_err());
+        let domain = get_default_domain(&state).unwrap();
+        let payee = Keys::generate().public_key();
+        assert!(bind_linking_key(&state, payee.to_string(), None, &domain, &k1).is_err());
+
+        // nor can a login challenge be used as payer auth
+        let (login_k1, _) = new_challenge(&state, &domain).unwrap();
+        assert!(!use_payer_auth_challenge(&state, &login_k1, &key).unwrap());
+    }
+
+    #[tokio::test]
+    pub async fn rotate_pubkey_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let name = generate_random_name(&state, &domain).unwrap();
+        let old_pk = Keys::generate().public_key();
+        let new_pk = Keys::generate().public_key();
+        let user = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: old_pk.to_string(),
+                name: name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: old_pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        // rotating to the same key is rejected
+        assert!(rotate_user_pubkey(&state, old_pk, old_pk).is_err());
+
+        rotate_user_pubkey(&state, old_pk, new_pk).expect("should rotate");
+        assert!(state
+            .db
+            .get_user_by_pubkey(old_pk.to_string())
+            .unwrap()
+            .is_none());
+        let rotated = state
+            .db
+            .get_user_by_pubkey(new_pk.to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(rotated.id, user.id);
+        assert_eq!(rotated.name, name);
+
+        // rotating onto a key with its own primary address keeps that one
+        let target_pk = Keys::generate().public_key();
+        let target = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: target_pk.to_string(),
+                name: generate_random_name(&state, &domain).unwrap(),
+                federation_id: "".to_string(),
+                unblinded_msg: target_pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+        set_primary_user(&state, target.clone()).unwrap();
+        set_primary_user(&state, rotated).unwrap();
+
+        rotate_user_pubkey(&state, new_pk, target_pk).expect("should rotate");
+        let users = state.db.get_users_by_pubkey(target_pk.to_string()).unwrap();
+        assert_eq!(users.len(), 2);
+        assert_eq!(users.iter().filter(|u| u.is_primary).count(), 1);
+        assert!(users[0].is_primary);
+        assert_eq!(users[0].id, target.id);
+    }
+
+    #[tokio::test]
+    pub async fn primary_name_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        let pk = Keys::generate().public_key();
+        let mut names = vec![];
+        for _ in 0..2 {
+            let name = generate_random_name(&state, &domain).unwrap();
+            state
+                .db
+                .insert_new_user(NewAppUser {
+                    pubkey: pk.to_string(),
+                    name: name.clone(),
+                    federation_id: "".to_string(),
+                    unblinded_msg: format!("{pk}{name}"),
+                    federation_invite_code: "".to_string(),
+                    expires_at: None,
+                    domain_id: domain.id,
+                })
+                .unwrap();
+            names.push(name);
+        }
+
+        // without a primary the first registered name is reported
+        assert_eq!(
+            state.db.check_registered_pubkey(pk.to_string()).unwrap(),
+            Some(names[0].clone())
+        );
+
+        // targeting narrows to a single address
+        let all = get_target_users(&state, pk.to_string(), None, None, None).unwrap();
+        assert_eq!(all.len(), 2);
+        let second =
+            get_target_users(&state, pk.to_string(), Some(names[1].clone()), None, None).unwrap();
+        assert_eq!(second.len(), 1);
+        assert!(
+            get_target_users(&state, pk.to_string(), Some("nope".to_string()), None, None)
+                .unwrap()
+                .is_empty()
+        );
+
+        // and a session only reaches the addresses it's bound to
+        let ids = [second[0].id];
+        assert_eq!(
+            get_target_users(&state, pk.to_string(), None, Some(&ids), None)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(get_target_users(
+            &state,
+            pk.to_string(),
+            Some(names[0].clone()),
+            Some(&ids),
+            None
+        )
+        .unwrap()
+        .is_empty());
+
+        set_primary_user(&state, second[0].clone()).expect("should set primary");
+        assert_eq!(
+            state.db.check_registered_pubkey(pk.to_string()).unwrap(),
+            Some(names[1].clone())
+        );
+        let users = state.db.get_users_by_pubkey(pk.to_string()).unwrap();
+        assert_eq!(users[0].name, names[1]);
+        assert!(users[0].is_primary);
+        assert!(!users[1].is_primary);
+    }
+
+    #[tokio::test]
+    pub async fn reserved_name_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        // seeded reservations
+        assert!(!check_available(&state, &domain, "admin".to_string()).unwrap());
+        assert!(!check_available(&state, &domain, "mutinyteam".to_string()).unwrap());
+
+        // invalid regexes are rejected
+        assert!(reserve_name(&state, "(".to_string(), ReservationKind::Regex, None, None).is_err());
+
+        let name = generate_random_name(&state, &domain).unwrap();
+        let owner = Keys::generate().public_key();
+        reserve_name(
+            &state,
+            name.clone(),
+            ReservationKind::Exact,
+            Some(owner.to_string()),
+            None,
+        )
+        .expect("should reserve");
+        assert!(!check_available(&state, &domain, name.clone()).unwrap());
+
+        // only the owner can rename into the reserved name
+        let other = Keys::generate().public_key();
+        let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::days(30);
+        for pk in [other, owner] {
+            let tmp_name = generate_random_name(&state, &domain).unwrap();
+            state
+                .db
+                .insert_new_user(NewAppUser {
+                    pubkey: pk.to_string(),
+                    name: tmp_name.clone(),
+                    federation_id: "".to_string(),
+                    unblinded_msg: pk.to_string(),
+                    federation_invite_code: "".to_string(),
+                    expires_at: Some(expires_at),
+                    domain_id: domain.id,
+                })
+                .unwrap();
+        }
+        let other_user = state
+            .db
+            .get_user_by_pubkey(other.to_string())
+            .unwrap()
+            .unwrap();
+        assert!(change_username(&state, other_user, name.clone()).is_err());
+        let owner_user = state
+            .db
+            .get_user_by_pubkey(owner.to_string())
+            .unwrap()
+            .unwrap();
+        change_username(&state, owner_user, name.clone()).expect("owner should rename");
+
+        assert!(unreserve_name(&state, name.clone(), ReservationKind::Exact, None).unwrap());
+        assert!(!unreserve_name(&state, name, ReservationKind::Exact, None).unwrap());
+
+        // a name reserved for its owner under the seeded "mutiny" prefix
+        let owned = format!("mutiny{}", rand::random::<u32>());
+        let owner_pk = owner.to_string();
+        assert!(is_reserved_for_other(&state, domain.id, &owned, &owner_pk).unwrap());
+        reserve_name(
+            &state,
+            owned.clone(),
+            ReservationKind::Exact,
+            Some(owner_pk.clone()),
+            None,
+        )
+        .expect("should reserve");
+        assert!(!is_reserved_for_other(&state, domain.id, &owned, &owner_pk).unwrap());
+        assert!(is_reserved_for_other(&state, domain.id, &owned, &other.to_string()).unwrap());
+        assert!(unreserve_name(&state, owned.clone(), ReservationKind::Exact, None).unwrap());
+        assert!(is_reserved_for_other(&state, domain.id, &owned, &owner_pk).unwrap());
+
+        // a reservation on a branded domain leaves the name free elsewhere
+        let host = format!("reserved{}.example.com", rand::random::<u32>());
+        let branded = add_domain(&state, host.clone(), None, None, vec![]).unwrap();
+        let name = generate_random_name(&state, &domain).unwrap();
+        assert!(reserve_name(
+            &state,
+            name.clone(),
+            ReservationKind::Exact,
+            None,
+            Some("unknown.example.com".to_string()),
+        )
+        .is_err());
+        reserve_name(
+            &state,
+            name.clone(),
+            ReservationKind::Exact,
+            None,
+            Some(host.clone()),
+        )
+        .expect("should reserve");
+        assert!(!check_available(&state, &branded, name.clone()).unwrap());
+        assert!(check_available(&state, &domain, name.clone()).unwrap());
+
+        // only the reservation on that domain is removed
+        assert!(!unreserve_name(&state, name.clone(), ReservationKind::Exact, None).unwrap());
+        assert!(unreserve_name(&state, name.clone(), ReservationKind::Exact, Some(host)).unwrap());
+        assert!(check_available(&state, &branded, name).unwrap());
+    }
+
+    #[tokio::test]
+    pub async fn confusable_name_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+
+        // an all cyrillic name that looks like a latin one
+        let suffix = nostr::Timestamp::now().as_u64() % 1_000_000;
+        let latin = format!("cope{suffix}");
+        let name = format!("\u{0441}\u{043e}\u{0440}\u{0435}{suffix}");
+        assert!(is_valid_name(&name));
+        let pk = Keys::generate().public_key();
+        state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: pk.to_string(),
+                name: name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: pk.to_string(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        // the user resolves by the upper case and punycode forms of the name
+        assert!(resolve_user_by_name(&state, &domain, name.to_uppercase())
+            .unwrap()
+            .is_some());
+        assert!(resolve_user_by_name(&state, &domain, ascii_name(&name))
+            .unwrap()
+            .is_some());
+
+        // the latin lookalike can't be registered
+        assert!(is_valid_name(&latin));
+        assert!(!check_available(&state, &domain, latin).unwrap());
+    }
+
+    #[tokio::test]
+    pub async fn renew_name_tests() {
+        // create blind signer
+        let free_signer = BlindSigner::derive(&[0u8; 32], 0, 0);
+        let paid_signer = BlindSigner::derive(&[1u8; 32], 0, 1);
+
+        let state = State {
+            paid_pk: paid_signer.pk,
+            ..test_state()
+        };
+        let domain = get_default_domain(&state).unwrap();
+
+        let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::days(1);
+        let mut names = vec![];
+        for expires_at in [Some(expires_at), None] {
+            let name = generate_random_name(&state, &domain).unwrap();
+            let pk = Keys::generate().public_key();
+            state
+                .db
+                .insert_new_user(NewAppUser {
+                    pubkey: pk.to_string(),
+                    name: name.clone(),
+                    federation_id: "".to_string(),
+                    unblinded_msg: pk.to_string(),
+                    federation_invite_code: "".to_string(),
+                    expires_at,
+                    domain_id: domain.id,
+                })
+                .unwrap();
+            names.push(name);
+        }
+
+        // generate a paid token
+        let msg = tbs::Message::from_bytes(Keys::generate().public_key().to_string().as_bytes());
+        let blinding_key = BlindingKey::random();
+        let blind_sig = paid_signer.blind_sign(blind_message(msg, blinding_key));
+        let sig = unblind_signature(blinding_key, blind_sig);
+
+        // free tokens can't renew
+        let free_sig = unblind_signature(
+            blinding_key,
+            free_signer.blind_sign(blind_message(msg, blinding_key)),
+        );
+        let req = RenewRequest {
+            name: names[0].clone(),
+            key_id: None,
+            msg,
+            sig: free_sig,
+        };
+        assert!(renew(&state, &domain, req).is_err());
+
+        // free names don't expire
+        let req = RenewRequest {
+            name: names[1].clone(),
+            key_id: None,
+            msg,
+            sig,
+        };
+        assert!(renew(&state, &domain, req).is_err());
+
+        let req = RenewRequest {
+            name: names[0].clone(),
+            key_id: None,
+            msg,
+            sig,
+        };
+        let res = renew(&state, &domain, req.clone()).expect("should renew");
+        assert_eq!(
+            res.expires_at,
+            (expires_at + chrono::Duration::days(PAID_NAME_TERM_DAYS))
+                .and_utc()
+                .timestamp() as u64
+        );
+        let user = state
+            .db
+            .get_user_by_name(domain.id, names[0].clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            user.expires_at.map(|t| t.and_utc().timestamp() as u64),
+            Some(res.expires_at)
+        );
+
+        // the token can only be spent once
+        assert!(renew(&state, &domain, req).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn signing_key_tests() {
+        // swap out fm with a mock here since that's not what is being tested
+        let mut mock_mm = MockMultiMintWrapperTrait::new();
+        mock_mm
+            .expect_check_has_federation()
+            .times(1)
+            .returning(|_| true);
+
+        let mock_mm = Arc::new(mock_mm);
+        let state = State {
+            mm: mock_mm,
+            ..test_state()
+        };
+        let domain = get_default_domain(&state).unwrap();
+
+        // a new paid key for a different plan, unique to this run
+        let seed = Keys::generate().secret_key().unwrap().secret_bytes();
+        let identity = SignerIdentity {
+            service_id: 1,
+            plan_id: 2,
+        };
+        let rotated_signer = BlindSigner::derive(&seed, identity.service_id, identity.plan_id);
+        let key = add_signing_key(
+            &state,
+            hex::encode(rotated_signer.pk.0.to_compressed()),
+            identity,
+            true,
+            None,
+            None,
+        )
+        .expect("should add key");
+        assert_eq!(key.identity(), identity);
+
+        let connect = InviteCode::new(
+            "ws://test1".parse().unwrap(),
+            PeerId::from_str("1").unwrap(),
+            FederationId::dummy(),
+        );
+        let token = |name: String| {
+            let msg = tbs::Message::from_bytes(name.as_bytes());
+            let blinding_key = BlindingKey::random();
+            let blind_sig = rotated_signer.blind_sign(blind_message(msg, blinding_key));
+            RegisterRequest {
+                name: Some(name),
+                key_id: Some(key.key_id.clone()),
+                pubkey: Keys::generate().public_key().to_string(),
+                federation_invite_code: connect.to_string(),
+                msg,
+                sig: unblind_signature(blinding_key, blind_sig),
+            }
+        };
+
+        // tokens signed by the rotated key only verify when naming it
+        let name = generate_random_name(&state, &domain).unwrap();
+        let mut req = token(name.clone());
+        req.key_id = None;
+        assert!(register(&state, &domain, req).await.is_err());
+        let res = register(&state, &domain, token(name.clone()))
+            .await
+            .expect("should register");
+        assert_eq!(res.name, name);
+
+        // unknown and retired keys are rejected
+        let mut req = token(generate_random_name(&state, &domain).unwrap());
+        req.key_id = Some("unknown".to_string());
+        assert!(register(&state, &domain, req).await.is_err());
+        assert!(retire_signing_key(&state, key.key_id.clone(), None).unwrap());
+        let req = token(generate_random_name(&state, &domain).unwrap());
+        assert!(register(&state, &domain, req).await.is_err());
+    }
+
+    #[tokio::test]
+    pub async fn multi_domain_tests() {
+        let state = test_state();
+        let domain = get_default_domain(&state).unwrap();
+        let other = add_domain(
+            &state,
+            "Other.Example.com".to_string(),
+            None,
+            Some("Tips for {name}".to_string()),
+            vec!["https://other.example.com".to_string()],
+        )
+        .unwrap();
+        assert_eq!(other.host, "other.example.com");
+
+        // hosts resolve to their domain, unknown ones to the default
+        assert_eq!(
+            resolve_domain(&state, Some("other.example.com"))
+                .unwrap()
+                .id,
+            other.id
+        );
+        assert_eq!(
+            resolve_domain(&state, Some("unknown.example.com"))
+                .unwrap()
+                .id,
+            domain.id
+        );
+        assert_eq!(resolve_domain(&state, None).unwrap().id, domain.id);
+
+        // the same name can be registered on both domains by different users
+        let name = generate_random_name(&state, &domain).unwrap();
+        for d in [&domain, &other] {
+            assert!(check_available(&state, d, name.clone()).unwrap());
+            let pk = Keys::generate().public_key();
+            state
+                .db
+                .insert_new_user(NewAppUser {
+                    pubkey: pk.to_string(),
+                    name: name.clone(),
+                    federation_id: "".to_string(),
+                    unblinded_msg: pk.to_string(),
+                    federation_invite_code: "".to_string(),
+                    expires_at: None,
+                    domain_id: d.id,
+                })
+                .unwrap();
+            assert!(!check_available(&state, d, name.clone()).unwrap());
+        }
+
+        let on_default = resolve_user_by_name(&state, &domain, name.clone())
+            .unwrap()
+            .unwrap();
+        let on_other = resolve_user_by_name(&state, &other, name.clone())
+            .unwrap()
+            .unwrap();
+        assert_ne!(on_default.id, on_other.id);
+        assert_eq!(on_other.domain_id, other.id);
+
+        // a pubkey with addresses on both only manages the one of the domain
+        // a request is made to
+        let pk = Keys::generate().public_key();
+        let name = generate_random_name(&state, &domain).unwrap();
+        for d in [&domain, &other] {
+            state
+                .db
+                .insert_new_user(NewAppUser {
+                    pubkey: pk.to_string(),
+                    name: name.clone(),
+                    federation_id: "".to_string(),
+                    unblinded_msg: format!("{pk}{}", d.id),
+                    federation_invite_code: "".to_string(),
+                    expires_at: None,
+                    domain_id: d.id,
+                })
+                .unwrap();
+        }
+        let targets = get_target_users(
+            &state,
+            pk.to_string(),
+            Some(name.clone()),
+            None,
+            Some(&other),
+        )
+        .unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].domain_id, other.id);
+        assert_eq!(
+            get_target_users(&state, pk.to_string(), None, None, Some(&domain))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            get_target_users(&state, pk.to_string(), Some(name), None, None)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    pub async fn custom_domain_tests() {
+        // only the owner has set the verification record for their domain
+        let owner_pk = Keys::generate().public_key().to_string();
+        let host = format!("{}.example.org", &owner_pk[..16]);
+        let mut resolver = MockTxtResolver::new();
+        let record_name = format!("_hermes.{host}");
+        let record_value = format!("hermes-verify={owner_pk}");
+        resolver.expect_lookup_txt().returning(move |name| {
+            if name == record_name {
+                Ok(vec!["v=spf1 -all".to_string(), record_value.clone()])
+            } else {
+                Ok(vec![])
+            }
+        });
+
+        let state = State {
+            resolver: Arc::new(resolver),
+            ..test_state()
+        };
+        let domain = get_default_domain(&state).unwrap();
+
+        let owner_name = generate_random_name(&state, &domain).unwrap();
+        let owner = state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: owner_pk.clone(),
+                name: owner_name.clone(),
+                federation_id: "".to_string(),
+                unblinded_msg: owner_pk.clone(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+
+        // the operator's domains can't be claimed
+        assert!(check_domain_claim(
+            &state,
+            owner_pk.clone(),
+            domain.host.clone(),
+            "me".to_string(),
+            None,
+            None,
+            &domain,
+        )
+        .is_err());
+
+        // unverified domains aren't served
+        assert_eq!(resolve_domain(&state, Some(&host)).unwrap().id, domain.id);
+
+        // someone else can't verify without the record naming them
+        let other_pk = Keys::generate().public_key().to_string();
+        state
+            .db
+            .insert_new_user(NewAppUser {
+                pubkey: other_pk.clone(),
+                name: generate_random_name(&state, &domain).unwrap(),
+                federation_id: "".to_string(),
+                unblinded_msg: other_pk.clone(),
+                federation_invite_code: "".to_string(),
+                expires_at: None,
+                domain_id: domain.id,
+            })
+            .unwrap();
+        let claim = check_domain_claim(
+            &state,
+            other_pk,
+            host.clone(),
+            "me".to_string(),
+            None,
+            None,
+            &domain,
+        )
+        .unwrap();
+        assert!(verify_custom_domain(&state, claim).await.is_err());
+
+        let claim = check_domain_claim(
+            &state,
+            owner_pk.clone(),
+            host.to_uppercase(),
+            "Me".to_string(),
+            Some(owner_name.clone()),
+            None,
+            &domain,
+        )
+        .unwrap();
+        assert_eq!(claim.owner.id, owner.id);
+        let custom = verify_custom_domain(&state, claim).await.unwrap();
+        assert_eq!(custom.host, host);
+        assert_eq!(custom.owner_user_id, Some(owner.id));
+        assert!(custom.verified_at.is_some());
+
+        // the claimed name resolves to the owner's address, nothing else does
+        let custom = resolve_domain(&state, Some(&host)).unwrap();
+        assert_eq!(custom.owner_name.as_deref(), Some("me"));
+        let user = resolve_user_by_name(&state, &custom, "me".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.id, owner.id);
+        assert!(resolve_user_by_name(&state, &custom, owner_name.clone())
+            .unwrap()
+            .is_none());
+        assert!(!check_available(&state, &custom, "anything".to_string()).unwrap());
+    }
 }
@@ -1,31 +1,53 @@
 use crate::{
-    lnurlp::{lnurl_callback, verify, well_known_lnurlp},
+    auth::{AuthMethod, Authenticated},
+    domains::{
+        add_domain, check_domain_claim, verification_record, verify_custom_domain, RequestDomain,
+    },
+    lnurl_auth::{
+        bind_linking_key as bind_user_linking_key, issue_session_token, login, new_challenge,
+    },
+    lnurl_code::{render_png, render_svg, user_lnurl},
+    lnurlp::{lnurl_callback, wait_for_verify_update, watch_verify, well_known_lnurlp},
+    models::{
+        app_user::AppUser,
+        domain::Domain,
+        reserved_name::{ReservationKind, ReservedName},
+        signing_key::SigningKey,
+    },
     nostr::well_known_nip5,
+    rate_limit::{RateLimiter, ThrottledCount},
     register::{
-        change_user_federation, check_available, check_registered_pubkey, disable_user_zaps,
-        ensure_added_federation, get_user_by_pubkey, register,
+        add_signing_key, change_user_federation, change_username as change_user_name,
+        check_available, check_registered_pubkey, delete_user, disable_user_zaps, enable_user_zaps,
+        ensure_added_federation, get_signing_keys, get_target_users, get_user_by_pubkey,
+        get_users_by_pubkey, normalize_name, register, renew, reserve_name as reserve_user_name,
+        retire_signing_key, rotate_user_pubkey, set_primary_user, set_user_profile,
+        set_user_success_action, unreserve_name as unreserve_user_name,
     },
-    State, ALLOWED_LOCALHOST, ALLOWED_ORIGINS, ALLOWED_SUBDOMAIN, API_VERSION,
+    signed_command::verify_signed_command,
+    SignerIdentity, State, ALLOWED_LOCALHOST, ALLOWED_ORIGINS, ALLOWED_SUBDOMAIN, API_VERSION,
 };
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
 use axum::extract::{Path, Query};
 use axum::headers::Origin;
-use axum::http::StatusCode;
+use axum::http::{header::CONTENT_TYPE, StatusCode};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
 use axum::response::{IntoResponse, Redirect, Response};
 use axum::Extension;
 use axum::{Json, TypedHeader};
 use fedimint_core::{api::InviteCode, config::FederationId, Amount};
 use fedimint_ln_common::lightning_invoice::Bolt11Invoice;
+use futures::{future, stream, Stream, StreamExt};
 use log::{error, info};
 use nostr::{Event, Kind};
 use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_json::{json, Value};
-use std::{collections::HashMap, fmt::Display, str::FromStr};
+use std::{collections::HashMap, fmt::Display, str::FromStr, sync::Arc, time::Duration};
 use tbs::AggregatePublicKey;
 use url::Url;
 
-const REGISTRATION_CHECK_EVENT_KIND: Kind = Kind::Custom(93_186);
-const NEW_FEDERATION_EVENT_KIND: Kind = Kind::Custom(93_187);
-const DISABLE_ZAPS_EVENT_KIND: Kind = Kind::Custom(93_188);
+/// Kind of the countersignature a new key gives when rotating to it
+const ROTATE_KEY_EVENT_KIND: Kind = Kind::Custom(93_192);
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct LnUrlErrorResponse {
@@ -43,12 +65,13 @@ impl IntoResponse for LnUrlErrorResponse {
 pub async fn check_username(
     origin: Option<TypedHeader<Origin>>,
     Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
     Path(username): Path<String>,
 ) -> Result<Json<bool>, (StatusCode, String)> {
     info!("check_username: {}", username);
-    validate_cors(origin)?;
+    validate_domain_cors(origin, &domain)?;
 
-    match check_available(&state, username.clone()) {
+    match check_available(&state, &domain, username.clone()) {
         Ok(res) => {
             info!("check_username finished: {}", username);
             Ok(Json(res))
@@ -78,185 +101,946 @@ pub async fn check_pubkey(
     }
 }
 
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegisteredAddress {
+    pub name: String,
+    pub federation_id: FederationId,
+    pub primary: bool,
+    pub disabled_zaps: bool,
+    /// Unix timestamp of when the address was disabled
+    pub disabled_zaps_at: Option<u64>,
+    /// Unix timestamp of when the address will start receiving again
+    pub disabled_zaps_until: Option<u64>,
+    pub disabled_zaps_reason: Option<String>,
+    /// Unix timestamp of when a paid name has to be renewed by
+    pub expires_at: Option<u64>,
+}
+
+impl RegisteredAddress {
+    fn from_user(user: AppUser, primary: bool) -> Result<Self, (StatusCode, String)> {
+        Ok(Self {
+            federation_id: FederationId::from_str(&user.federation_id).map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "FederationId invalid".to_string(),
+                )
+            })?,
+            primary,
+            disabled_zaps: user.zaps_disabled(),
+            disabled_zaps_at: user
+                .disabled_zaps_at
+                .map(|t| t.and_utc().timestamp() as u64),
+            disabled_zaps_until: user
+                .disabled_zaps_until
+                .map(|t| t.and_utc().timestamp() as u64),
+            disabled_zaps_reason: user.disabled_zaps_reason,
+            expires_at: user.expires_at.map(|t| t.and_utc().timestamp() as u64),
+            name: user.name,
+        })
+    }
+}
+
+/// The top level fields describe the primary address of the pubkey
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RegistrationInfo {
     pub name: Option<String>,
     pub federation_id: Option<FederationId>,
     pub disabled_zaps: bool,
+    /// Unix timestamp of when the address was disabled
+    pub disabled_zaps_at: Option<u64>,
+    /// Unix timestamp of when the address will start receiving again
+    pub disabled_zaps_until: Option<u64>,
+    pub disabled_zaps_reason: Option<String>,
+    /// Every address registered to the pubkey, the primary one first
+    pub addresses: Vec<RegisteredAddress>,
 }
 
 pub async fn check_registration_info(
     origin: Option<TypedHeader<Origin>>,
     Extension(state): Extension<State>,
-    Json(event): Json<Event>,
+    auth: Authenticated<()>,
 ) -> Result<Json<RegistrationInfo>, (StatusCode, String)> {
-    validate_cors(origin)?;
+    validate_domain_cors(origin, &auth.domain)?;
 
-    let pubkey = event.author();
+    let pubkey = auth.pubkey;
     info!("check_registration_info: {}", pubkey);
 
-    if event.verify().is_err() && event.kind() != REGISTRATION_CHECK_EVENT_KIND {
-        error!("error in check_registration_info: bad event");
-        return Err((StatusCode::BAD_REQUEST, "Bad event".to_string()));
-    }
-
-    // make sure it was made recently
-    let created_at = event.created_at();
-    let now = nostr::Timestamp::now();
-    if created_at < now - 120_i64 && created_at > now + 120_i64 {
-        error!("error in check_registration_info: event time not in range");
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Event time not in range".to_string(),
-        ));
-    }
-
-    match get_user_by_pubkey(&state, pubkey.to_string()) {
-        Ok(Some(u)) => {
+    match get_users_by_pubkey(&state, pubkey.to_string()) {
+        Ok(users) if !users.is_empty() => {
             info!("check_pubkey finished: {}", pubkey);
 
+            let addresses = users
+                .into_iter()
+                .enumerate()
+                .map(|(i, u)| RegisteredAddress::from_user(u, i == 0))
+                .collect::<Result<Vec<_>, _>>()?;
+            let primary = addresses[0].clone();
+
             Ok(Json(RegistrationInfo {
-                name: Some(u.name),
-                federation_id: Some(FederationId::from_str(&u.federation_id).map_err(|_| {
-                    (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        "FederationId invalid".to_string(),
-                    )
-                })?),
-                disabled_zaps: u.disabled_zaps,
+                name: Some(primary.name),
+                federation_id: Some(primary.federation_id),
+                disabled_zaps: primary.disabled_zaps,
+                disabled_zaps_at: primary.disabled_zaps_at,
+                disabled_zaps_until: primary.disabled_zaps_until,
+                disabled_zaps_reason: primary.disabled_zaps_reason,
+                addresses,
             }))
         }
-        Ok(None) => {
+        Ok(_) => {
             info!("check_pubkey not found: {}", pubkey);
 
             Ok(Json(RegistrationInfo {
                 name: None,
                 federation_id: None,
                 disabled_zaps: true,
+                disabled_zaps_at: None,
+                disabled_zaps_until: None,
+                disabled_zaps_reason: None,
+                addresses: vec![],
             }))
         }
         Err(e) => Err(handle_anyhow_error("check_pubkey", e)),
     }
 }
 
+/// Management requests can target one of a pubkey's addresses by name,
+/// otherwise they apply to all of them
+#[derive(Deserialize, Default)]
+pub struct TargetRequest {
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeFederationRequest {
+    pub name: Option<String>,
+    pub invite_code: String,
+}
+
 pub async fn change_federation(
     origin: Option<TypedHeader<Origin>>,
     Extension(state): Extension<State>,
-    Json(event): Json<Event>,
+    auth: Authenticated<ChangeFederationRequest>,
 ) -> Result<(), (StatusCode, String)> {
-    validate_cors(origin)?;
+    validate_domain_cors(origin, &auth.domain)?;
+    apply_change_federation(
+        &state,
+        auth.pubkey,
+        auth.user_ids.as_deref(),
+        Some(&auth.domain),
+        auth.body,
+    )
+    .await
+}
 
-    let pubkey = event.author();
+/// Moves the pubkey's targeted addresses to a new federation, for requests
+/// made over HTTP or nostr. Nostr commands aren't made to a domain.
+pub(crate) async fn apply_change_federation(
+    state: &State,
+    pubkey: nostr::PublicKey,
+    user_ids: Option<&[i32]>,
+    domain: Option<&Domain>,
+    req: ChangeFederationRequest,
+) -> Result<(), (StatusCode, String)> {
     info!("change_federation: {}", pubkey);
 
-    if event.verify().is_err() && event.kind() != NEW_FEDERATION_EVENT_KIND {
-        error!("error in change_federation: bad event");
-        return Err((StatusCode::BAD_REQUEST, "Bad event".to_string()));
-    }
-
-    // make sure it was made recently
-    let created_at = event.created_at();
-    let now = nostr::Timestamp::now();
-    if created_at < now - 120_i64 && created_at > now + 120_i64 {
-        error!("error in change_federation: event time not in range");
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Event time not in range".to_string(),
-        ));
-    }
-
     // get the federation invite code and parse it
-    let federation_invite_code = InviteCode::from_str(event.content())
+    let federation_invite_code = InviteCode::from_str(&req.invite_code)
         .map_err(|_| (StatusCode::BAD_REQUEST, "InviteCode Invalid".to_string()))?;
     let federation_id = federation_invite_code.federation_id();
 
     // make sure it's added to our federation list
-    ensure_added_federation(&state, federation_id, federation_invite_code.clone()).await?;
+    ensure_added_federation(state, federation_id, federation_invite_code.clone()).await?;
 
-    match get_user_by_pubkey(&state, pubkey.to_string()) {
-        Ok(Some(u)) => {
-            info!("change_federation found user for pubkey: {}", pubkey);
-
-            // got the user, now change the federation
-            match change_user_federation(
-                &state,
-                u,
-                federation_id.to_string(),
-                federation_invite_code.to_string(),
-            ) {
+    match get_target_users(state, pubkey.to_string(), req.name, user_ids, domain) {
+        Ok(users) if !users.is_empty() => {
+            info!("change_federation found users for pubkey: {}", pubkey);
+
+            // got the users, now change the federation
+            for u in users {
+                change_user_federation(
+                    state,
+                    u,
+                    federation_id.to_string(),
+                    federation_invite_code.to_string(),
+                )
+                .map_err(|e| handle_anyhow_error("change_federation", e))?;
+            }
+
+            info!(
+                "change_federation changed user federation for pubkey: {}, {}",
+                pubkey, federation_id
+            );
+            Ok(())
+        }
+        Ok(_) => {
+            error!("change_federation not found: {}", pubkey);
+
+            Err((StatusCode::NOT_FOUND, "User not found".to_string()))
+        }
+        Err(e) => Err(handle_anyhow_error("change_federation", e)),
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub struct DisableZapsRequest {
+    pub name: Option<String>,
+    pub reason: Option<String>,
+    /// Unix timestamp of when the address should start receiving again
+    pub until: Option<u64>,
+}
+
+pub async fn disable_zaps(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<DisableZapsRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+    apply_disable_zaps(
+        &state,
+        auth.pubkey,
+        auth.user_ids.as_deref(),
+        Some(&auth.domain),
+        auth.body,
+    )
+}
+
+/// Disables the pubkey's targeted addresses, for requests made over HTTP or
+/// nostr. Nostr commands aren't made to a domain.
+pub(crate) fn apply_disable_zaps(
+    state: &State,
+    pubkey: nostr::PublicKey,
+    user_ids: Option<&[i32]>,
+    domain: Option<&Domain>,
+    req: DisableZapsRequest,
+) -> Result<(), (StatusCode, String)> {
+    info!("disable_zaps: {}", pubkey);
+
+    match get_target_users(state, pubkey.to_string(), req.name, user_ids, domain) {
+        Ok(users) if !users.is_empty() => {
+            info!("disable_zaps found users for pubkey: {}", pubkey);
+
+            // got the users, now disable them
+            for u in users {
+                disable_user_zaps(state, u, req.reason.clone(), req.until)
+                    .map_err(|e| handle_anyhow_error("disable_zaps", e))?;
+            }
+
+            info!("disable_zaps disabled zaps for pubkey: {}", pubkey);
+            Ok(())
+        }
+        Ok(_) => {
+            error!("disable_zaps not found: {}", pubkey);
+
+            Err((StatusCode::NOT_FOUND, "User not found".to_string()))
+        }
+        Err(e) => Err(handle_anyhow_error("disable_zaps", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ChangeUsernameRequest {
+    pub name: Option<String>,
+    pub new_name: String,
+}
+
+pub async fn change_username(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<ChangeUsernameRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    let pubkey = auth.pubkey;
+    let req = auth.body;
+    info!("change_username: {}", pubkey);
+
+    let new_name = req.new_name;
+
+    match get_target_users(
+        &state,
+        pubkey.to_string(),
+        req.name,
+        auth.user_ids.as_deref(),
+        Some(&auth.domain),
+    ) {
+        Ok(users) if users.len() > 1 => {
+            error!("change_username ambiguous for pubkey: {}", pubkey);
+
+            Err((
+                StatusCode::BAD_REQUEST,
+                "Multiple names registered, specify the one to change".to_string(),
+            ))
+        }
+        Ok(mut users) if !users.is_empty() => {
+            info!("change_username found user for pubkey: {}", pubkey);
+
+            match change_user_name(&state, users.remove(0), new_name.clone()) {
                 Ok(_) => {
                     info!(
-                        "change_federation changed user federation for pubkey: {}, {}",
-                        pubkey, federation_id
+                        "change_username changed username for pubkey: {}, {}",
+                        pubkey, new_name
                     );
                     Ok(())
                 }
-                Err(e) => Err(handle_anyhow_error("change_federation", e)),
+                Err(e) => Err(handle_anyhow_error("change_username", e)),
             }
         }
-        Ok(None) => {
-            error!("change_federation not found: {}", pubkey);
+        Ok(_) => {
+            error!("change_username not found: {}", pubkey);
 
             Err((StatusCode::NOT_FOUND, "User not found".to_string()))
         }
-        Err(e) => Err(handle_anyhow_error("change_federation", e)),
+        Err(e) => Err(handle_anyhow_error("change_username", e)),
     }
 }
 
-pub async fn disable_zaps(
+pub async fn delete_account(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<TargetRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    let pubkey = auth.pubkey;
+    info!("delete_account: {}", pubkey);
+
+    match get_target_users(
+        &state,
+        pubkey.to_string(),
+        auth.body.name,
+        auth.user_ids.as_deref(),
+        Some(&auth.domain),
+    ) {
+        Ok(users) if !users.is_empty() => {
+            info!("delete_account found users for pubkey: {}", pubkey);
+
+            for u in users {
+                delete_user(&state, u).map_err(|e| handle_anyhow_error("delete_account", e))?;
+            }
+
+            info!("delete_account deleted users for pubkey: {}", pubkey);
+            Ok(())
+        }
+        Ok(_) => {
+            error!("delete_account not found: {}", pubkey);
+
+            Err((StatusCode::NOT_FOUND, "User not found".to_string()))
+        }
+        Err(e) => Err(handle_anyhow_error("delete_account", e)),
+    }
+}
+
+pub async fn enable_zaps(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<TargetRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    let pubkey = auth.pubkey;
+    info!("enable_zaps: {}", pubkey);
+
+    match get_target_users(
+        &state,
+        pubkey.to_string(),
+        auth.body.name,
+        auth.user_ids.as_deref(),
+        Some(&auth.domain),
+    ) {
+        Ok(users) if !users.is_empty() => {
+            info!("enable_zaps found users for pubkey: {}", pubkey);
+
+            for u in users {
+                enable_user_zaps(&state, u).map_err(|e| handle_anyhow_error("enable_zaps", e))?;
+            }
+
+            info!("enable_zaps enabled zaps for pubkey: {}", pubkey);
+            Ok(())
+        }
+        Ok(_) => {
+            error!("enable_zaps not found: {}", pubkey);
+
+            Err((StatusCode::NOT_FOUND, "User not found".to_string()))
+        }
+        Err(e) => Err(handle_anyhow_error("enable_zaps", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetPrimaryNameRequest {
+    pub name: String,
+}
+
+pub async fn set_primary_name(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<SetPrimaryNameRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    let pubkey = auth.pubkey;
+    info!("set_primary_name: {}", pubkey);
+
+    let name = normalize_name(&auth.body.name);
+
+    match get_target_users(
+        &state,
+        pubkey.to_string(),
+        Some(name.clone()),
+        auth.user_ids.as_deref(),
+        Some(&auth.domain),
+    ) {
+        Ok(mut users) if !users.is_empty() => {
+            info!("set_primary_name found user for pubkey: {}", pubkey);
+
+            match set_primary_user(&state, users.remove(0)) {
+                Ok(_) => {
+                    info!("set_primary_name set {} for pubkey: {}", name, pubkey);
+                    Ok(())
+                }
+                Err(e) => Err(handle_anyhow_error("set_primary_name", e)),
+            }
+        }
+        Ok(_) => {
+            error!("set_primary_name not found: {}", pubkey);
+
+            Err((StatusCode::NOT_FOUND, "User not found".to_string()))
+        }
+        Err(e) => Err(handle_anyhow_error("set_primary_name", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ClaimDomainRequest {
+    pub host: String,
+    /// The name served on the domain
+    pub name: String,
+    /// The address backing it, the primary one if not set
+    pub address: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ClaimDomainResponse {
+    pub host: String,
+    /// Name and value of the TXT record to set before verifying
+    pub record_name: String,
+    pub record_value: String,
+}
+
+pub async fn claim_domain(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<ClaimDomainRequest>,
+) -> Result<Json<ClaimDomainResponse>, (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    let pubkey = auth.pubkey;
+    let req = auth.body;
+    info!("claim_domain: {} for {}", req.host, pubkey);
+
+    let claim = check_domain_claim(
+        &state,
+        pubkey.to_string(),
+        req.host,
+        req.name,
+        req.address,
+        auth.user_ids.as_deref(),
+        &auth.domain,
+    )
+    .map_err(|e| handle_anyhow_error("claim_domain", e))?;
+    let (record_name, record_value) = verification_record(&claim);
+
+    Ok(Json(ClaimDomainResponse {
+        host: claim.host,
+        record_name,
+        record_value,
+    }))
+}
+
+pub async fn verify_domain(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<ClaimDomainRequest>,
+) -> Result<Json<Domain>, (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    let pubkey = auth.pubkey;
+    let req = auth.body;
+    info!("verify_domain: {} for {}", req.host, pubkey);
+
+    let claim = check_domain_claim(
+        &state,
+        pubkey.to_string(),
+        req.host,
+        req.name,
+        req.address,
+        auth.user_ids.as_deref(),
+        &auth.domain,
+    )
+    .map_err(|e| handle_anyhow_error("verify_domain", e))?;
+
+    match verify_custom_domain(&state, claim).await {
+        Ok(domain) => {
+            info!("verify_domain: verified {} for {}", domain.host, pubkey);
+            Ok(Json(domain))
+        }
+        Err(e) => Err(handle_anyhow_error("verify_domain", e)),
+    }
+}
+
+#[derive(Serialize)]
+pub struct LnurlAuthChallengeResponse {
+    pub k1: String,
+    /// LUD-04 login url for the wallet to sign in with
+    pub url: String,
+}
+
+pub async fn lnurl_auth_challenge(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
+) -> Result<Json<LnurlAuthChallengeResponse>, (StatusCode, String)> {
+    validate_domain_cors(origin, &domain)?;
+
+    match new_challenge(&state, &domain) {
+        Ok((k1, url)) => Ok(Json(LnurlAuthChallengeResponse { k1, url })),
+        Err(e) => Err(handle_anyhow_error("lnurl_auth_challenge", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LnurlAuthParams {
+    pub k1: String,
+    pub sig: String,
+    pub key: String,
+}
+
+/// The LUD-04 callback the wallet calls with its signature
+pub async fn lnurl_auth_login(
+    Extension(state): Extension<State>,
+    Query(params): Query<LnurlAuthParams>,
+) -> Result<Json<Value>, LnUrlErrorResponse> {
+    info!("lnurl_auth_login: {}", params.key);
+    match login(&state, &params.k1, &params.sig, &params.key) {
+        Ok(_) => Ok(Json(json!({"status": "OK"}))),
+        Err(e) => {
+            error!("Error in lnurl_auth_login {}: {e:?}", params.key);
+            Err(LnUrlErrorResponse {
+                status: LnurlStatus::Error,
+                reason: e.to_string(),
+            })
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LnurlAuthSessionParams {
+    pub k1: String,
+}
+
+#[derive(Serialize)]
+pub struct LnurlAuthSessionResponse {
+    /// Bearer token for the account endpoints
+    pub token: String,
+    pub expires_at: u64,
+}
+
+/// Polled by the client that asked for the challenge, gives out the session
+/// token once the wallet has signed it
+pub async fn lnurl_auth_session(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
+    Query(params): Query<LnurlAuthSessionParams>,
+) -> Result<Json<LnurlAuthSessionResponse>, (StatusCode, String)> {
+    validate_domain_cors(origin, &domain)?;
+
+    match issue_session_token(&state, &params.k1) {
+        Ok((token, expires_at)) => Ok(Json(LnurlAuthSessionResponse { token, expires_at })),
+        Err(e) => Err(handle_anyhow_error("lnurl_auth_session", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BindLinkingKeyRequest {
+    pub name: Option<String>,
+    /// A challenge the linking key signed
+    pub k1: String,
+}
+
+pub async fn bind_linking_key(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<BindLinkingKeyRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    // only the nostr key can let another key in
+    if auth.method != AuthMethod::Nostr {
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string()));
+    }
+
+    let pubkey = auth.pubkey;
+    let req = auth.body;
+    info!("bind_linking_key: {}", pubkey);
+
+    match bind_user_linking_key(&state, pubkey.to_string(), req.name, &auth.domain, &req.k1) {
+        Ok(_) => {
+            info!("bind_linking_key bound for pubkey: {}", pubkey);
+            Ok(())
+        }
+        Err(e) => Err(handle_anyhow_error("bind_linking_key", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetSuccessActionRequest {
+    pub name: Option<String>,
+    /// Clears the success action if not set
+    pub success_action: Option<SuccessActionConfig>,
+}
+
+pub async fn set_success_action(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<SetSuccessActionRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    let pubkey = auth.pubkey;
+    let req = auth.body;
+    info!("set_success_action: {}", pubkey);
+
+    match get_target_users(
+        &state,
+        pubkey.to_string(),
+        req.name,
+        auth.user_ids.as_deref(),
+        Some(&auth.domain),
+    ) {
+        Ok(users) if !users.is_empty() => {
+            for u in users {
+                set_user_success_action(&state, u, req.success_action.clone())
+                    .map_err(|e| handle_anyhow_error("set_success_action", e))?;
+            }
+
+            info!("set_success_action set for pubkey: {}", pubkey);
+            Ok(())
+        }
+        Ok(_) => {
+            error!("set_success_action not found: {}", pubkey);
+
+            Err((StatusCode::NOT_FOUND, "User not found".to_string()))
+        }
+        Err(e) => Err(handle_anyhow_error("set_success_action", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetProfileRequest {
+    pub name: Option<String>,
+    /// Clears the profile if not set
+    pub profile: Option<UserProfile>,
+}
+
+pub async fn set_profile(
     origin: Option<TypedHeader<Origin>>,
     Extension(state): Extension<State>,
-    Json(event): Json<Event>,
+    auth: Authenticated<SetProfileRequest>,
 ) -> Result<(), (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    let pubkey = auth.pubkey;
+    let req = auth.body;
+    info!("set_profile: {}", pubkey);
+
+    match get_target_users(
+        &state,
+        pubkey.to_string(),
+        req.name,
+        auth.user_ids.as_deref(),
+        Some(&auth.domain),
+    ) {
+        Ok(users) if !users.is_empty() => {
+            for u in users {
+                set_user_profile(&state, u, req.profile.clone())
+                    .map_err(|e| handle_anyhow_error("set_profile", e))?;
+            }
+
+            info!("set_profile set for pubkey: {}", pubkey);
+            Ok(())
+        }
+        Ok(_) => {
+            error!("set_profile not found: {}", pubkey);
+
+            Err((StatusCode::NOT_FOUND, "User not found".to_string()))
+        }
+        Err(e) => Err(handle_anyhow_error("set_profile", e)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReserveNameRequest {
+    pub pattern: String,
+    pub kind: ReservationKind,
+    /// Only this pubkey may register matching names
+    pub pubkey: Option<String>,
+    /// Host of the only domain the reservation covers, all of them if not given
+    pub domain: Option<String>,
+}
+
+/// Checks that an authenticated request comes from the operator
+fn require_admin<T>(
+    state: &State,
+    auth: &Authenticated<T>,
+    method: &str,
+) -> Result<(), (StatusCode, String)> {
+    // operator requests have to be signed by the admin key itself
+    if state.admin_pubkey != Some(auth.pubkey) || auth.method != AuthMethod::Nostr {
+        error!("error in {method}: not the admin: {}", auth.pubkey);
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string()));
+    }
+
+    Ok(())
+}
+
+pub async fn reserve_name(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<ReserveNameRequest>,
+) -> Result<Json<ReservedName>, (StatusCode, String)> {
     validate_cors(origin)?;
+    require_admin(&state, &auth, "reserve_name")?;
 
-    let pubkey = event.author();
-    info!("disable_zaps: {}", pubkey);
+    let req = auth.body;
+    info!("reserve_name: {:?} {}", req.kind, req.pattern);
 
-    if event.verify().is_err() && event.kind() != DISABLE_ZAPS_EVENT_KIND {
-        error!("error in disable_zaps: bad event");
-        return Err((StatusCode::BAD_REQUEST, "Bad event".to_string()));
+    match reserve_user_name(&state, req.pattern, req.kind, req.pubkey, req.domain) {
+        Ok(reserved) => Ok(Json(reserved)),
+        Err(e) => Err(handle_anyhow_error("reserve_name", e)),
     }
+}
 
-    // make sure it was made recently
-    let created_at = event.created_at();
-    let now = nostr::Timestamp::now();
-    if created_at < now - 120_i64 && created_at > now + 120_i64 {
-        error!("error in disable_zaps: event time not in range");
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Event time not in range".to_string(),
-        ));
+/// Requests turned away by the rate limiter since the instance started
+pub async fn rate_limit_stats(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    Extension(limiter): Extension<Arc<RateLimiter>>,
+    auth: Authenticated<()>,
+) -> Result<Json<Vec<ThrottledCount>>, (StatusCode, String)> {
+    validate_cors(origin)?;
+    require_admin(&state, &auth, "rate_limit_stats")?;
+
+    Ok(Json(limiter.throttled()))
+}
+
+pub async fn unreserve_name(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<ReserveNameRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_cors(origin)?;
+    require_admin(&state, &auth, "unreserve_name")?;
+
+    let req = auth.body;
+    info!("unreserve_name: {:?} {}", req.kind, req.pattern);
+
+    match unreserve_user_name(&state, req.pattern, req.kind, req.domain) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::NOT_FOUND, "Reservation not found".to_string())),
+        Err(e) => Err(handle_anyhow_error("unreserve_name", e)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SigningKeyInfo {
+    pub key_id: String,
+    /// Hex of the compressed aggregate public key
+    pub pubkey: String,
+    #[serde(flatten)]
+    pub identity: SignerIdentity,
+    pub paid: bool,
+    pub valid_from: u64,
+    pub valid_until: Option<u64>,
+}
+
+impl From<SigningKey> for SigningKeyInfo {
+    fn from(key: SigningKey) -> Self {
+        Self {
+            identity: key.identity(),
+            key_id: key.key_id,
+            pubkey: key.pubkey,
+            paid: key.paid,
+            valid_from: key.valid_from.and_utc().timestamp() as u64,
+            valid_until: key.valid_until.map(|t| t.and_utc().timestamp() as u64),
+        }
+    }
+}
+
+pub async fn signing_keys(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Vec<SigningKeyInfo>>, (StatusCode, String)> {
+    validate_cors(origin)?;
+
+    match get_signing_keys(&state) {
+        Ok(keys) => Ok(Json(keys.into_iter().map(SigningKeyInfo::from).collect())),
+        Err(e) => Err(handle_anyhow_error("signing_keys", e)),
     }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AddSigningKeyRequest {
+    /// Hex of the compressed aggregate public key
+    pub pubkey: String,
+    #[serde(flatten)]
+    pub identity: SignerIdentity,
+    pub paid: bool,
+    pub valid_from: Option<u64>,
+    pub valid_until: Option<u64>,
+}
+
+pub async fn add_signing_key_route(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<AddSigningKeyRequest>,
+) -> Result<Json<SigningKeyInfo>, (StatusCode, String)> {
+    validate_cors(origin)?;
+    require_admin(&state, &auth, "add_signing_key")?;
+
+    let req = auth.body;
+    info!("add_signing_key: {:?}", req.identity);
+
+    match add_signing_key(
+        &state,
+        req.pubkey,
+        req.identity,
+        req.paid,
+        req.valid_from,
+        req.valid_until,
+    ) {
+        Ok(key) => Ok(Json(key.into())),
+        Err(e) => Err(handle_anyhow_error("add_signing_key", e)),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RetireSigningKeyRequest {
+    pub key_id: String,
+    /// When the key stops accepting tokens, defaults to now
+    pub valid_until: Option<u64>,
+}
+
+pub async fn retire_signing_key_route(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<RetireSigningKeyRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_cors(origin)?;
+    require_admin(&state, &auth, "retire_signing_key")?;
+
+    let req = auth.body;
+    info!("retire_signing_key: {}", req.key_id);
+
+    match retire_signing_key(&state, req.key_id, req.valid_until) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::NOT_FOUND, "Signing key not found".to_string())),
+        Err(e) => Err(handle_anyhow_error("retire_signing_key", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AddDomainRequest {
+    pub host: String,
+    /// nsec to sign the domain's zap receipts with, the service key if not set
+    pub nostr_nsec: Option<String>,
+    /// text/plain LNURL metadata, `{name}` is replaced with the user's name
+    pub lnurl_description: Option<String>,
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+pub async fn add_domain_route(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<AddDomainRequest>,
+) -> Result<Json<Domain>, (StatusCode, String)> {
+    validate_cors(origin)?;
+    require_admin(&state, &auth, "add_domain")?;
+
+    let req = auth.body;
+    info!("add_domain: {}", req.host);
+
+    match add_domain(
+        &state,
+        req.host,
+        req.nostr_nsec,
+        req.lnurl_description,
+        req.allowed_origins,
+    ) {
+        Ok(domain) => Ok(Json(domain)),
+        Err(e) => Err(handle_anyhow_error("add_domain", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RotateKeyRequest {
+    /// An event from the new key that tags the current key
+    pub countersignature: Event,
+}
+
+/// Moves a user to a new nostr key. The request is authenticated by the
+/// current key and carries a countersignature from the new key.
+pub async fn rotate_key(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    auth: Authenticated<RotateKeyRequest>,
+) -> Result<(), (StatusCode, String)> {
+    validate_domain_cors(origin, &auth.domain)?;
+
+    // a session's linking key can't move the account to another key
+    if auth.method != AuthMethod::Nostr {
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".to_string()));
+    }
+
+    let pubkey = auth.pubkey;
+    info!("rotate_key: {}", pubkey);
+
+    let countersig = auth.body.countersignature;
+    let new_pubkey = countersig.author();
+    if !countersig.public_keys().any(|pk| *pk == pubkey) {
+        error!("error in rotate_key: bad countersignature");
+        return Err((StatusCode::BAD_REQUEST, "Bad countersignature".to_string()));
+    }
+    verify_signed_command(&state, &countersig, ROTATE_KEY_EVENT_KIND)?;
 
     match get_user_by_pubkey(&state, pubkey.to_string()) {
-        Ok(Some(u)) => {
-            info!("disable_zaps found user for pubkey: {}", pubkey);
+        Ok(Some(_)) => {
+            info!("rotate_key found user for pubkey: {}", pubkey);
 
-            // got the user, now change the federation
-            match disable_user_zaps(&state, u) {
+            match rotate_user_pubkey(&state, pubkey, new_pubkey) {
                 Ok(_) => {
-                    info!(
-                        "disable_zaps changed user federation for pubkey: {}",
-                        pubkey
-                    );
+                    info!("rotate_key rotated pubkey: {} -> {}", pubkey, new_pubkey);
                     Ok(())
                 }
-                Err(e) => Err(handle_anyhow_error("disable_zaps", e)),
+                Err(e) => Err(handle_anyhow_error("rotate_key", e)),
             }
         }
         Ok(None) => {
-            error!("disable_zaps not found: {}", pubkey);
+            error!("rotate_key not found: {}", pubkey);
 
             Err((StatusCode::NOT_FOUND, "User not found".to_string()))
         }
-        Err(e) => Err(handle_anyhow_error("disable_zaps", e)),
+        Err(e) => Err(handle_anyhow_error("rotate_key", e)),
     }
 }
 
 #[derive(Deserialize, Clone)]
 pub struct RegisterRequest {
     pub name: Option<String>,
+    /// The registered signing key the token was issued under, if not the default
+    #[serde(default)]
+    pub key_id: Option<String>,
     pub pubkey: String,
     pub federation_invite_code: String,
     pub msg: tbs::Message,
@@ -272,16 +1056,62 @@ impl RegisterRequest {
 #[derive(Serialize)]
 pub struct RegisterResponse {
     pub name: String,
+    /// Unix timestamp of when a paid name has to be renewed by
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RenewRequest {
+    pub name: String,
+    /// The registered signing key the token was issued under, if not the default
+    #[serde(default)]
+    pub key_id: Option<String>,
+    pub msg: tbs::Message,
+    pub sig: tbs::Signature,
+}
+
+impl RenewRequest {
+    pub fn verify(&self, pubkey: AggregatePublicKey) -> bool {
+        tbs::verify(self.msg, self.sig, pubkey)
+    }
+}
+
+#[derive(Serialize)]
+pub struct RenewResponse {
+    pub name: String,
+    /// Unix timestamp of the new expiry
+    pub expires_at: u64,
+}
+
+pub async fn renew_route(
+    origin: Option<TypedHeader<Origin>>,
+    Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
+    Json(req): Json<RenewRequest>,
+) -> Result<Json<RenewResponse>, (StatusCode, String)> {
+    info!("renew: {}", req.name);
+    validate_domain_cors(origin, &domain)?;
+    match renew(&state, &domain, req.clone()) {
+        Ok(res) => {
+            info!("renew finished: {}", req.name);
+            Ok(Json(res))
+        }
+        Err(e) => {
+            error!("Error in renew {}: {e:?}", req.name);
+            Err(e)
+        }
+    }
 }
 
 pub async fn register_route(
     origin: Option<TypedHeader<Origin>>,
     Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
     Json(req): Json<RegisterRequest>,
 ) -> Result<Json<RegisterResponse>, (StatusCode, String)> {
     info!("register: {:?}", req.name);
-    validate_cors(origin)?;
-    match register(&state, req.clone()).await {
+    validate_domain_cors(origin, &domain)?;
+    match register(&state, &domain, req.clone()).await {
         Ok(res) => {
             info!("register finished: {:?}", req.name);
             Ok(Json(res))
@@ -305,12 +1135,13 @@ pub struct UserWellKnownNip5Resp {
 
 pub async fn well_known_nip5_route(
     Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
     Query(params): Query<UserWellKnownNip5Req>,
 ) -> Result<Json<UserWellKnownNip5Resp>, (StatusCode, Json<Value>)> {
     info!("well_known_nip5_route: {:?}", params.name);
     match params.name.clone() {
         Some(name) => {
-            let names = well_known_nip5(&state, name)?;
+            let names = well_known_nip5(&state, &domain, name)?;
             info!("well_known_nip5_route finished: {:?}", params.name);
             Ok(Json(UserWellKnownNip5Resp { names }))
         }
@@ -348,7 +1179,9 @@ pub struct LnurlWellKnownResponse {
     pub min_sendable: Amount,
     pub metadata: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub comment_allowed: Option<i32>,
+    pub comment_allowed: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payer_data: Option<PayerDataSpec>,
     pub tag: LnurlType,
     pub status: LnurlStatus,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -356,12 +1189,65 @@ pub struct LnurlWellKnownResponse {
     pub allows_nostr: bool,
 }
 
+/// LUD-18 payer fields we ask for, fields that aren't set may not be sent
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct PayerDataSpec {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pubkey: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<PayerDataField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<PayerDataField>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PayerDataField {
+    pub mandatory: bool,
+    /// The challenge to sign for `auth`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k1: Option<String>,
+}
+
+/// LUD-18 payer identity sent with the callback
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PayerData {
+    pub name: Option<String>,
+    /// Hex encoded secp256k1 pubkey
+    pub pubkey: Option<String>,
+    pub identifier: Option<String>,
+    pub email: Option<String>,
+    pub auth: Option<PayerDataAuth>,
+}
+
+/// A LNURL-auth style signature of the advertised `k1` by the payer's linking key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PayerDataAuth {
+    pub key: String,
+    pub k1: String,
+    /// DER encoded, in hex
+    pub sig: String,
+}
+
+#[derive(Deserialize)]
+pub struct LnurlAmountParams {
+    /// Fixed amount for the payment, in msats
+    pub amount: Option<u64>,
+}
+
 pub async fn well_known_lnurlp_route(
     Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
     Path(username): Path<String>,
+    Query(params): Query<LnurlAmountParams>,
 ) -> Result<Json<LnurlWellKnownResponse>, LnUrlErrorResponse> {
     info!("well_known_lnurlp_route: {username}");
-    match well_known_lnurlp(&state, username.clone()).await {
+    match well_known_lnurlp(&state, &domain, username.clone(), params.amount).await {
         Ok(res) => {
             info!("well_known_lnurlp_route finished: {username}");
             Ok(Json(res))
@@ -376,6 +1262,56 @@ pub async fn well_known_lnurlp_route(
     }
 }
 
+#[derive(Serialize)]
+pub struct LnurlCodeResponse {
+    /// Bech32 encoded pay endpoint, `lnurl1...`
+    pub lnurl: String,
+    pub url: Url,
+}
+
+pub async fn lnurl_code(
+    Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
+    Path(username): Path<String>,
+    Query(params): Query<LnurlAmountParams>,
+) -> Result<Json<LnurlCodeResponse>, (StatusCode, String)> {
+    match user_lnurl(&state, &domain, username, params.amount) {
+        Ok((url, lnurl)) => Ok(Json(LnurlCodeResponse { lnurl, url })),
+        Err(e) => Err(handle_anyhow_error("lnurl_code", e)),
+    }
+}
+
+pub async fn lnurl_qr_svg(
+    Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
+    Path(username): Path<String>,
+    Query(params): Query<LnurlAmountParams>,
+) -> Result<Response, (StatusCode, String)> {
+    match user_lnurl(&state, &domain, username, params.amount)
+        .and_then(|(_, lnurl)| render_svg(&lnurl))
+    {
+        Ok(svg) => Ok(([(CONTENT_TYPE, "image/svg+xml")], svg).into_response()),
+        Err(e) => Err(handle_anyhow_error("lnurl_qr_svg", e)),
+    }
+}
+
+pub async fn lnurl_qr_png(
+    Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
+    Path(username): Path<String>,
+    Query(params): Query<LnurlAmountParams>,
+) -> Result<Response, (StatusCode, String)> {
+    match user_lnurl(&state, &domain, username, params.amount)
+        .and_then(|(_, lnurl)| render_png(&lnurl))
+    {
+        Ok(png) => Ok(([(CONTENT_TYPE, "image/png")], png).into_response()),
+        Err(e) => Err(handle_anyhow_error("lnurl_qr_png", e)),
+    }
+}
+
+/// Query of the pay callback. Wallets still sending the `proofofpayer` key of
+/// the withdrawn LUD-06 draft are fine, unknown params are ignored, it's
+/// LUD-18 `payerdata` that identifies payers now.
 #[derive(Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct LnurlCallbackParams {
@@ -385,7 +1321,7 @@ pub struct LnurlCallbackParams {
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub comment: Option<String>, // Optional parameter to pass the LN WALLET user's comment to LN SERVICE
     #[serde(default, deserialize_with = "empty_string_as_none")]
-    pub proofofpayer: Option<String>, // Optional ephemeral secp256k1 public key generated by payer
+    pub payerdata: Option<String>, // Optional LUD-18 payer identity, as JSON
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub nostr: Option<String>, // Optional zap request
 }
@@ -404,20 +1340,84 @@ pub struct LnurlCallbackResponse {
     pub routes: Option<Vec<String>>,
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct LnurlCallbackSuccessAction {
-    pub tag: String,
-    pub message: String,
+/// LUD-09 success action, `aes` ones are encrypted per LUD-10
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum LnurlCallbackSuccessAction {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: Url,
+    },
+    Aes {
+        description: String,
+        /// base64 of the AES-256-CBC encrypted plaintext, keyed by the preimage
+        ciphertext: String,
+        /// base64 of the 16 byte IV
+        iv: String,
+    },
+}
+
+/// The success action a user sets for their address. The plaintext of an
+/// `aes` one is encrypted for each invoice with its preimage.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum SuccessActionConfig {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: Url,
+    },
+    Aes {
+        description: String,
+        plaintext: String,
+    },
+}
+
+/// What a user shows in the LNURL metadata of their address
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserProfile {
+    /// Replaces the domain's `text/plain` description, `{name}` is filled in
+    #[serde(default)]
+    pub description: Option<String>,
+    /// `text/long-desc`
+    #[serde(default)]
+    pub long_description: Option<String>,
+    #[serde(default)]
+    pub avatar: Option<ProfileAvatar>,
+    /// Leaves the name out of the metadata, which is what the invoice's
+    /// description hash commits to
+    #[serde(default)]
+    pub private: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProfileAvatar {
+    pub mime: AvatarMime,
+    /// The image, base64 encoded
+    pub data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvatarMime {
+    #[serde(rename = "image/png")]
+    Png,
+    #[serde(rename = "image/jpeg")]
+    Jpeg,
 }
 
 pub async fn lnurl_callback_route(
     Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
     Query(params): Query<LnurlCallbackParams>,
     Path(username): Path<String>,
 ) -> Result<Json<LnurlCallbackResponse>, LnUrlErrorResponse> {
     info!("lnurl_callback_route: {username}");
-    match lnurl_callback(&state, username.clone(), params).await {
+    match lnurl_callback(&state, &domain, username.clone(), params).await {
         Ok(res) => {
             info!("lnurl_callback_route finished: {username}");
             Ok(Json(res))
@@ -432,7 +1432,7 @@ pub async fn lnurl_callback_route(
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct LnurlVerifyResponse {
     pub status: LnurlStatus,
@@ -441,13 +1441,40 @@ pub struct LnurlVerifyResponse {
     pub pr: String,
 }
 
+#[derive(Deserialize)]
+pub struct LnurlVerifyParams {
+    /// Seconds to wait for the invoice to be paid before answering
+    pub wait: Option<u64>,
+}
+
+/// Longest a long-polling verify request is held open
+const MAX_VERIFY_WAIT_SECS: u64 = 60;
+
+/// How long verify streams stay open for a payment, the invoice's expiry
+/// ends them before this does
+const VERIFY_STREAM_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub async fn lnurl_verify_route(
     Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
     Path((username, op_id)): Path<(String, String)>,
+    Query(params): Query<LnurlVerifyParams>,
 ) -> Result<Json<LnurlVerifyResponse>, LnUrlErrorResponse> {
     info!("lnurl_callback_route: {username}");
-    match verify(&state, username.clone(), op_id).await {
-        Ok(res) => {
+    let wait = match params.wait {
+        Some(wait) => Duration::from_secs(wait.min(MAX_VERIFY_WAIT_SECS)),
+        None => Duration::ZERO,
+    };
+
+    match watch_verify(&state, &domain, username.clone(), op_id.clone()).await {
+        Ok((res, updates)) => {
+            let res = if wait.is_zero() {
+                res
+            } else {
+                wait_for_verify_update(&state, updates, &res, &op_id, wait)
+                    .await
+                    .unwrap_or(res)
+            };
             info!("lnurl_callback_route finished: {username}");
             Ok(Json(res))
         }
@@ -461,6 +1488,88 @@ pub async fn lnurl_verify_route(
     }
 }
 
+/// Server-Sent Events variant of the verify endpoint, sends the current
+/// state and then the update once the invoice is paid or cancelled
+pub async fn lnurl_verify_events(
+    Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
+    Path((username, op_id)): Path<(String, String)>,
+) -> Result<Sse<impl Stream<Item = Result<SseEvent, axum::Error>>>, LnUrlErrorResponse> {
+    info!("lnurl_verify_events: {username}");
+    let (res, updates) = watch_verify(&state, &domain, username.clone(), op_id.clone())
+        .await
+        .map_err(|e| {
+            error!("Error in lnurl_verify_events {username}: {e:?}");
+            LnUrlErrorResponse {
+                status: LnurlStatus::Error,
+                reason: e.to_string(),
+            }
+        })?;
+
+    let current = res.clone();
+    let update = async move {
+        wait_for_verify_update(&state, updates, &current, &op_id, VERIFY_STREAM_TIMEOUT).await
+    };
+    let events = stream::once(future::ready(Some(res)))
+        .chain(stream::once(update))
+        .filter_map(future::ready)
+        .map(|res| SseEvent::default().json_data(res));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// WebSocket variant of the verify endpoint, sends the current state and
+/// then the update once the invoice is paid or cancelled, as JSON messages
+pub async fn lnurl_verify_ws(
+    ws: WebSocketUpgrade,
+    Extension(state): Extension<State>,
+    RequestDomain(domain): RequestDomain,
+    Path((username, op_id)): Path<(String, String)>,
+) -> Result<Response, LnUrlErrorResponse> {
+    info!("lnurl_verify_ws: {username}");
+    let (res, updates) = watch_verify(&state, &domain, username.clone(), op_id.clone())
+        .await
+        .map_err(|e| {
+            error!("Error in lnurl_verify_ws {username}: {e:?}");
+            LnUrlErrorResponse {
+                status: LnurlStatus::Error,
+                reason: e.to_string(),
+            }
+        })?;
+
+    Ok(ws.on_upgrade(move |mut socket| async move {
+        if send_ws_json(&mut socket, &res).await.is_err() {
+            return;
+        }
+
+        let wait = wait_for_verify_update(&state, updates, &res, &op_id, VERIFY_STREAM_TIMEOUT);
+        let update = tokio::select! {
+            update = wait => update,
+            // the client going away stops the wait
+            _ = async {
+                while let Some(Ok(msg)) = socket.recv().await {
+                    if matches!(msg, WsMessage::Close(_)) {
+                        break;
+                    }
+                }
+            } => None,
+        };
+
+        if let Some(update) = update {
+            let _ = send_ws_json(&mut socket, &update).await;
+        }
+        let _ = socket.close().await;
+    }))
+}
+
+async fn send_ws_json(
+    socket: &mut WebSocket,
+    res: &LnurlVerifyResponse,
+) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(res).expect("valid json");
+    socket.send(WsMessage::Text(json)).await
+}
+
 #[derive(Serialize)]
 pub struct HealthResponse {
     pub status: String,
@@ -493,6 +1602,20 @@ pub fn valid_origin(origin: &str) -> bool {
         || origin.starts_with(ALLOWED_LOCALHOST)
 }
 
+/// Like [`validate_cors`], also allowing the origins of the domain
+pub fn validate_domain_cors(
+    origin: Option<TypedHeader<Origin>>,
+    domain: &Domain,
+) -> Result<(), (StatusCode, String)> {
+    if let Some(TypedHeader(ref o)) = origin {
+        if domain.allowed_origins.contains(&o.to_string()) {
+            return Ok(());
+        }
+    }
+
+    validate_cors(origin)
+}
+
 pub fn validate_cors(origin: Option<TypedHeader<Origin>>) -> Result<(), (StatusCode, String)> {
     if let Some(TypedHeader(origin)) = origin {
         if origin.is_null() {
@@ -0,0 +1,126 @@
+use axum::http::StatusCode;
+use chrono::DateTime;
+use log::error;
+use nostr::{Event, Kind, Timestamp};
+
+use crate::{models::used_event_id::NewUsedEventId, routes::handle_anyhow_error, State};
+
+/// How far the time of a signed command may be from ours, in seconds
+pub(crate) const SIGNED_COMMAND_WINDOW: i64 = 60;
+
+/// Checks that a signed command is validly signed, of the expected kind and
+/// made within the freshness window
+pub(crate) fn check_signed_command(event: &Event, kind: Kind) -> Result<(), (StatusCode, String)> {
+    if event.verify().is_err() || event.kind() != kind {
+        error!("Invalid signed command {}: bad event", event.id);
+        return Err((StatusCode::UNAUTHORIZED, "Bad event".to_string()));
+    }
+
+    let created_at = event.created_at();
+    let now = Timestamp::now();
+    if created_at < now - SIGNED_COMMAND_WINDOW || created_at > now + SIGNED_COMMAND_WINDOW {
+        error!(
+            "Invalid signed command {}: event time not in range",
+            event.id
+        );
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Event time not in range".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Marks a checked signed command as used, failing if it was seen before.
+/// Commands older than the freshness window are forgotten since they can't
+/// pass [`check_signed_command`] again.
+pub(crate) fn use_signed_command(state: &State, event: &Event) -> Result<(), (StatusCode, String)> {
+    let to_naive = |t: Timestamp| {
+        DateTime::from_timestamp(t.as_u64() as i64, 0)
+            .map(|t| t.naive_utc())
+            .ok_or((StatusCode::UNAUTHORIZED, "Bad event".to_string()))
+    };
+
+    let used = NewUsedEventId {
+        event_id: event.id.to_hex(),
+        pubkey: event.author().to_string(),
+        created_at: to_naive(event.created_at())?,
+    };
+    let stale_before = to_naive(Timestamp::now() - SIGNED_COMMAND_WINDOW)?;
+
+    match state.db.mark_event_used(used, stale_before) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            error!("Invalid signed command {}: replayed", event.id);
+            Err((StatusCode::UNAUTHORIZED, "Event already used".to_string()))
+        }
+        Err(e) => Err(handle_anyhow_error("use_signed_command", e)),
+    }
+}
+
+/// Checks a signed command and marks it used so it can only be accepted once
+pub(crate) fn verify_signed_command(
+    state: &State,
+    event: &Event,
+    kind: Kind,
+) -> Result<(), (StatusCode, String)> {
+    check_signed_command(event, kind)?;
+    use_signed_command(state, event)
+}
+
+#[cfg(all(test, not(feature = "integration-tests")))]
+mod tests {
+    use nostr::{EventBuilder, Keys, Kind, Timestamp};
+
+    use crate::signed_command::{check_signed_command, SIGNED_COMMAND_WINDOW};
+
+    #[tokio::test]
+    async fn check_signed_command_freshness() {
+        let keys = Keys::generate();
+        let event_at = |created_at: Timestamp| {
+            EventBuilder::new(Kind::Custom(93_192), "", [])
+                .custom_created_at(created_at)
+                .to_event(&keys)
+                .unwrap()
+        };
+
+        let now = Timestamp::now();
+        assert!(check_signed_command(&event_at(now), Kind::Custom(93_192)).is_ok());
+
+        // wrong kind
+        assert!(check_signed_command(&event_at(now), Kind::Custom(93_193)).is_err());
+
+        // stale or from the future
+        let stale = event_at(now - (SIGNED_COMMAND_WINDOW + 10));
+        assert!(check_signed_command(&stale, Kind::Custom(93_192)).is_err());
+        let future = event_at(now + (SIGNED_COMMAND_WINDOW + 10));
+        assert!(check_signed_command(&future, Kind::Custom(93_192)).is_err());
+    }
+}
+
+#[cfg(all(test, feature = "integration-tests"))]
+mod tests_integration {
+    use nostr::{EventBuilder, Keys, Kind};
+
+    use crate::{signed_command::verify_signed_command, test_state};
+
+    #[tokio::test]
+    pub async fn replayed_command_test() {
+        let state = test_state();
+
+        let keys = Keys::generate();
+        let kind = Kind::Custom(93_192);
+        let event = EventBuilder::new(kind, "", []).to_event(&keys).unwrap();
+
+        // accepted once, then rejected
+        assert!(verify_signed_command(&state, &event, kind).is_ok());
+        assert!(verify_signed_command(&state, &event, kind).is_err());
+
+        // a new command from the same key is fine
+        let event = EventBuilder::new(kind, "again", [])
+            .to_event(&keys)
+            .unwrap();
+        assert!(verify_signed_command(&state, &event, kind).is_ok());
+    }
+}